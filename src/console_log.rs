@@ -0,0 +1,144 @@
+//! Record of the most recent console output of each guest, kept so that it can be retrieved after
+//! the fact even if the serial log was lost or interleaved with other guests beyond recognition.
+//! Each line is stamped with the host timer (mtime) as it completes.
+//!
+//! The log of a guest is printed by the monitor's `log` command, and automatically when the guest
+//! crashes: that is, when it finishes printing a Linux kernel panic, or asks to be reset because of
+//! a system failure. The logs of every guest are printed if the hypervisor itself panics.
+
+use core::fmt::Write;
+use crate::constants::MAX_GUESTS;
+use crate::context::HostClint;
+use crate::statics::SHARED_STATICS;
+
+/// Number of lines kept for each guest.
+pub const LOG_LINES: usize = 512;
+/// Longer lines are cut short.
+const LINE_LEN: usize = 128;
+
+/// Final line of a Linux kernel panic report.
+const PANIC_END_MARKER: &[u8] = b"---[ end Kernel panic";
+
+#[derive(Copy, Clone)]
+struct Line {
+    time: u64,
+    len: usize,
+    text: [u8; LINE_LEN],
+}
+
+#[derive(Copy, Clone)]
+struct GuestLog {
+    lines: [Line; LOG_LINES],
+    /// Index in `lines` to write the next line to, and how many lines are in use.
+    next: usize,
+    count: usize,
+}
+
+impl GuestLog {
+    const fn new() -> Self {
+        Self {
+            lines: [Line { time: 0, len: 0, text: [0; LINE_LEN] }; LOG_LINES],
+            next: 0,
+            count: 0,
+        }
+    }
+}
+
+pub struct ConsoleLog {
+    clock: HostClint,
+    guests: [GuestLog; MAX_GUESTS],
+}
+
+impl ConsoleLog {
+    pub const fn new() -> Self {
+        Self {
+            clock: HostClint::Sbi,
+            guests: [GuestLog::new(); MAX_GUESTS],
+        }
+    }
+
+    /// Take timestamps from `clock` rather than the `time` CSR.
+    pub fn set_clock(&mut self, clock: HostClint) {
+        self.clock = clock;
+    }
+}
+
+/// Record a line of console output from the guest in slot `guest`, and dump the guest's log if the
+/// line shows that it crashed. Called with the guest's lock held.
+pub fn record(guest: usize, line: &[u8]) {
+    let line = match line.last() {
+        Some(&b'\r') => &line[..line.len() - 1],
+        _ => line,
+    };
+
+    {
+        let mut log = SHARED_STATICS.console_log.lock();
+        let time = log.clock.get_mtime();
+        let guest_log = &mut log.guests[guest];
+        let entry = &mut guest_log.lines[guest_log.next];
+        entry.time = time;
+        entry.len = line.len().min(LINE_LEN);
+        entry.text[..entry.len].copy_from_slice(&line[..entry.len]);
+        guest_log.next = (guest_log.next + 1) % LOG_LINES;
+        guest_log.count = (guest_log.count + 1).min(LOG_LINES);
+    }
+
+    if line.windows(PANIC_END_MARKER.len()).any(|w| w == PANIC_END_MARKER) {
+        dump(guest);
+    }
+}
+
+/// Write the log of the guest in slot `guest` to `output`, oldest line first. The console lock
+/// must already be held if `output` is the console, as it has to be taken first.
+pub fn write_log(guest: usize, output: &mut dyn Write) {
+    let log = SHARED_STATICS.console_log.lock();
+    let guest_log = &log.guests[guest];
+    let _ = writeln!(output, "==== console log of guest {} ({} lines) ====", guest + 1, guest_log.count);
+    for i in 0..guest_log.count {
+        let line = &guest_log.lines[(guest_log.next + LOG_LINES - guest_log.count + i) % LOG_LINES];
+        let _ = write!(output, "[{:>14}] ", line.time);
+        for &byte in &line.text[..line.len] {
+            let _ = output.write_char(byte as char);
+        }
+        let _ = writeln!(output);
+    }
+    let _ = writeln!(output, "==== end of console log of guest {} ====", guest + 1);
+}
+
+/// Call `f` with each of the last `count` lines logged for the guest in slot `guest`, oldest first,
+/// or with as many of them as are still kept. Returns the number of lines passed to `f`. The console
+/// lock must already be held if `f` writes to the console.
+pub fn for_recent_lines<F: FnMut(&[u8])>(guest: usize, count: usize, mut f: F) -> usize {
+    let log = SHARED_STATICS.console_log.lock();
+    let guest_log = &log.guests[guest];
+    let count = count.min(guest_log.count);
+    for i in 0..count {
+        let line = &guest_log.lines[(guest_log.next + LOG_LINES - count + i) % LOG_LINES];
+        f(&line.text[..line.len]);
+    }
+    count
+}
+
+/// Print the log of the guest in slot `guest` to the console.
+pub fn dump(guest: usize) {
+    let mut writer = SHARED_STATICS.uart_writer.lock();
+    write_log(guest, &mut *writer);
+}
+
+/// Print the logs of every guest that has written anything. Called when the hypervisor crashes, so
+/// the logs are skipped rather than waited for if their locks are held.
+pub fn dump_all() {
+    let mut writer = match SHARED_STATICS.uart_writer.try_lock() {
+        Some(writer) => writer,
+        None => return,
+    };
+    for guest in 0..MAX_GUESTS {
+        let count = match SHARED_STATICS.console_log.try_lock() {
+            Some(log) => log.guests[guest].count,
+            None => return,
+        };
+        if count != 0 {
+            write_log(guest, &mut *writer);
+        }
+    }
+}
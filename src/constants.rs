@@ -7,7 +7,15 @@ pub const SYMBOL_PA2VA_OFFSET: u64 = 0xffffffff40000000;
 /// result in buffer overflows in various places.
 pub const MAX_HOST_HARTS: usize = 16;
 
+/// Maximum number of vCPUs in a single guest. This is also the number of harts listed in the guest
+/// device tree.
 pub const MAX_GUEST_HARTS: usize = 8;
 
+/// Maximum number of guests that can be running at once.
+pub const MAX_GUESTS: usize = 8;
+
+/// Maximum number of vCPUs that can be time-sliced on a single host hart.
+pub const MAX_HART_VCPUS: usize = 16;
+
 pub const MACHINE_SHARED_STATIC_ADDRESS: u64 = 0x80400000;
 pub const SUPERVISOR_SHARED_STATIC_ADDRESS: u64 = 0xffffffffc0200000;
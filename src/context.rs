@@ -1,14 +1,24 @@
-use arrayvec::ArrayVec;
+use arrayvec::{ArrayString, ArrayVec};
 use spin::Mutex;
-use crate::fdt::MachineMeta;
+use crate::constants::MAX_GUEST_HARTS;
+use crate::drivers::GuestDevice;
+use crate::drivers::blk::RamDiskDriver;
+use crate::drivers::console::ConsoleDriver;
+use crate::drivers::macb::MacbDriver;
+use crate::drivers::partition::PartitionDriver;
+use crate::drivers::rng::RngDriver;
+use crate::drivers::switch::SwitchPortDriver;
+use crate::drivers::vsock::VsockDriver;
+use crate::fdt::{Fdt, MachineMeta};
 use crate::memory_region::MemoryRegion;
-use crate::plic::PlicState;
+use crate::plic::{self, PlicState};
 use crate::pmap::{PageTables, PageTableRoot};
+use crate::print::ConsoleOutput;
 use crate::riscv::bits::*;
 use crate::riscv::csr;
 use crate::statics::SHARED_STATICS;
 use crate::trap::U64Bits;
-use crate::{pmap, print, riscv, virtio};
+use crate::{elf, pmap, riscv, trace, virtio};
 
 pub static CONTEXT: Mutex<Option<Context>> = Mutex::new(None);
 
@@ -32,7 +42,9 @@ pub struct ControlRegisters {
 
 pub struct VirtIO {
     pub devices: ArrayVec<[virtio::Device; virtio::MAX_DEVICES]>,
-    pub queue_guest_pages: ArrayVec<[u64; virtio::MAX_DEVICES * virtio::MAX_QUEUES]>,
+    /// Guest interrupt number of each device slot.
+    pub guest_irqs: ArrayVec<[u16; virtio::MAX_DEVICES]>,
+    pub queue_guest_pages: ArrayVec<[u64; virtio::MAX_QUEUE_PAGES]>,
 }
 
 pub struct Uart {
@@ -46,8 +58,7 @@ pub struct Uart {
     pub input_fifo: [u8; 16],
     pub input_bytes_ready: usize,
 
-    pub line_buffer: ArrayVec<[u8; 256]>,
-    pub guestid: Option<u64>,
+    pub output: ConsoleOutput,
 }
 
 pub enum HostClint {
@@ -61,6 +72,15 @@ pub struct HostPlic {
     pub claim_clear: MemoryRegion<u32>,
 }
 
+/// Guest state that lives in hardware registers or on the trap stack while a context is running,
+/// and is copied here while it is switched out.
+pub struct SwitchedOutState {
+    pub registers: [u64; 32],
+    pub pc: u64,
+    pub fs: u64,
+    pub fp_registers: [u64; 33],
+}
+
 pub struct SavedRegisters {
     registers: MemoryRegion,
 }
@@ -75,13 +95,74 @@ pub struct TestFinisher {
     registers: MemoryRegion<u32>,
 }
 
-pub struct Context {
-    pub csrs: ControlRegisters,
+// Requests that one vCPU can make of another, stored in `VirtualHart::requests`.
+pub const REQUEST_SOFTWARE_INTERRUPT: u32 = 0x1;
+pub const REQUEST_FENCE_I: u32 = 0x2;
+pub const REQUEST_FLUSH_SHADOW_PAGE_TABLES: u32 = 0x4;
+pub const REQUEST_FLUSH_SHADOW_PAGE_TABLE_RANGE: u32 = 0x8;
+/// Sent after another vCPU changes the target's status to stopped. There is nothing to do beyond
+/// taking the trap: a stopped vCPU never returns to the guest.
+pub const REQUEST_STOP: u32 = 0x10;
+
+/// Lifecycle of a vCPU as seen through the SBI hart state management extension.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum HartStatus {
+    Started,
+    Stopped,
+    /// Another vCPU asked for this one to be started, and it will begin executing at `start_addr`
+    /// with `opaque` in a1 once its host hart picks it up.
+    StartPending { start_addr: u64, opaque: u64 },
+}
+
+pub struct VirtualHart {
+    /// Host hart this vCPU runs on, or None if it hasn't come online yet.
+    pub hartid: Option<u64>,
+    /// Whether the vCPU is currently scheduled on its host hart. Requests to a vCPU that isn't are
+    /// handled when it is next switched in.
+    pub running: bool,
+    /// Outstanding REQUEST_* bits. The target vCPU clears them once they have been handled.
+    pub requests: u32,
+    /// Guest virtual addresses to flush for REQUEST_FLUSH_SHADOW_PAGE_TABLE_RANGE, as `start..end`.
+    /// Ranges from multiple requests are merged.
+    pub flush_range: (u64, u64),
+    /// Stopped vCPUs are never scheduled, leaving their host hart to other vCPUs.
+    pub status: HartStatus,
+}
+
+/// State shared by all vCPUs of a guest.
+pub struct Guest {
     pub plic: PlicState,
     pub uart: Uart,
     pub virtio: VirtIO,
+    pub vcpus: ArrayVec<[VirtualHart; MAX_GUEST_HARTS]>,
+
+    /// Map from host external interrupt number to guest external interrupt nmuber. Host interrupts
+    /// for a guest's devices are always routed to the hart running its first vCPU.
+    pub irq_map: [IrqMapping; 512],
+
+    /// Guest physical address of the kernel entry point.
+    pub entry: u64,
+    /// Guest physical address of the guest device tree.
+    pub dtb: u64,
+
+    /// Host physical address of the kernel ELF image, kept around so the guest can be rebooted.
+    pub image: u64,
+    /// Device tree that `dtb` is generated from, and the bootargs and memory size to patch into it.
+    pub dtb_template: &'static [u8],
+    pub bootargs: ArrayString<[u8; 256]>,
+}
+
+pub struct Context {
+    pub csrs: ControlRegisters,
+    pub guest: &'static Mutex<Option<Guest>>,
+    /// Slot of the guest in `SHARED_STATICS.guests`.
+    pub guest_index: usize,
+
+    /// Index of this vCPU within its guest, which is also the hartid the guest sees.
+    pub vcpu: usize,
 
     pub saved_registers: SavedRegisters,
+    pub switched_out: SwitchedOutState,
     pub guest_memory: MemoryRegion,
     pub shadow_page_tables: PageTables,
 
@@ -100,13 +181,27 @@ pub struct Context {
     pub host_plic: HostPlic,
 
     pub test_finisher: Option<TestFinisher>,
-
-    /// Map from host external interrupt number to guest external interrupt nmuber
-    pub irq_map: [IrqMapping; 512],
 }
 
 
 impl ControlRegisters {
+    /// Register state of a hart as it enters the guest kernel.
+    pub fn new() -> Self {
+        Self {
+            sstatus: 0,
+            stvec: 0,
+            sie: 0,
+            sip: 0,
+            sscratch: 0,
+            sepc: 0,
+            scause: 0,
+            stval: 0,
+            satp: 0,
+
+            mtimecmp: u64::max_value(),
+        }
+    }
+
     pub fn push_sie(&mut self) {
         self.sstatus.set(STATUS_SPIE, self.sstatus.get(STATUS_SIE));
         self.sstatus.set(STATUS_SIE, false);
@@ -121,23 +216,37 @@ impl ControlRegisters {
 impl Uart {
     const IRQ: u32 = 10;
 
+    pub fn new(guestid: Option<u64>) -> Self {
+        Self {
+            dlab: false,
+            interrupt_enable: 0,
+            divisor_latch: 1,
+            next_interrupt_time: 0,
+            input_fifo: [0; 16],
+            input_bytes_ready: 0,
+            output: ConsoleOutput::new(guestid),
+        }
+    }
+
     fn tx_interrupt(&self, current_time: u64) -> bool {
         self.next_interrupt_time  <= current_time && self.interrupt_enable & 0x2 != 0
     }
     fn rx_interrupt(&self) -> bool {
         self.input_bytes_ready >= 1 && self.interrupt_enable & 0x1 != 0
     }
-    pub fn timer(state: &mut Context, current_time: u64) {
-        state.uart.fill_fifo();
-        if state.uart.tx_interrupt(current_time) || state.uart.rx_interrupt() {
-            state.plic.set_pending(Uart::IRQ, true);
-            state.no_interrupt = false;
+    /// Returns whether the UART raised its interrupt.
+    pub fn timer(guest: &mut Guest, current_time: u64) -> bool {
+        guest.uart.fill_fifo();
+        if guest.uart.tx_interrupt(current_time) || guest.uart.rx_interrupt() {
+            guest.plic.set_pending(Uart::IRQ, true);
+            return true;
         }
+        false
     }
 
     pub fn fill_fifo(&mut self) {
         while self.input_bytes_ready < self.input_fifo.len() {
-            if let Some(ch) = SHARED_STATICS.uart_writer.lock().getchar() {
+            if let Some(ch) = SHARED_STATICS.uart_writer.lock().guest_input(self.output.guestid) {
                 self.input_fifo[self.input_bytes_ready] = ch;
                 self.input_bytes_ready += 1;
             } else {
@@ -146,6 +255,20 @@ impl Uart {
         }
     }
 
+    /// Remove the oldest byte from the input FIFO.
+    pub fn take_input_byte(&mut self) -> Option<u8> {
+        if self.input_bytes_ready == 0 {
+            return None;
+        }
+
+        let ret = self.input_fifo[0];
+        self.input_bytes_ready -= 1;
+        for i in 0..(self.input_bytes_ready) {
+            self.input_fifo[i] = self.input_fifo[i+1];
+        }
+        Some(ret)
+    }
+
     const TRANSMIT_HOLDING_REGISTER: u64 = 0x10000000;
     const RECEIVE_BUFFER_REGISTER: u64 = 0x10000000;
     const DIVISOR_LATCH_LSB: u64 = 0x10000000;
@@ -187,18 +310,7 @@ impl Uart {
 
     pub fn read(&mut self, host_clint: &HostClint, addr: u64) -> u8 {
         match (self.dlab, addr) {
-            (false, Uart::RECEIVE_BUFFER_REGISTER) => {
-                if self.input_bytes_ready > 0 {
-                    let ret = self.input_fifo[0];
-                    self.input_bytes_ready -= 1;
-                    for i in 0..(self.input_bytes_ready) {
-                        self.input_fifo[i] = self.input_fifo[i+1];
-                    }
-                    ret
-                } else {
-                    0
-                }
-            }
+            (false, Uart::RECEIVE_BUFFER_REGISTER) => self.take_input_byte().unwrap_or(0),
             (true, Uart::DIVISOR_LATCH_LSB) => (self.divisor_latch & 0xff) as u8,
             (true, Uart::DIVISOR_LATCH_MSB) => (self.divisor_latch >> 8) as u8,
             (false, Uart::INTERRUPT_ENABLE_REGISTER) => self.interrupt_enable, // (top four should always be zero)
@@ -263,25 +375,22 @@ impl Uart {
     }
 
     pub fn output_byte(&mut self, value: u8) {
-        if let Some(guestid) = self.guestid {
-            let len = self.line_buffer.len();
-            if len > 0 && self.line_buffer[len - 1] == '\r' as u8 && value != '\n' as u8 {
-                print::guest_println(guestid, &self.line_buffer);
-                self.line_buffer.clear();
-            }
-            if value == '\n' as u8 || self.line_buffer.is_full() {
-                print::guest_println(guestid, &self.line_buffer);
-                self.line_buffer.clear();
-            } else {
-                self.line_buffer.push(value);
-            }
-        } else {
-            SHARED_STATICS.uart_writer.lock().putchar(value);
-        }
+        self.output.output_byte(value);
     }
 }
 
 impl HostClint {
+    /// Read mtime directly from the CLINT at `clint_address` if there is one, or else through the
+    /// `time` CSR. The CLINT must be mapped in the direct map.
+    pub unsafe fn new(clint_address: Option<u64>) -> Self {
+        match clint_address {
+            Some(address) => HostClint::Direct {
+                mtime: MemoryRegion::with_base_address(pmap::pa2va(address + 0xbff8), 0, 8),
+            },
+            None => HostClint::Sbi,
+        }
+    }
+
     pub fn get_mtime(&self) -> u64 {
         match self {
             HostClint::Direct { ref mtime } => mtime[0],
@@ -329,7 +438,50 @@ impl SavedRegisters {
     }
 }
 
+impl VirtualHart {
+    pub const EMPTY_RANGE: (u64, u64) = (u64::max_value(), 0);
+
+    pub fn add_flush_range(&mut self, start: u64, end: u64) {
+        self.flush_range = (self.flush_range.0.min(start), self.flush_range.1.max(end));
+    }
+
+    /// Take the outstanding requests along with the range to flush.
+    pub fn take_requests(&mut self) -> (u32, (u64, u64)) {
+        let requests = (self.requests, self.flush_range);
+        self.requests = 0;
+        self.flush_range = Self::EMPTY_RANGE;
+        requests
+    }
+}
+
+impl Guest {
+    /// Put every device back in its initial state, so that nothing keeps running on behalf of a
+    /// guest that has shut down or is about to reboot.
+    pub fn reset_devices(&mut self, guest_memory: &mut MemoryRegion) {
+        for device in self.virtio.devices.iter_mut() {
+            device.reset(guest_memory);
+        }
+        self.virtio.queue_guest_pages.clear();
+        self.plic = PlicState::new();
+        self.uart = Uart::new(self.uart.output.guestid);
+    }
+
+    /// Send an IPI to every vCPU other than `current` that has an external interrupt pending, so
+    /// that it notices without having to wait for its next trap.
+    pub fn notify_external_interrupts(&self, current: usize) {
+        for (i, vcpu) in self.vcpus.iter().enumerate() {
+            if i != current && vcpu.running && self.plic.interrupt_pending(plic::supervisor_context(i)) {
+                riscv::sbi::send_ipi_to_hart(vcpu.hartid.unwrap());
+            }
+        }
+    }
+}
+
 impl Context {
+    pub fn plic_context(&self) -> usize {
+        plic::supervisor_context(self.vcpu)
+    }
+
     pub fn get_csr(&mut self, csr: u32) -> Option<u64> {
         Some(match csr as u64 {
             csr::sstatus => {
@@ -388,6 +540,7 @@ impl Context {
                 // This should not be necessary. However, currently QEMU doesn't trap when
                 // sfence.vma is executed from user mode so flush here to compensate.
                 pmap::flush_shadow_page_table(&mut self.shadow_page_tables);
+                trace::flush(self, 0, u64::max_value());
             }
             csr::sie => {
                 let value = value & (IE_SEIE | IE_STIE | IE_SSIE);
@@ -432,46 +585,185 @@ impl Context {
     }
 }
 
-pub unsafe fn initialize(machine: &MachineMeta,
-                         guest_machine: &MachineMeta,
-                         shadow_page_tables: PageTables,
-                         guest_memory: MemoryRegion,
+/// Load the kernel ELF image at host physical address `image` into guest memory, and follow it with
+/// a device tree generated from `dtb_template`. Returns the entry point and guest physical address
+/// of the device tree, along with the parsed device tree.
+pub unsafe fn load_guest(image: u64,
+                         dtb_template: &[u8],
+                         guest_memory: &MemoryRegion,
                          guest_shift: u64,
-                         hartid: u64,
-                         guestid: Option<u64>) {
+                         bootargs: &str,
+                         vcpus: u64) -> (u64, u64, MachineMeta) {
+    // Guest memory is written through the direct map, so this works regardless of which shadow page
+    // table is installed.
+    let (entry, max_addr) = elf::load_elf(pmap::pa2va(image) as *const u8,
+                                          pmap::pa2va(guest_memory.base() + guest_shift) as *mut u8);
+    let dtb = (max_addr | 0x1fffff) + 1;
+
+    let dtb_va = pmap::pa2va(dtb + guest_shift);
+    core::ptr::copy(dtb_template.as_ptr(), dtb_va as *mut u8, dtb_template.len());
+    let mut fdt = Fdt::new(dtb_va);
+    fdt.initialize_guest(guest_memory.len(), bootargs, vcpus, SHARED_STATICS.shmem.size());
+    (entry, dtb, fdt.parse())
+}
+
+/// Create the state shared by all vCPUs of a guest. This must happen before `create_context` is
+/// called for any of them.
+pub unsafe fn create_guest(machine: &MachineMeta,
+                           guest_machine: &MachineMeta,
+                           guestid: Option<u64>,
+                           vcpus: u64,
+                           entry: u64,
+                           dtb: u64,
+                           image: u64,
+                           dtb_template: &'static [u8],
+                           dma: u64) {
     let mut irq_map = [IrqMapping::Ignored; 512];
     let mut virtio_devices = ArrayVec::new();
+    let mut virtio_guest_irqs = ArrayVec::new();
+    let shared_disk = SHARED_STATICS.shared_disk.lock().as_ref().map(|d| d.index());
     for i in 0..4 {
+        let guest_irq = guest_machine.virtio.iter()
+            .find(|d| d.base_address == 0x10001000 + 0x1000 * i as u64)
+            .unwrap()
+            .irq as u16;
+        virtio_guest_irqs.push(guest_irq);
+
+        // Host transports with no device behind them, and the disk that the guests share, are left
+        // free for emulated devices.
         let index = (guestid.unwrap_or(1) as usize - 1) * 4 + i;
-        if index < machine.virtio.len() {
-            virtio_devices.push(virtio::Device::new(machine.virtio[index].base_address));
+        if index < machine.virtio.len() && Some(index) != shared_disk
+            && *(pmap::pa2va(machine.virtio[index].base_address + 0x8) as *const u32) != 0 {
+            let rings = dma + pmap::DMA_SIZE / 4 + virtio::PASSTHROUGH_MEMORY_SIZE * i as u64;
+            virtio_devices.push(virtio::Device::new(machine.virtio[index].base_address, rings));
             let host_irq = machine.virtio[index].irq;
-            let mut guest_irq = None;
-            for j in 0..4 {
-                if guest_machine.virtio[j].base_address == 0x10001000 + 0x1000 * i as u64 {
-                    guest_irq = Some(guest_machine.virtio[j].irq);
-                    break;
-                }
-            }
             assert_eq!(irq_map[host_irq as usize], IrqMapping::Ignored);
             irq_map[host_irq as usize] = IrqMapping::Virtio {
                 device_index: i as u8,
-                guest_irq: guest_irq.unwrap() as u16
+                guest_irq,
             };
         } else {
             virtio_devices.push(virtio::Device::Unmapped);
         }
     }
 
-    let plic_context = machine.harts.iter().find(|h| h.hartid == hartid).unwrap().plic_context;
+    // Every guest gets an entropy device, so it takes the first free slot ahead of the optional
+    // devices below.
+    if let Some(slot) = virtio_devices.iter_mut().find(|d| match d { virtio::Device::Unmapped => true, _ => false }) {
+        *slot = virtio::Device::Rng(GuestDevice::new(RngDriver));
+    } else {
+        println!("WARN: No virtio slot left for the entropy device of guest {}", guestid.unwrap_or(1));
+    }
+
+    // On machines with a Cadence GEM, the first guest gets a virtio-net device backed by it. If asked
+    // to, and there is a switch, the same controller is its uplink.
+    if let (1, Some(macb)) = (guestid.unwrap_or(1), &machine.macb) {
+        if let Some(i) = virtio_devices.iter().position(|d| match d { virtio::Device::Unmapped => true, _ => false }) {
+            let uplink = machine.uplink && guestid.is_some();
+            let driver = MacbDriver::new(macb.base_address, machine.macb_mac, dma, uplink);
+            virtio_devices[i] = virtio::Device::Macb(GuestDevice::new(driver));
+            assert_eq!(irq_map[macb.irq as usize], IrqMapping::Ignored);
+            irq_map[macb.irq as usize] = IrqMapping::Virtio {
+                device_index: i as u8,
+                guest_irq: virtio_guest_irqs[i],
+            };
+        }
+    }
+
+    // If a disk image was provided, the first guest gets a block device backed by it.
+    if guestid.unwrap_or(1) == 1 {
+        if let Some(slot) = virtio_devices.iter_mut().find(|d| match d { virtio::Device::Unmapped => true, _ => false }) {
+            if let Some(disk) = SHARED_STATICS.ram_disk.lock().take() {
+                *slot = virtio::Device::Blk(GuestDevice::new(RamDiskDriver::new(disk)));
+            }
+        }
+    }
+
+    // When a host disk is shared, every guest gets a block device for its part of it. With
+    // overlays, that is the whole disk, and the guest's writes go to the memory below `dma`.
+    let overlay = if machine.disk_overlay {
+        let pa = dma - pmap::OVERLAY_SIZE;
+        Some(MemoryRegion::with_base_address(pmap::pa2va(pa), pa, pmap::OVERLAY_SIZE))
+    } else {
+        None
+    };
+    if let Some(driver) = PartitionDriver::new(guestid.unwrap_or(1) as usize - 1, overlay) {
+        if let Some(slot) = virtio_devices.iter_mut().find(|d| match d { virtio::Device::Unmapped => true, _ => false }) {
+            *slot = virtio::Device::Partition(GuestDevice::new(driver));
+        }
+    }
+
+    // The paravirtual console takes the next slot without a host device behind it.
+    if let Some(slot) = virtio_devices.iter_mut().find(|d| match d { virtio::Device::Unmapped => true, _ => false }) {
+        *slot = virtio::Device::Console(GuestDevice::new(ConsoleDriver::new(guestid)));
+    }
+
+    // When there are several guests, each of them is connected to the others through the switch.
+    if let Some(id) = guestid {
+        if let Some(slot) = virtio_devices.iter_mut().find(|d| match d { virtio::Device::Unmapped => true, _ => false }) {
+            *slot = virtio::Device::Switch(GuestDevice::new(SwitchPortDriver::new(id as usize - 1)));
+        }
+    }
+
+    // The control guest, if there is one, gets a socket device for managing the others.
+    if guestid.unwrap_or(1) == machine.control_guest {
+        if let Some(slot) = virtio_devices.iter_mut().find(|d| match d { virtio::Device::Unmapped => true, _ => false }) {
+            *slot = virtio::Device::Vsock(GuestDevice::new(VsockDriver::new(guestid.unwrap_or(1) as usize - 1)));
+        }
+    }
 
-    let host_clint = match machine.clint_address {
-        Some(address) => HostClint::Direct {
-            mtime: MemoryRegion::with_base_address(pmap::pa2va(address + 0xbff8), 0, 8),
+    let guest = Guest {
+        plic: PlicState::new(),
+        uart: Uart::new(guestid),
+        virtio: VirtIO {
+            devices: virtio_devices,
+            guest_irqs: virtio_guest_irqs,
+            queue_guest_pages: ArrayVec::new(),
         },
-        None => HostClint::Sbi,
+        vcpus: (0..vcpus).map(|i| VirtualHart {
+            hartid: None,
+            running: false,
+            requests: 0,
+            flush_range: VirtualHart::EMPTY_RANGE,
+            status: if i == 0 { HartStatus::Started } else { HartStatus::Stopped },
+        }).collect(),
+        irq_map,
+        entry,
+        dtb,
+        image,
+        dtb_template,
+        bootargs: machine.bootargs.clone(),
     };
 
+    *SHARED_STATICS.guest(guestid.unwrap_or(1) as usize - 1).lock() = Some(guest);
+    SHARED_STATICS.guest_control(guestid.unwrap_or(1) as usize - 1).set_vcpus(vcpus);
+}
+
+/// Create the context for one vCPU. It starts out switched out, about to execute the guest entry
+/// point with the hartid in a0 and the device tree in a1. Only the first vCPU of a guest begins in
+/// that state; the rest wait to be started through SBI HSM calls.
+pub unsafe fn create_context(machine: &MachineMeta,
+                             shadow_page_tables: PageTables,
+                             guest_memory: MemoryRegion,
+                             guest_shift: u64,
+                             hartid: u64,
+                             guestid: Option<u64>,
+                             vcpu: u64,
+                             entry: u64,
+                             dtb: u64) -> Context {
+    let guest_index = guestid.unwrap_or(1) as usize - 1;
+    let guest = SHARED_STATICS.guest(guest_index);
+    guest.lock().as_mut().unwrap().vcpus[vcpu as usize].hartid = Some(hartid);
+    SHARED_STATICS.guest_control(guest_index).add_hart(hartid);
+    if vcpu == 0 {
+        SHARED_STATICS.switch.lock().set_hart(guestid.unwrap_or(1) as usize - 1, hartid);
+        SHARED_STATICS.shmem.attach(guest_index, hartid);
+    }
+
+    let plic_context = machine.harts.iter().find(|h| h.hartid == hartid).unwrap().plic_context;
+
+    let host_clint = HostClint::new(machine.clint_address);
+
     let test_finisher = match (guestid, machine.test_finisher_address) {
         (None, Some(pa)) => Some(TestFinisher {
             registers: MemoryRegion::with_base_address(pmap::pa2va(pa), 0, 8)
@@ -479,40 +771,26 @@ pub unsafe fn initialize(machine: &MachineMeta,
         _ => None,
     };
 
-    let context = Context {
-        csrs: ControlRegisters {
-            sstatus: 0,
-            stvec: 0,
-            sie: 0,
-            sip: 0,
-            sscratch: 0,
-            sepc: 0,
-            scause: 0,
-            stval: 0,
-            satp: 0,
+    let mut registers = [0; 32];
+    registers[10] = vcpu;
+    registers[11] = dtb;
 
-            mtimecmp: u64::max_value(),
-        },
+    Context {
+        csrs: ControlRegisters::new(),
+        guest,
+        guest_index,
+        vcpu: vcpu as usize,
         saved_registers: SavedRegisters {
             registers: MemoryRegion::with_base_address(SSTACK_BASE, 0, 32 * 8)
         },
+        switched_out: SwitchedOutState {
+            registers,
+            pc: entry,
+            fs: 0,
+            fp_registers: [0; 33],
+        },
         guest_memory,
         shadow_page_tables,
-        plic: PlicState::new(),
-        uart: Uart {
-            dlab: false,
-            interrupt_enable: 0,
-            divisor_latch: 1,
-            next_interrupt_time: 0,
-            input_fifo: [0; 16],
-            input_bytes_ready: 0,
-            line_buffer: ArrayVec::new(),
-            guestid,
-        },
-        virtio: VirtIO {
-            devices: virtio_devices,
-            queue_guest_pages: ArrayVec::new(),
-        },
         guest_shift,
         smode: true,
         no_interrupt: true,
@@ -524,13 +802,5 @@ pub unsafe fn initialize(machine: &MachineMeta,
         consecutive_page_fault_count: 0,
         tlb_caches_invalid_ptes: false,
         test_finisher,
-        irq_map,
-    };
-
-    // Memory backing for CONTEXT might not be in a valid state, so force_unlock() first, and avoid
-    // calling drop on the old contents. This is safe because no other hart will be trying to access
-    // this memory right now.
-    CONTEXT.force_unlock();
-    let old = CONTEXT.lock().replace(context);
-    core::mem::forget(old);
+    }
 }
@@ -0,0 +1,194 @@
+// References:
+//
+// https://docs.oasis-open.org/virtio/virtio/v1.1/cs01/virtio-v1.1-cs01.html#x1-2390002
+
+use arrayvec::ArrayVec;
+use byteorder::{ByteOrder, LittleEndian};
+use crate::memory_region::MemoryRegion;
+use super::*;
+
+pub(super) const REQUEST_QUEUE: u32 = 0;
+
+pub(super) const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+pub(super) const VIRTIO_BLK_T_IN: u32 = 0;
+pub(super) const VIRTIO_BLK_T_OUT: u32 = 1;
+pub(super) const VIRTIO_BLK_T_FLUSH: u32 = 4;
+pub(super) const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+pub(super) const VIRTIO_BLK_S_OK: u8 = 0;
+pub(super) const VIRTIO_BLK_S_IOERR: u8 = 1;
+pub(super) const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+pub(super) const SECTOR_SIZE: u64 = 512;
+/// Length of the type, reserved and sector fields at the start of every request.
+pub(super) const REQUEST_HEADER_LEN: u32 = 16;
+const ID_LEN: usize = 20;
+const DISK_ID: &[u8] = b"rvirt-ramdisk";
+
+/// A virtio-blk request taken from a guest queue: a header the device reads, the data buffers, and
+/// a final byte the device writes the status into.
+pub(super) struct Request {
+    pub request_type: u32,
+    pub sector: u64,
+    /// Guest physical address, length and flags of each data buffer.
+    pub data: ArrayVec<[(u64, u32, u16); 16]>,
+    status: u64,
+}
+
+impl Request {
+    /// Make sense of the descriptor chain of a request. Returns None if it is malformed or reaches
+    /// outside of guest memory, in which case there is nowhere to report an error.
+    pub fn parse(guest_memory: &MemoryRegion, descriptors: &[(u64, u32, u16)]) -> Option<Self> {
+        let in_guest_memory = |&(addr, len, _): &(u64, u32, u16)| {
+            guest_memory.in_region(addr) && (len == 0 || addr.checked_add(len as u64 - 1)
+                .map(|end| guest_memory.in_region(end))
+                .unwrap_or(false))
+        };
+        if descriptors.len() < 2 || !descriptors.iter().all(in_guest_memory) {
+            return None;
+        }
+
+        let (header, header_len, header_flags) = descriptors[0];
+        let (status, status_len, status_flags) = descriptors[descriptors.len() - 1];
+        if header_len < REQUEST_HEADER_LEN || header_flags & VIRTQ_DESC_F_WRITE != 0
+            || status_len == 0 || status_flags & VIRTQ_DESC_F_WRITE == 0 {
+            return None;
+        }
+
+        // Any bytes of the last descriptor before the status byte still hold data.
+        let mut data = ArrayVec::new();
+        data.extend(descriptors[1..descriptors.len() - 1].iter().cloned());
+        if status_len > 1 {
+            data.push((status, status_len - 1, status_flags));
+        }
+
+        Some(Self {
+            request_type: LittleEndian::read_u32(guest_memory.slice(header, 4)),
+            sector: LittleEndian::read_u64(guest_memory.slice(header + 8, 8)),
+            data,
+            status: status + status_len as u64 - 1,
+        })
+    }
+
+    /// Store `status` for the guest, and return the total number of bytes written to guest memory
+    /// given that `written` bytes of data were.
+    pub fn complete(&self, guest_memory: &mut MemoryRegion, status: u8, written: u32) -> u32 {
+        guest_memory.slice_mut(self.status, 1)[0] = status;
+        written + 1
+    }
+}
+
+/// Fill the first data buffer of a GET_ID request with `id`, padded with zeroes. Returns the
+/// status and the number of bytes written.
+pub(super) fn write_id(guest_memory: &mut MemoryRegion, request: &Request, id: &[u8]) -> (u8, u32) {
+    match request.data.first() {
+        Some(&(addr, len, flags)) if flags & VIRTQ_DESC_F_WRITE != 0 => {
+            let len = (len as usize).min(ID_LEN);
+            for (i, byte) in guest_memory.slice_mut(addr, len as u64).iter_mut().enumerate() {
+                *byte = id.get(i).cloned().unwrap_or(0);
+            }
+            (VIRTIO_BLK_S_OK, len as u32)
+        }
+        _ => (VIRTIO_BLK_S_IOERR, 0),
+    }
+}
+
+/// Virtio block device backed by a disk image held in host memory. Since the image never leaves
+/// memory, writes are visible immediately and flushes have nothing to do.
+pub struct RamDiskDriver {
+    disk: MemoryRegion,
+}
+
+impl RamDiskDriver {
+    /// Create a device for the image in `disk`, whose length must be a whole number of sectors.
+    pub fn new(disk: MemoryRegion) -> Self {
+        assert_eq!(disk.len() % SECTOR_SIZE, 0);
+        Self { disk }
+    }
+
+    /// Carry out the request made up of `descriptors`. Returns the number of bytes written to guest
+    /// memory.
+    fn handle_request(&mut self, guest_memory: &mut MemoryRegion, descriptors: &[(u64, u32, u16)]) -> u32 {
+        let request = match Request::parse(guest_memory, descriptors) {
+            Some(request) => request,
+            None => return 0,
+        };
+
+        let (status, written) = match request.request_type {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => {
+                match self.transfer(guest_memory, request.sector, &request.data, request.request_type == VIRTIO_BLK_T_OUT) {
+                    Some(written) => (VIRTIO_BLK_S_OK, written),
+                    None => (VIRTIO_BLK_S_IOERR, 0),
+                }
+            }
+            VIRTIO_BLK_T_FLUSH => (VIRTIO_BLK_S_OK, 0),
+            VIRTIO_BLK_T_GET_ID => write_id(guest_memory, &request, DISK_ID),
+            _ => (VIRTIO_BLK_S_UNSUPP, 0),
+        };
+        request.complete(guest_memory, status, written)
+    }
+
+    /// Copy between the disk, starting at `sector`, and the data buffers of a request. Returns the
+    /// number of bytes written to guest memory, or None if the request runs past the end of the
+    /// disk or has a buffer that points the wrong way.
+    fn transfer(&mut self, guest_memory: &mut MemoryRegion, sector: u64, data: &[(u64, u32, u16)], write: bool) -> Option<u32> {
+        let mut offset = sector.checked_mul(SECTOR_SIZE)?;
+        let mut written = 0;
+        for &(addr, len, flags) in data {
+            let end = offset.checked_add(len as u64)?;
+            if end > self.disk.len() || write == (flags & VIRTQ_DESC_F_WRITE != 0) {
+                return None;
+            }
+            if len == 0 {
+                // There is nothing to copy, and the offset may be the end of the disk.
+                continue;
+            }
+
+            if write {
+                self.disk.slice_mut(offset, len as u64).copy_from_slice(guest_memory.slice(addr, len as u64));
+            } else {
+                guest_memory.slice_mut(addr, len as u64).copy_from_slice(self.disk.slice(offset, len as u64));
+                written += len;
+            }
+            offset = end;
+        }
+        Some(written)
+    }
+}
+
+impl Driver for RamDiskDriver {
+    const DEVICE_ID: u32 = 2;
+    const FEATURES: u64 = VIRTIO_BLK_F_FLUSH;
+    const QUEUE_NUM_MAX: u32 = 64;
+
+    fn interrupt(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) -> bool {
+        false
+    }
+    fn doorbell(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion, queue: u32) {
+        if queue != REQUEST_QUEUE || !device.queue_enabled(queue) {
+            return;
+        }
+
+        let mut completed = false;
+        while device.with_descriptors(guest_memory, REQUEST_QUEUE, |driver, guest_memory, descriptors| {
+            Some(driver.handle_request(guest_memory, descriptors))
+        }) {
+            completed = true;
+        }
+        if completed {
+            device.signal_used(guest_memory, REQUEST_QUEUE);
+        }
+    }
+
+    fn read_config_u8(device: &GuestDevice<Self>, _guest_memory: &mut MemoryRegion, offset: u64) -> u8 {
+        let capacity = device.host_driver.disk.len() / SECTOR_SIZE;
+        match offset {
+            0..=7 => capacity.to_le_bytes()[offset as usize],
+            _ => 0
+        }
+    }
+    fn write_config_u8(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion, _offset: u64, _value: u8) {}
+
+    fn reset(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) {}
+}
@@ -0,0 +1,97 @@
+// References:
+//
+// https://docs.oasis-open.org/virtio/virtio/v1.1/cs01/virtio-v1.1-cs01.html#x1-2900003
+
+use crate::memory_region::MemoryRegion;
+use crate::print::ConsoleOutput;
+use crate::statics::SHARED_STATICS;
+use super::*;
+
+const RECEIVE_QUEUE: u32 = 0;
+const TRANSMIT_QUEUE: u32 = 1;
+
+/// Paravirtual console backed by the host UART. Unlike the emulated 16550, which traps on every
+/// byte, whole buffers are moved on each doorbell.
+pub struct ConsoleDriver {
+    output: ConsoleOutput,
+}
+
+impl ConsoleDriver {
+    pub fn new(guestid: Option<u64>) -> Self {
+        Self {
+            output: ConsoleOutput::new(guestid),
+        }
+    }
+}
+
+impl Driver for ConsoleDriver {
+    const DEVICE_ID: u32 = 3;
+    const FEATURES: u64 = 0;
+    const QUEUE_NUM_MAX: u32 = 64;
+
+    fn interrupt(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) -> bool {
+        false
+    }
+    fn doorbell(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion, queue: u32) {
+        if !device.queue_enabled(queue) {
+            return;
+        }
+
+        match queue {
+            RECEIVE_QUEUE => device.poll_input(guest_memory),
+            TRANSMIT_QUEUE => {
+                let mut transmitted = false;
+                while device.with_buffer(guest_memory, TRANSMIT_QUEUE, |driver, buffers| {
+                    for buffer in buffers {
+                        for &byte in buffer.iter() {
+                            driver.output.output_byte(byte);
+                        }
+                    }
+                    Some(0)
+                }) {
+                    transmitted = true;
+                }
+                if transmitted {
+                    device.signal_used(guest_memory, TRANSMIT_QUEUE);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_config_u8(_device: &GuestDevice<Self>, _guest_memory: &mut MemoryRegion, _offset: u64) -> u8 {
+        0
+    }
+    fn write_config_u8(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion, _offset: u64, _value: u8) {}
+
+    fn reset(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) {}
+}
+
+impl GuestDevice<ConsoleDriver> {
+    /// Move any host console input into buffers the guest has made available. Input is left for the
+    /// emulated UART until the guest driver has posted receive buffers.
+    pub fn poll_input(&mut self, guest_memory: &mut MemoryRegion) {
+        if !self.driver_ok() || !self.queue_enabled(RECEIVE_QUEUE) {
+            return;
+        }
+
+        let mut received = false;
+        while self.with_buffer_mut(guest_memory, RECEIVE_QUEUE, |driver, buffer| {
+            let mut uart_writer = SHARED_STATICS.uart_writer.lock();
+            let mut len = 0;
+            while len < buffer.len() {
+                match uart_writer.guest_input(driver.output.guestid) {
+                    Some(ch) => buffer[len] = ch,
+                    None => break,
+                }
+                len += 1;
+            }
+            if len > 0 { Some(len as u32) } else { None }
+        }) {
+            received = true;
+        }
+        if received {
+            self.signal_used(guest_memory, RECEIVE_QUEUE);
+        }
+    }
+}
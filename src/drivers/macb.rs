@@ -1,47 +1,231 @@
-
 // References:
 //
 // https://github.com/qemu/qemu/blob/d522fba24478474911b0e6e488b6d1dcf1af54f8/hw/net/cadence_gem.c
 // https://github.com/torvalds/linux/blob/master/drivers/net/ethernet/cadence/macb_main.c
 // https://www.yumpu.com/en/document/view/31739994/gigabit-ethernet-mac-gem-technical-data-sheet-cadence-
 
-#![allow(unused)]
-
+use byteorder::{ByteOrder, LittleEndian};
 use crate::memory_region::MemoryRegion;
+use crate::pmap;
+use crate::statics::SHARED_STATICS;
 use super::*;
+use super::switch::{Switch, UPLINK};
 
+const GEM_NCR: u64 = 0x00000000;
+const GEM_NCFGR: u64 = 0x00000004;
 const GEM_DMACFG: u64 = 0x00000010;
+const GEM_TSR: u64 = 0x00000014;
+const GEM_RBQP: u64 = 0x00000018;
+const GEM_TBQP: u64 = 0x0000001c;
+const GEM_RSR: u64 = 0x00000020;
+const GEM_ISR: u64 = 0x00000024;
+const GEM_IER: u64 = 0x00000028;
+const GEM_IDR: u64 = 0x0000002c;
+const GEM_SA1B: u64 = 0x00000088;
+const GEM_SA1T: u64 = 0x0000008c;
+const GEM_TBQPH: u64 = 0x000004c8;
+const GEM_RBQPH: u64 = 0x000004d4;
+
+const GEM_NCR_RE: u32 = 1 << 2;
+const GEM_NCR_TE: u32 = 1 << 3;
+const GEM_NCR_TSTART: u32 = 1 << 9;
 
+const GEM_NCFGR_CAF: u32 = 1 << 4;
+const GEM_NCFGR_DRFCS: u32 = 1 << 17;
+
+const GEM_DMACFG_INCR4: u32 = 4;
+const GEM_DMACFG_RXBMS_FULL: u32 = 3 << 8;
+const GEM_DMACFG_TXPBMS: u32 = 1 << 10;
+const GEM_DMACFG_RXBS_SHIFT: u32 = 16;
 const GEM_DMACFG_ADDR_64B: u32 = 1 << 30;
 
-const VIRTIO_MTU: u16 = 2048;
+const GEM_INT_RCOMP: u32 = 1 << 1;
+const GEM_INT_TCOMP: u32 = 1 << 7;
+
+// Descriptors are four words long since 64-bit addressing is used: the low address word, a control
+// and status word, the high address word and one unused word.
+const RX_DESC_USED: u32 = 1 << 0;
+const RX_DESC_WRAP: u32 = 1 << 1;
+const RX_DESC_LENGTH_MASK: u32 = 0x1fff;
+const RX_DESC_SOF: u32 = 1 << 14;
+const RX_DESC_EOF: u32 = 1 << 15;
+const TX_DESC_LAST: u32 = 1 << 15;
+const TX_DESC_WRAP: u32 = 1 << 30;
+const TX_DESC_USED: u32 = 1 << 31;
 
-#[repr(transparent)]
-struct RxDesc([u32; 4]);
-#[repr(transparent)]
-struct TxDesc([u32; 4]);
+const RING_SIZE: u64 = 8;
+const BUFFER_SIZE: u64 = 2048;
+
+// Layout of the DMA region.
+const RX_RING: u64 = 0x0;
+const TX_RING: u64 = RX_RING + RING_SIZE * 16;
+const RX_BUFFERS: u64 = 0x1000;
+const TX_BUFFERS: u64 = RX_BUFFERS + RING_SIZE * BUFFER_SIZE;
+
+const RECEIVE_QUEUE: u32 = 0;
+const TRANSMIT_QUEUE: u32 = 1;
+
+/// Size of the `virtio_net_hdr` that precedes every packet. With VIRTIO_F_VERSION_1 it always
+/// includes the `num_buffers` field.
+const VIRTIO_NET_HDR_LEN: usize = 12;
+
+/// Keeping to the standard Ethernet MTU means Linux posts receive buffers large enough for a whole
+/// frame in a single descriptor.
+const VIRTIO_MTU: u16 = 1500;
 
 /// Driver for the Cadence GEM Ethernet device.
 pub struct MacbDriver {
     control_registers: MemoryRegion<u32>,
     mac: [u8; 6],
 
-    rx_buffers: [[u8; 2048]; 8],
-    rx_queue: [RxDesc; 8],
-    tx_buffers: [[u8; 2048]; 8],
-    tx_queue: [TxDesc; 8],
+    /// Descriptor rings and packet buffers. The device accesses these by physical address, so they
+    /// live in memory reserved for the purpose rather than inside this struct.
+    dma: MemoryRegion,
+    /// Next receive descriptor the device will complete.
+    rx_next: u64,
+    /// Next transmit descriptor to hand to the device.
+    tx_next: u64,
+    started: bool,
+    /// Whether the controller is also the uplink of the switch between guests. It then receives
+    /// every frame on the wire, passes the guest only those for its own or a group address, and
+    /// hands everything not for the guest alone to the switch.
+    uplink: bool,
+}
+
+impl MacbDriver {
+    /// Create a driver for the controller with registers at host physical address `base_address`,
+    /// using the first quarter of the `pmap::DMA_SIZE` bytes at host physical address `dma` for its
+    /// buffers. An uplink is started right away, so that the other guests can reach the network
+    /// whether or not this one ever brings up its interface.
+    pub unsafe fn new(base_address: u64, mac: [u8; 6], dma: u64, uplink: bool) -> Self {
+        let mut driver = Self {
+            control_registers: MemoryRegion::with_base_address(pmap::pa2va(base_address), 0, 0x2000),
+            mac,
+            dma: MemoryRegion::with_base_address(pmap::pa2va(dma), dma, pmap::DMA_SIZE / 4),
+            rx_next: 0,
+            tx_next: 0,
+            started: false,
+            uplink,
+        };
+        driver.stop();
+        if uplink {
+            SHARED_STATICS.switch.lock().attach(UPLINK);
+            driver.start();
+        }
+        driver
+    }
+
+    fn read_dma(&self, offset: u64) -> u32 {
+        let base = self.dma.base();
+        LittleEndian::read_u32(self.dma.slice(base + offset, 4))
+    }
+    fn write_dma(&mut self, offset: u64, value: u32) {
+        let base = self.dma.base();
+        LittleEndian::write_u32(self.dma.slice_mut(base + offset, 4), value)
+    }
+
+    fn rx_buffer(&self, index: u64, len: u64) -> &[u8] {
+        self.dma.slice(self.dma.base() + RX_BUFFERS + index * BUFFER_SIZE, len)
+    }
+    fn tx_buffer(&mut self, index: u64) -> &mut [u8] {
+        let base = self.dma.base();
+        self.dma.slice_mut(base + TX_BUFFERS + index * BUFFER_SIZE, BUFFER_SIZE)
+    }
+
+    /// Initialize the descriptor rings and enable the receiver and transmitter. Other settings,
+    /// like the link speed and the management port for the PHY, are left as the boot loader
+    /// configured them.
+    fn start(&mut self) {
+        let base = self.dma.base();
+        for i in 0..RING_SIZE {
+            let wrap = i == RING_SIZE - 1;
+
+            let rx = base + RX_BUFFERS + i * BUFFER_SIZE;
+            self.write_dma(RX_RING + i * 16, rx as u32 | if wrap { RX_DESC_WRAP } else { 0 });
+            self.write_dma(RX_RING + i * 16 + 4, 0);
+            self.write_dma(RX_RING + i * 16 + 8, (rx >> 32) as u32);
+
+            // Transmit descriptors start out owned by software.
+            let tx = base + TX_BUFFERS + i * BUFFER_SIZE;
+            self.write_dma(TX_RING + i * 16, tx as u32);
+            self.write_dma(TX_RING + i * 16 + 4, TX_DESC_USED | if wrap { TX_DESC_WRAP } else { 0 });
+            self.write_dma(TX_RING + i * 16 + 8, (tx >> 32) as u32);
+        }
+        self.rx_next = 0;
+        self.tx_next = 0;
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+
+        let regs = &mut self.control_registers;
+        regs[GEM_DMACFG] = GEM_DMACFG_ADDR_64B | ((BUFFER_SIZE / 64) as u32) << GEM_DMACFG_RXBS_SHIFT |
+            GEM_DMACFG_TXPBMS | GEM_DMACFG_RXBMS_FULL | GEM_DMACFG_INCR4;
+        regs[GEM_NCFGR] |= GEM_NCFGR_DRFCS;
+        if self.uplink {
+            regs[GEM_NCFGR] |= GEM_NCFGR_CAF;
+        }
+        regs[GEM_RBQP] = (base + RX_RING) as u32;
+        regs[GEM_RBQPH] = ((base + RX_RING) >> 32) as u32;
+        regs[GEM_TBQP] = (base + TX_RING) as u32;
+        regs[GEM_TBQPH] = ((base + TX_RING) >> 32) as u32;
+        regs[GEM_TSR] = !0;
+        regs[GEM_RSR] = !0;
+        regs[GEM_IER] = GEM_INT_RCOMP | GEM_INT_TCOMP;
+        regs[GEM_NCR] |= GEM_NCR_RE | GEM_NCR_TE;
+
+        self.set_mac();
+        self.started = true;
+    }
+
+    fn stop(&mut self) {
+        self.control_registers[GEM_IDR] = !0;
+        self.control_registers[GEM_NCR] &= !(GEM_NCR_RE | GEM_NCR_TE);
+        self.started = false;
+    }
+
+    fn set_mac(&mut self) {
+        self.control_registers[GEM_SA1B] = u32::from_le_bytes([self.mac[0], self.mac[1], self.mac[2], self.mac[3]]);
+        self.control_registers[GEM_SA1T] = u32::from_le_bytes([self.mac[4], self.mac[5], 0, 0]);
+    }
 }
 
 impl Driver for MacbDriver {
     const DEVICE_ID: u32 = 1;
     const FEATURES: u64 = VIRTIO_NET_F_MAC | VIRTIO_NET_F_MTU;
-    const QUEUE_NUM_MAX: u32 = 2;
+    const QUEUE_NUM_MAX: u32 = 64;
+
+    fn interrupt(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion) -> bool {
+        // Reading the interrupt status clears it, which also deasserts the interrupt line.
+        let status = device.host_driver.control_registers[GEM_ISR];
+        device.host_driver.control_registers[GEM_TSR] = !0;
+        device.host_driver.control_registers[GEM_RSR] = !0;
 
-    fn interrupt(device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) -> bool {
-        false
+        if device.host_driver.started {
+            if status & GEM_INT_RCOMP != 0 {
+                device.receive(guest_memory);
+            }
+            if status & GEM_INT_TCOMP != 0 {
+                // Descriptors were freed up, so transmission may be able to continue.
+                device.transmit(guest_memory);
+                device.transmit_uplink();
+            }
+        }
+        device.take_interrupt()
     }
-    fn doorbell(device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion, queue: u32) {
+    fn doorbell(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion, queue: u32) {
+        if !device.driver_ok() || !device.queue_enabled(queue) {
+            return;
+        }
+
+        // Linux makes receive buffers available as soon as the interface is brought up, so the
+        // first doorbell is a good time to start the controller.
+        if !device.host_driver.started {
+            device.host_driver.start();
+        }
 
+        match queue {
+            RECEIVE_QUEUE => device.receive(guest_memory),
+            TRANSMIT_QUEUE => device.transmit(guest_memory),
+            _ => {}
+        }
     }
 
     fn read_config_u8(device: &GuestDevice<Self>, _guest_memory: &mut MemoryRegion, offset: u64) -> u8 {
@@ -56,13 +240,169 @@ impl Driver for MacbDriver {
         match offset {
             0..=5 => {
                 device.host_driver.mac[offset as usize] = value;
-                unimplemented!(); // TODO: set device MAC to updated value
+                device.host_driver.set_mac();
             }
             _ => {}
         }
     }
 
     fn reset(device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) {
+        // An uplink keeps running for the other guests.
+        if !device.host_driver.uplink {
+            device.host_driver.stop();
+        }
+    }
+}
+
+impl GuestDevice<MacbDriver> {
+    /// Copy frames the controller has received into buffers the guest made available, and pass
+    /// those not only for the guest on to the switch if this is its uplink. Frames for the guest
+    /// stay in the receive ring while it has no buffers, and the controller drops anything that
+    /// arrives once the ring is full.
+    fn receive(&mut self, guest_memory: &mut MemoryRegion) {
+        // The rings of a queue are only checked once it is enabled, so until then it mustn't be read.
+        let guest_ready = self.driver_ok() && self.queue_enabled(RECEIVE_QUEUE);
+        let mut received = false;
+        let mut notify = 0;
+        loop {
+            let index = self.host_driver.rx_next;
+            let desc = RX_RING + index * 16;
+            let addr = self.host_driver.read_dma(desc);
+            if addr & RX_DESC_USED == 0 {
+                break;
+            }
+
+            let status = self.host_driver.read_dma(desc + 4);
+            let len = (status & RX_DESC_LENGTH_MASK) as usize;
+
+            // Buffers are large enough that frames never span several of them, so anything else is
+            // dropped.
+            let complete = status & (RX_DESC_SOF | RX_DESC_EOF) == RX_DESC_SOF | RX_DESC_EOF;
+            let destination = self.host_driver.rx_buffer(index, 6);
+            let own = destination == self.host_driver.mac;
+            let for_guest = !self.host_driver.uplink || own || destination[0] & 1 != 0;
+            let for_switch = self.host_driver.uplink && !own;
+
+            if complete && for_guest && !guest_ready && !self.host_driver.uplink {
+                // Leave the frame for when the guest is ready. An uplink instead drops it for the
+                // guest, so as not to hold up the others.
+                break;
+            }
+            if complete && for_guest && guest_ready {
+                let delivered = self.with_buffer_mut(guest_memory, RECEIVE_QUEUE, |driver, buffer| {
+                    if buffer.len() < VIRTIO_NET_HDR_LEN + len {
+                        return Some(0);
+                    }
+
+                    for byte in &mut buffer[..VIRTIO_NET_HDR_LEN] {
+                        *byte = 0;
+                    }
+                    LittleEndian::write_u16(&mut buffer[10..], 1); // num_buffers
+                    buffer[VIRTIO_NET_HDR_LEN..][..len].copy_from_slice(driver.rx_buffer(index, len as u64));
+                    Some((VIRTIO_NET_HDR_LEN + len) as u32)
+                });
+                if !delivered {
+                    break;
+                }
+                received = true;
+            }
+            if complete && for_switch {
+                notify |= SHARED_STATICS.switch.lock().forward(UPLINK, self.host_driver.rx_buffer(index, len as u64));
+            }
+
+            // Hand the descriptor back to the controller.
+            self.host_driver.write_dma(desc, addr & !RX_DESC_USED);
+            self.host_driver.rx_next = (index + 1) % RING_SIZE;
+        }
+
+        if received {
+            self.signal_used(guest_memory, RECEIVE_QUEUE);
+        }
+        Switch::notify(notify);
+    }
 
+    /// Move packets the guest has queued for transmission into free transmit descriptors, and
+    /// start the controller on them.
+    fn transmit(&mut self, guest_memory: &mut MemoryRegion) {
+        let mut transmitted = false;
+        loop {
+            let index = self.host_driver.tx_next;
+            let desc = TX_RING + index * 16;
+            if self.host_driver.read_dma(desc + 4) & TX_DESC_USED == 0 {
+                break;
+            }
+
+            let mut queued = false;
+            let consumed = self.with_buffer(guest_memory, TRANSMIT_QUEUE, |driver, buffers| {
+                let tx_buffer = driver.tx_buffer(index);
+                let mut skip = VIRTIO_NET_HDR_LEN;
+                let mut len = 0;
+                for buffer in buffers {
+                    let data = &buffer[skip.min(buffer.len())..];
+                    skip -= skip.min(buffer.len());
+                    if len + data.len() > tx_buffer.len() {
+                        // Too large to be a valid frame, so drop it.
+                        return Some(0);
+                    }
+                    tx_buffer[len..][..data.len()].copy_from_slice(data);
+                    len += data.len();
+                }
+
+                if len > 0 {
+                    let wrap = if index == RING_SIZE - 1 { TX_DESC_WRAP } else { 0 };
+                    core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+                    driver.write_dma(desc + 4, len as u32 | TX_DESC_LAST | wrap);
+                    queued = true;
+                }
+                Some(0)
+            });
+            if !consumed {
+                break;
+            }
+
+            transmitted = true;
+            if queued {
+                self.host_driver.tx_next = (index + 1) % RING_SIZE;
+            }
+        }
+
+        if transmitted {
+            core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+            self.host_driver.control_registers[GEM_NCR] |= GEM_NCR_TSTART;
+            self.signal_used(guest_memory, TRANSMIT_QUEUE);
+        }
+    }
+
+    /// Move frames the switch has queued for the uplink into free transmit descriptors, and start
+    /// the controller on them. Frames the first guest sends through this device itself only go out
+    /// on the wire; it reaches the other guests through its own port on the switch.
+    pub fn transmit_uplink(&mut self) {
+        if !self.host_driver.uplink || !self.host_driver.started {
+            return;
+        }
+
+        let mut queued = false;
+        let mut switch = SHARED_STATICS.switch.lock();
+        while let Some(frame) = switch.front(UPLINK) {
+            let index = self.host_driver.tx_next;
+            let desc = TX_RING + index * 16;
+            if self.host_driver.read_dma(desc + 4) & TX_DESC_USED == 0 {
+                break;
+            }
+
+            self.host_driver.tx_buffer(index)[..frame.len()].copy_from_slice(frame);
+            let wrap = if index == RING_SIZE - 1 { TX_DESC_WRAP } else { 0 };
+            core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+            self.host_driver.write_dma(desc + 4, frame.len() as u32 | TX_DESC_LAST | wrap);
+            self.host_driver.tx_next = (index + 1) % RING_SIZE;
+            switch.pop(UPLINK);
+            queued = true;
+        }
+        drop(switch);
+
+        if queued {
+            core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+            self.host_driver.control_registers[GEM_NCR] |= GEM_NCR_TSTART;
+        }
     }
 }
@@ -4,7 +4,13 @@ use arrayvec::ArrayVec;
 use byteorder::{ByteOrder, LittleEndian};
 use crate::memory_region::MemoryRegion;
 
+pub mod blk;
+pub mod console;
 pub mod macb;
+pub mod partition;
+pub mod rng;
+pub mod switch;
+pub mod vsock;
 
 #[allow(unused)]
 mod constants {
@@ -19,16 +25,25 @@ mod constants {
     pub const REG_HOST_FEATURES_SEL: u64 = 0x014;
     pub const REG_GUEST_FEATURES: u64 = 0x020;
     pub const REG_GUEST_FEATURES_SEL: u64 = 0x024;
-    pub const REG_GUEST_PAGE_SIZE: u64 = 0x028;
+    pub const REG_GUEST_PAGE_SIZE: u64 = 0x028; // Legacy only
     pub const REG_QUEUE_SEL: u64 = 0x030;
     pub const REG_QUEUE_NUM_MAX: u64 = 0x034;
     pub const REG_QUEUE_NUM: u64 = 0x038;
-    pub const REG_QUEUE_ALIGN: u64 = 0x03c;
-    pub const REG_QUEUE_PFN: u64 = 0x040;
+    pub const REG_QUEUE_ALIGN: u64 = 0x03c; // Legacy only
+    pub const REG_QUEUE_PFN: u64 = 0x040; // Legacy only
+    pub const REG_QUEUE_READY: u64 = 0x044;
     pub const REG_QUEUE_NOTIFY: u64 = 0x050;
     pub const REG_INTERRUPT_STATUS: u64 = 0x060;
     pub const REG_INTERRUPT_ACK: u64 = 0x064;
     pub const REG_STATUS: u64 = 0x070;
+    pub const REG_QUEUE_DESC_LOW: u64 = 0x080;
+    pub const REG_QUEUE_DESC_HIGH: u64 = 0x084;
+    pub const REG_QUEUE_DRIVER_LOW: u64 = 0x090;
+    pub const REG_QUEUE_DRIVER_HIGH: u64 = 0x094;
+    pub const REG_QUEUE_DEVICE_LOW: u64 = 0x0a0;
+    pub const REG_QUEUE_DEVICE_HIGH: u64 = 0x0a4;
+    pub const REG_CONFIG_GENERATION: u64 = 0x0fc;
+    pub const REG_CONFIG: u64 = 0x100;
 
     pub const STATUS_ACKNOWLEDGE: u32 = 1;
     pub const STATUS_DRIVER: u32 = 2;
@@ -37,11 +52,28 @@ mod constants {
     pub const STATUS_DRIVER_OK: u32 = 4;
     pub const STATUS_NEEDS_RESET: u32 = 64;
 
+    pub const INTERRUPT_USED_BUFFER: u32 = 1;
+    pub const INTERRUPT_CONFIG_CHANGE: u32 = 2;
+
+    pub const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+    pub const VIRTIO_F_EVENT_IDX: u64 = 1 << 29;
+    pub const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+    pub const VIRTIO_F_RING_PACKED: u64 = 1 << 34;
+
     pub const VIRTIO_NET_F_MTU: u64 = 1 << 3;
     pub const VIRTIO_NET_F_MAC: u64 = 1 << 5;
 
     pub const VIRTQ_DESC_F_NEXT: u16 = 1;
     pub const VIRTQ_DESC_F_WRITE: u16 = 2;
+    pub const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+    pub const VIRTQ_DESC_F_AVAIL: u16 = 1 << 7;
+    pub const VIRTQ_DESC_F_USED: u16 = 1 << 15;
+
+    pub const VIRTQ_AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+    pub const RING_EVENT_FLAGS_ENABLE: u16 = 0;
+    pub const RING_EVENT_FLAGS_DISABLE: u16 = 1;
+    pub const RING_EVENT_FLAGS_DESC: u16 = 2;
 
     pub const MAX_QUEUES: usize = 4;
 }
@@ -75,48 +107,110 @@ pub trait Driver: Sized {
     fn reset(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion);
 }
 
+/// Virtqueue in guest memory, located by the guest physical addresses of its three parts. For a
+/// packed ring, `avail` and `used` hold the driver and device event suppression structures instead.
 pub struct DescriptorTable<'a> {
-    desc: &'a [u8],
-    avail: &'a [u8],
-    used: &'a mut [u8],
+    guest_memory: &'a mut MemoryRegion,
+    desc: u64,
+    avail: u64,
+    used: u64,
     queue_size: usize,
 }
 #[allow(unused)]
 impl<'a> DescriptorTable<'a> {
-    fn desc_addr(&self, index: usize) -> u64 { LittleEndian::read_u64(&self.desc[16*index..]) }
-    fn desc_len(&self, index: usize) -> u32 { LittleEndian::read_u32(&self.desc[8+16*index..]) }
-    fn desc_flags(&self, index: usize) -> u16 { LittleEndian::read_u16(&self.desc[12+16*index..]) }
-    fn desc_next(&self, index: usize) -> u16 { LittleEndian::read_u16(&self.desc[14+16*index..]) }
-
-    fn avail_flags(&self) -> u16 { LittleEndian::read_u16(&self.avail) }
-    fn avail_idx(&self) -> u16 { LittleEndian::read_u16(&self.avail[2..]) }
-    fn avail_ring(&self, index: usize) -> u16 { LittleEndian::read_u16(&self.avail[4+2*index..]) }
-
-    fn used_flags(&self) -> u16 { LittleEndian::read_u16(&self.used) }
-    fn used_idx(&self) -> u16 { LittleEndian::read_u16(&self.used[2..]) }
-    fn used_ring_id(&self, index: usize) -> u32 { LittleEndian::read_u32(&self.used[4+8*index..]) }
-    fn used_ring_len(&self, index: usize) -> u32 { LittleEndian::read_u32(&self.used[8+8*index..]) }
-
-    fn set_used_flags(&mut self, value: u16) { LittleEndian::write_u16(&mut self.used, value) }
-    fn set_used_idx(&mut self, value: u16) { LittleEndian::write_u16(&mut self.used[2..], value) }
-    fn set_used_ring_id(&mut self, index: usize, value: u32) { LittleEndian::write_u32(&mut self.used[4+8*index..], value) }
-    fn set_used_ring_len(&mut self, index: usize, value: u32) { LittleEndian::write_u32(&mut self.used[8+8*index..], value) }
+    fn read_u16(&self, addr: u64) -> u16 { LittleEndian::read_u16(self.guest_memory.slice(addr, 2)) }
+    fn read_u32(&self, addr: u64) -> u32 { LittleEndian::read_u32(self.guest_memory.slice(addr, 4)) }
+    fn read_u64(&self, addr: u64) -> u64 { LittleEndian::read_u64(self.guest_memory.slice(addr, 8)) }
+    fn write_u16(&mut self, addr: u64, value: u16) { LittleEndian::write_u16(self.guest_memory.slice_mut(addr, 2), value) }
+    fn write_u32(&mut self, addr: u64, value: u32) { LittleEndian::write_u32(self.guest_memory.slice_mut(addr, 4), value) }
+
+    fn desc_addr(&self, index: usize) -> u64 { self.read_u64(self.desc + 16*index as u64) }
+    fn desc_len(&self, index: usize) -> u32 { self.read_u32(self.desc + 8+16*index as u64) }
+    fn desc_flags(&self, index: usize) -> u16 { self.read_u16(self.desc + 12+16*index as u64) }
+    fn desc_next(&self, index: usize) -> u16 { self.read_u16(self.desc + 14+16*index as u64) }
+
+    fn avail_flags(&self) -> u16 { self.read_u16(self.avail) }
+    fn avail_idx(&self) -> u16 { self.read_u16(self.avail + 2) }
+    fn avail_ring(&self, index: usize) -> u16 { self.read_u16(self.avail + 4+2*index as u64) }
+
+    fn used_event(&self) -> u16 { self.read_u16(self.avail + 4+2*self.queue_size as u64) }
+
+    fn used_flags(&self) -> u16 { self.read_u16(self.used) }
+    fn used_idx(&self) -> u16 { self.read_u16(self.used + 2) }
+    fn used_ring_id(&self, index: usize) -> u32 { self.read_u32(self.used + 4+8*index as u64) }
+    fn used_ring_len(&self, index: usize) -> u32 { self.read_u32(self.used + 8+8*index as u64) }
+
+    fn set_used_flags(&mut self, value: u16) { let a = self.used; self.write_u16(a, value) }
+    fn set_used_idx(&mut self, value: u16) { let a = self.used + 2; self.write_u16(a, value) }
+    fn set_used_ring_id(&mut self, index: usize, value: u32) { let a = self.used + 4+8*index as u64; self.write_u32(a, value) }
+    fn set_used_ring_len(&mut self, index: usize, value: u32) { let a = self.used + 8+8*index as u64; self.write_u32(a, value) }
+    fn set_avail_event(&mut self, value: u16) { let a = self.used + 4+8*self.queue_size as u64; self.write_u16(a, value) }
+
+    // Packed descriptors share the address and length fields of split ones, but are followed by the
+    // buffer id and then the flags.
+    fn packed_desc_id(&self, index: usize) -> u16 { self.read_u16(self.desc + 12+16*index as u64) }
+    fn packed_desc_flags(&self, index: usize) -> u16 { self.read_u16(self.desc + 14+16*index as u64) }
+    fn set_packed_desc_id(&mut self, index: usize, value: u16) { let a = self.desc + 12+16*index as u64; self.write_u16(a, value) }
+    fn set_packed_desc_len(&mut self, index: usize, value: u32) { let a = self.desc + 8+16*index as u64; self.write_u32(a, value) }
+    fn set_packed_desc_flags(&mut self, index: usize, value: u16) { let a = self.desc + 14+16*index as u64; self.write_u16(a, value) }
+
+    fn driver_event_off_wrap(&self) -> u16 { self.read_u16(self.avail) }
+    fn driver_event_flags(&self) -> u16 { self.read_u16(self.avail + 2) }
+    fn set_device_event_flags(&mut self, value: u16) { let a = self.used + 2; self.write_u16(a, value) }
+}
+
+/// Whether the `len` bytes at guest physical address `addr` all lie within guest memory. Even an
+/// empty buffer must start inside it, since it is still sliced out of guest memory.
+pub fn buffer_in_guest_memory(guest_memory: &MemoryRegion, addr: u64, len: u64) -> bool {
+    guest_memory.in_region(addr) &&
+        addr.checked_add(len.saturating_sub(1)).map_or(false, |end| guest_memory.in_region(end))
 }
 
+/// What `GuestDevice::read_buffer` found on a queue.
+enum Buffer {
+    /// The guest hasn't made any more buffers available.
+    Empty,
+    /// A buffer with the given id, spanning the given number of descriptors.
+    Available(u32, u16),
+    /// A buffer with more descriptors than are handled here, which were not all collected.
+    TooLong(u32, u16),
+    /// A chain with a descriptor index past the end of the ring, or a loop.
+    Malformed,
+}
+
+/// Whether an event index of `event` asks for a notification when an index moves from `old` to
+/// `new`, as in the virtio specification's `vring_need_event`.
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+/// A virtio device emulated by the hypervisor and exposed to the guest through the virtio-mmio
+/// version 2 (modern) transport.
 pub struct GuestDevice<D: Driver> {
     host_features_sel: u32,
 
     guest_features_sel: u32,
     guest_features: u64,
 
-    guest_page_size: u32,
-
     queue_sel: u32,
     queue_num: [u32; MAX_QUEUES],
-    queue_align: [u32; MAX_QUEUES],
-    queue_pfn: [u32; MAX_QUEUES],
+    queue_ready: [bool; MAX_QUEUES],
+    queue_desc: [u64; MAX_QUEUES],
+    queue_driver: [u64; MAX_QUEUES],
+    queue_device: [u64; MAX_QUEUES],
+
+    /// For packed rings, the position of the next descriptor the device will look at and the
+    /// current value of the ring wrap counter. Buffers are always used as soon as they are taken,
+    /// so the same position serves for both available and used descriptors.
+    queue_next: [u16; MAX_QUEUES],
+    queue_wrap: [bool; MAX_QUEUES],
+    /// Used index, or packed ring position, as of the last time the guest was considered for a
+    /// used buffer notification.
+    queue_signalled: [u16; MAX_QUEUES],
 
     interrupt_status: u32,
+    /// Set when `interrupt_status` gains a bit, until `take_interrupt` is called.
+    interrupt_raised: bool,
     status: u32,
 
     host_driver: D,
@@ -128,20 +222,25 @@ impl<D: Driver> GuestDevice<D> {
             host_features_sel: 0,
             guest_features_sel: 0,
             guest_features: 0,
-            guest_page_size: 4096,
             queue_sel: 0,
             queue_num: [0; MAX_QUEUES],
-            queue_align: [0; MAX_QUEUES],
-            queue_pfn: [0; MAX_QUEUES],
+            queue_ready: [false; MAX_QUEUES],
+            queue_desc: [0; MAX_QUEUES],
+            queue_driver: [0; MAX_QUEUES],
+            queue_device: [0; MAX_QUEUES],
+            queue_next: [0; MAX_QUEUES],
+            queue_wrap: [true; MAX_QUEUES],
+            queue_signalled: [0; MAX_QUEUES],
             interrupt_status: 0,
+            interrupt_raised: false,
             status: 0,
             host_driver,
         }
     }
 
     pub fn read_u8(&mut self, guest_memory: &mut MemoryRegion, offset: u64) -> u8 {
-        if offset > 0x100 {
-            D::read_config_u8(self, guest_memory, offset)
+        if offset >= REG_CONFIG {
+            D::read_config_u8(self, guest_memory, offset - REG_CONFIG)
         } else {
             0
         }
@@ -152,29 +251,37 @@ impl<D: Driver> GuestDevice<D> {
             return 0;
         }
 
-        if offset > 0x100 {
-            return D::read_config_u32(self, guest_memory, offset);
+        if offset >= REG_CONFIG {
+            return D::read_config_u32(self, guest_memory, offset - REG_CONFIG);
         }
 
+        let queue = (self.queue_sel as usize).min(MAX_QUEUES - 1);
         match offset {
             REG_MAGIC_VALUE => MAGIC_VALUE,
-            REG_VERSION => 1,
+            REG_VERSION => 2,
             REG_DEVICE_ID => D::DEVICE_ID,
             REG_VENDOR_ID => VENDOR_ID,
-            REG_HOST_FEATURES if self.host_features_sel == 0 => (D::FEATURES & 0xffffffff) as u32,
-            REG_HOST_FEATURES if self.host_features_sel == 1 => ((D::FEATURES >> 32) & 0xffffffff) as u32,
+            REG_HOST_FEATURES if self.host_features_sel == 0 => (Self::features() & 0xffffffff) as u32,
+            REG_HOST_FEATURES if self.host_features_sel == 1 => ((Self::features() >> 32) & 0xffffffff) as u32,
             REG_HOST_FEATURES => 0,
             REG_HOST_FEATURES_SEL => self.host_features_sel,
             REG_GUEST_FEATURES => 0,
             REG_GUEST_FEATURES_SEL => self.guest_features_sel,
-            REG_GUEST_PAGE_SIZE => self.guest_page_size,
             REG_QUEUE_SEL => self.queue_sel,
-            REG_QUEUE_NUM_MAX => D::QUEUE_NUM_MAX,
-            REG_QUEUE_NUM => self.queue_num[self.queue_sel as usize],
-            REG_QUEUE_ALIGN => self.queue_align[self.queue_sel as usize],
-            REG_QUEUE_PFN => self.queue_pfn[self.queue_sel as usize],
+            REG_QUEUE_NUM_MAX if (self.queue_sel as usize) < MAX_QUEUES => D::QUEUE_NUM_MAX,
+            REG_QUEUE_NUM_MAX => 0,
+            REG_QUEUE_NUM => self.queue_num[queue],
+            REG_QUEUE_READY => self.queue_ready[queue] as u32,
+            REG_QUEUE_DESC_LOW => self.queue_desc[queue] as u32,
+            REG_QUEUE_DESC_HIGH => (self.queue_desc[queue] >> 32) as u32,
+            REG_QUEUE_DRIVER_LOW => self.queue_driver[queue] as u32,
+            REG_QUEUE_DRIVER_HIGH => (self.queue_driver[queue] >> 32) as u32,
+            REG_QUEUE_DEVICE_LOW => self.queue_device[queue] as u32,
+            REG_QUEUE_DEVICE_HIGH => (self.queue_device[queue] >> 32) as u32,
+            // Device configuration never changes behind the guest's back.
+            REG_CONFIG_GENERATION => 0,
             REG_QUEUE_NOTIFY => 0,
-            REG_INTERRUPT_STATUS => 0,
+            REG_INTERRUPT_STATUS => self.interrupt_status,
             REG_INTERRUPT_ACK => 0,
             REG_STATUS => self.status,
             _ => 0,
@@ -182,8 +289,8 @@ impl<D: Driver> GuestDevice<D> {
     }
 
     pub fn write_u8(&mut self, guest_memory: &mut MemoryRegion, offset: u64, value: u8)  {
-        if offset > 0x100 {
-            D::write_config_u8(self, guest_memory, offset, value);
+        if offset >= REG_CONFIG {
+            D::write_config_u8(self, guest_memory, offset - REG_CONFIG, value);
         }
     }
 
@@ -192,29 +299,51 @@ impl<D: Driver> GuestDevice<D> {
             return;
         }
 
-        if offset > 0x100 {
-            D::write_config_u32(self, guest_memory, offset, value);
+        if offset >= REG_CONFIG {
+            D::write_config_u32(self, guest_memory, offset - REG_CONFIG, value);
             return;
         }
 
+        // Queue registers can only be changed while the queue is disabled.
+        let queue = self.queue_sel as usize;
+        let queue_writable = queue < MAX_QUEUES && !self.queue_ready[queue];
+        let set_low = |r: &mut u64| *r = (*r & !0xffffffff) | value as u64;
+        let set_high = |r: &mut u64| *r = (*r & 0xffffffff) | ((value as u64) << 32);
+
         match offset {
             REG_HOST_FEATURES_SEL => self.host_features_sel = value,
+            // The rings of a queue are checked for the layout the features select when it is
+            // enabled, so they can't change after that.
+            REG_GUEST_FEATURES if self.queue_ready.iter().any(|&ready| ready) => {}
             REG_GUEST_FEATURES if self.guest_features_sel == 0 => self.guest_features = (self.guest_features & !0xffffffff) | value as u64,
             REG_GUEST_FEATURES if self.guest_features_sel == 1 => self.guest_features = (self.guest_features & 0xffffffff) | ((value as u64) << 32),
             REG_GUEST_FEATURES_SEL => self.guest_features_sel = value,
-            REG_GUEST_PAGE_SIZE => self.guest_page_size = value,
             REG_QUEUE_SEL => self.queue_sel = value,
-            REG_QUEUE_NUM => self.queue_num[self.queue_sel as usize] = value,
-            REG_QUEUE_ALIGN => self.queue_align[self.queue_sel as usize] = value,
-            REG_QUEUE_PFN => self.queue_pfn[self.queue_sel as usize] = value,
+            REG_QUEUE_NUM if queue_writable => self.queue_num[queue] = value.min(D::QUEUE_NUM_MAX),
+            REG_QUEUE_READY if queue < MAX_QUEUES => {
+                if value & 1 != 0 && !self.rings_in_guest_memory(guest_memory, queue) {
+                    self.set_needs_reset();
+                } else {
+                    self.queue_ready[queue] = value & 1 != 0;
+                }
+            }
+            REG_QUEUE_DESC_LOW if queue_writable => set_low(&mut self.queue_desc[queue]),
+            REG_QUEUE_DESC_HIGH if queue_writable => set_high(&mut self.queue_desc[queue]),
+            REG_QUEUE_DRIVER_LOW if queue_writable => set_low(&mut self.queue_driver[queue]),
+            REG_QUEUE_DRIVER_HIGH if queue_writable => set_high(&mut self.queue_driver[queue]),
+            REG_QUEUE_DEVICE_LOW if queue_writable => set_low(&mut self.queue_device[queue]),
+            REG_QUEUE_DEVICE_HIGH if queue_writable => set_high(&mut self.queue_device[queue]),
             REG_QUEUE_NOTIFY => D::doorbell(self, guest_memory, value),
             REG_INTERRUPT_ACK => self.interrupt_status &= !value,
             REG_STATUS => {
                 if value == 0 {
                     self.reset();
                     D::reset(self, guest_memory);
+                } else if value & STATUS_FEATURES_OK != 0 && !self.features_acceptable() {
+                    // Leaving FEATURES_OK clear tells the driver its feature selection was refused.
+                    self.status = value & !STATUS_FEATURES_OK | self.status & STATUS_NEEDS_RESET;
                 } else {
-                    self.status = value;
+                    self.status = value | self.status & STATUS_NEEDS_RESET;
                 }
             }
             _ => {},
@@ -226,85 +355,344 @@ impl<D: Driver> GuestDevice<D> {
         D::interrupt(self, guest_memory)
     }
 
+    /// Returns whether the device has raised an interrupt since the last call, in which case the
+    /// caller should make the device's guest interrupt pending.
+    pub fn take_interrupt(&mut self) -> bool {
+        core::mem::replace(&mut self.interrupt_raised, false)
+    }
+
+    /// Signal the guest that buffers have been added to the used ring of `queue`, unless it has
+    /// suppressed notifications for them.
+    fn signal_used(&mut self, guest_memory: &mut MemoryRegion, queue: u32) {
+        let q = queue as usize;
+        let event_idx = self.guest_features & VIRTIO_F_EVENT_IDX != 0;
+        let dt = self.get_queue(guest_memory, queue);
+
+        let notify = if self.packed() {
+            let new = self.queue_next[q];
+            let old = core::mem::replace(&mut self.queue_signalled[q], new);
+            match dt.driver_event_flags() {
+                RING_EVENT_FLAGS_ENABLE => true,
+                RING_EVENT_FLAGS_DESC if event_idx => {
+                    // The event offset is relative to the lap of the ring given by its wrap bit.
+                    let off_wrap = dt.driver_event_off_wrap();
+                    let mut event = off_wrap & 0x7fff;
+                    if (off_wrap >> 15 != 0) != self.queue_wrap[q] {
+                        event = event.wrapping_sub(dt.queue_size as u16);
+                    }
+                    need_event(event, new, old)
+                }
+                _ => false,
+            }
+        } else {
+            let new = dt.used_idx();
+            let old = core::mem::replace(&mut self.queue_signalled[q], new);
+            if event_idx {
+                need_event(dt.used_event(), new, old)
+            } else {
+                dt.avail_flags() & VIRTQ_AVAIL_F_NO_INTERRUPT == 0
+            }
+        };
+
+        if notify {
+            self.interrupt_status |= INTERRUPT_USED_BUFFER;
+            self.interrupt_raised = true;
+        }
+    }
+
+    fn driver_ok(&self) -> bool {
+        self.status & STATUS_DRIVER_OK != 0
+    }
+
+    /// Features offered to the guest. The modern transport requires VIRTIO_F_VERSION_1, and both
+    /// ring layouts and event suppression are handled here for every driver.
+    fn features() -> u64 {
+        D::FEATURES | VIRTIO_F_VERSION_1 | VIRTIO_F_RING_PACKED | VIRTIO_F_EVENT_IDX
+    }
+
+    fn packed(&self) -> bool {
+        self.guest_features & VIRTIO_F_RING_PACKED != 0
+    }
+
+    fn features_acceptable(&self) -> bool {
+        self.guest_features & !Self::features() == 0 && self.guest_features & VIRTIO_F_VERSION_1 != 0
+    }
+
+    /// Whether the guest has set up and enabled `queue`, and the device may use it.
+    fn queue_enabled(&self, queue: u32) -> bool {
+        let queue = queue as usize;
+        queue < MAX_QUEUES && self.queue_ready[queue] && self.queue_num[queue] != 0 &&
+            self.status & STATUS_NEEDS_RESET == 0
+    }
+
+    /// Whether the rings of `queue` lie within guest memory. They are checked when the queue is
+    /// enabled, so that after that only the buffers the guest makes available need to be.
+    fn rings_in_guest_memory(&self, guest_memory: &MemoryRegion, queue: usize) -> bool {
+        let num = self.queue_num[queue] as u64;
+        let (driver_len, device_len) = if self.packed() { (4, 4) } else { (6 + 2 * num, 6 + 8 * num) };
+        buffer_in_guest_memory(guest_memory, self.queue_desc[queue], 16 * num) &&
+            buffer_in_guest_memory(guest_memory, self.queue_driver[queue], driver_len) &&
+            buffer_in_guest_memory(guest_memory, self.queue_device[queue], device_len)
+    }
+
+    /// Stop using the queues after the guest gave the device something it can't use, and tell the
+    /// guest that it has to reset the device.
+    fn set_needs_reset(&mut self) {
+        if self.status & STATUS_NEEDS_RESET == 0 {
+            println!("VIRTIO: Emulated device given an invalid queue or descriptor chain");
+            self.status |= STATUS_NEEDS_RESET;
+            self.interrupt_status |= INTERRUPT_CONFIG_CHANGE;
+            self.interrupt_raised = true;
+        }
+    }
+
     fn reset(&mut self) {
         self.host_features_sel = 0;
         self.guest_features_sel = 0;
         self.guest_features = 0;
-        self.guest_page_size = 4096;
 
         self.queue_sel = 0;
         self.queue_num = [0; MAX_QUEUES];
-        self.queue_align = [0; MAX_QUEUES];
-        self.queue_pfn = [0; MAX_QUEUES];
+        self.queue_ready = [false; MAX_QUEUES];
+        self.queue_desc = [0; MAX_QUEUES];
+        self.queue_driver = [0; MAX_QUEUES];
+        self.queue_device = [0; MAX_QUEUES];
+        self.queue_next = [0; MAX_QUEUES];
+        self.queue_wrap = [true; MAX_QUEUES];
+        self.queue_signalled = [0; MAX_QUEUES];
 
         self.interrupt_status = 0;
+        self.interrupt_raised = false;
+        self.status = 0;
     }
 
-    fn with_buffer<F: FnOnce(&[&[u8]]) -> Option<u32>>(&mut self, guest_memory: &mut MemoryRegion, queue: u32, f: F) {
-        let dt = self.get_queue(guest_memory, queue);
+    /// Collect the descriptors of the next buffer the guest made available on `queue` into
+    /// `ranges`, as address, length and flags. Returns the buffer id and the number of descriptors
+    /// it spans, or None if there is no buffer available. Buffers with more descriptors than fit
+    /// in `ranges`, or whose first descriptor the device can't write to when `writable` is set, are
+    /// returned to the guest unused. A malformed chain, or a buffer that doesn't lie entirely
+    /// within guest memory, stops the device until the guest resets it.
+    fn next_buffer(&mut self, guest_memory: &mut MemoryRegion, queue: u32, writable: bool,
+                   ranges: &mut ArrayVec<[(u64, u32, u16); 16]>) -> Option<(u32, u16)> {
+        loop {
+            ranges.clear();
+            let (id, count) = match self.read_buffer(guest_memory, queue, ranges) {
+                Buffer::Empty => return None,
+                Buffer::Available(id, count) => {
+                    if !ranges.iter().all(|&(addr, len, _)| buffer_in_guest_memory(guest_memory, addr, len as u64)) {
+                        self.set_needs_reset();
+                        return None;
+                    }
+                    if !writable || ranges[0].2 & VIRTQ_DESC_F_WRITE != 0 {
+                        return Some((id, count));
+                    }
+                    (id, count)
+                }
+                Buffer::TooLong(id, count) => (id, count),
+                Buffer::Malformed => {
+                    self.set_needs_reset();
+                    return None;
+                }
+            };
 
-        if dt.avail_idx() == dt.used_idx() {
-            return;
+            self.push_used(guest_memory, queue, id, 0, count);
+            self.signal_used(guest_memory, queue);
         }
+    }
 
-        let mut ranges = ArrayVec::<[(u64, u32); 16]>::new();
+    /// Find the next buffer the guest made available on `queue`, collecting as many of its
+    /// descriptors into `ranges` as fit.
+    fn read_buffer(&mut self, guest_memory: &mut MemoryRegion, queue: u32,
+                   ranges: &mut ArrayVec<[(u64, u32, u16); 16]>) -> Buffer {
+        let q = queue as usize;
+        let mut dt = self.get_queue(guest_memory, queue);
+        if dt.queue_size == 0 {
+            return Buffer::Empty;
+        }
 
-        let idx = (dt.used_idx() as usize + 1) % dt.queue_size;
-        let id = dt.avail_ring(idx) as usize;
+        if self.packed() {
+            let mut index = self.queue_next[q] as usize;
+            let flags = dt.packed_desc_flags(index);
+            let avail = flags & VIRTQ_DESC_F_AVAIL != 0;
+            let used = flags & VIRTQ_DESC_F_USED != 0;
+            if avail != self.queue_wrap[q] || used == self.queue_wrap[q] {
+                dt.set_device_event_flags(RING_EVENT_FLAGS_ENABLE);
+                return Buffer::Empty;
+            }
 
-        let mut flags = VIRTQ_DESC_F_NEXT;
-        let mut next_id = id;
-        while flags & VIRTQ_DESC_F_NEXT != 0 {
-            let addr = dt.desc_addr(next_id);
-            let len = dt.desc_len(next_id);
-            flags = dt.desc_flags(next_id);
-            next_id = dt.desc_next(next_id) as usize;
+            // The rest of the chain must not be read before the flags that made it available.
+            core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+            for count in 1..=dt.queue_size {
+                let flags = dt.packed_desc_flags(index);
+                if !ranges.is_full() {
+                    ranges.push((dt.desc_addr(index), dt.desc_len(index), flags));
+                }
+                if flags & VIRTQ_DESC_F_NEXT == 0 {
+                    let id = dt.packed_desc_id(index) as u32;
+                    return if count <= ranges.capacity() {
+                        Buffer::Available(id, count as u16)
+                    } else {
+                        Buffer::TooLong(id, count as u16)
+                    };
+                }
+                index = (index + 1) % dt.queue_size;
+            }
+            Buffer::Malformed
+        } else {
+            let used_idx = dt.used_idx();
+            if dt.avail_idx() == used_idx {
+                if self.guest_features & VIRTIO_F_EVENT_IDX == 0 {
+                    return Buffer::Empty;
+                }
+
+                // Ask to be notified as soon as the guest makes another buffer available, then
+                // check again in case one was made available before the guest could see that.
+                dt.set_avail_event(used_idx);
+                core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+                if dt.avail_idx() == used_idx {
+                    return Buffer::Empty;
+                }
+            }
 
-            ranges.push((addr, len));
+            core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+            let id = dt.avail_ring(used_idx as usize % dt.queue_size) as usize;
+            let mut next_id = id;
+            for count in 1..=dt.queue_size {
+                if next_id >= dt.queue_size {
+                    return Buffer::Malformed;
+                }
+
+                let flags = dt.desc_flags(next_id);
+                if !ranges.is_full() {
+                    ranges.push((dt.desc_addr(next_id), dt.desc_len(next_id), flags));
+                }
+                if flags & VIRTQ_DESC_F_NEXT == 0 {
+                    return if count <= ranges.capacity() {
+                        Buffer::Available(id as u32, 1)
+                    } else {
+                        Buffer::TooLong(id as u32, 1)
+                    };
+                }
+                next_id = dt.desc_next(next_id) as usize;
+            }
+            Buffer::Malformed
         }
+    }
+
+    /// Pass the next buffer the guest made available on `queue` to `f`, along with the host driver.
+    /// If `f` returns the number of bytes written, the buffer is moved to the used ring. Returns
+    /// whether that happened.
+    fn with_buffer<F: FnOnce(&mut D, &[&[u8]]) -> Option<u32>>(&mut self, guest_memory: &mut MemoryRegion, queue: u32, f: F) -> bool {
+        let mut ranges = ArrayVec::new();
+        let (id, count) = match self.next_buffer(guest_memory, queue, false, &mut ranges) {
+            Some(buffer) => buffer,
+            None => return false,
+        };
 
-        // Handling the borrow checker is a bit tricky here. At this point, we let the lifetime of
-        // `dt` end so that its borrow of `guest_memory` ends. Then we borrow a bunch of slices from
+        // Handling the borrow checker is a bit tricky here. At this point, the borrow of
+        // `guest_memory` by `next_buffer` has ended. Then we borrow a bunch of slices from
         // `guest_memory` and pass them to `f`. Once that function returns, we have `buffers` go out
         // of scope so that we can borrow `guest_memory` again to make a DescriptorTable.
         let consume_buffers = {
             let mut buffers = ArrayVec::<[&[u8]; 16]>::new();
-            for (addr, len) in ranges {
+            for (addr, len, _) in ranges {
                 buffers.push(guest_memory.slice(addr, len as u64));
             }
 
-            f(&*buffers)
+            f(&mut self.host_driver, &*buffers)
         };
 
-        if let Some(len) = consume_buffers {
-            let mut dt = self.get_queue(guest_memory, queue);
-            dt.set_used_ring_id(idx, id as u32);
-            dt.set_used_ring_len(idx, len);
-            dt.set_used_idx(dt.used_idx().wrapping_add(1));
+        match consume_buffers {
+            Some(len) => {
+                self.push_used(guest_memory, queue, id, len, count);
+                true
+            }
+            None => false,
         }
     }
 
-    fn get_queue<'a>(&'a mut self, guest_memory: &'a mut MemoryRegion, queue: u32) -> DescriptorTable<'a> {
-        let pfn = self.queue_pfn[queue as usize];
-        let queue_size = self.queue_num[queue as usize] as usize;
-        let align = self.queue_align[queue as usize] as usize;
+    /// Like `with_buffer`, but for buffers the device writes into. Only the first descriptor of
+    /// each chain is used.
+    fn with_buffer_mut<F: FnOnce(&mut D, &mut [u8]) -> Option<u32>>(&mut self, guest_memory: &mut MemoryRegion, queue: u32, f: F) -> bool {
+        let mut ranges = ArrayVec::new();
+        let (id, count) = match self.next_buffer(guest_memory, queue, true, &mut ranges) {
+            Some(buffer) => buffer,
+            None => return false,
+        };
 
-        let desc_size = 16 * queue_size;
-        let avail_size = 6 + 2 * queue_size;
-        let used_size = 6 + 8 * queue_size;
+        let (addr, len, _) = ranges[0];
 
-        let used_start = ((desc_size + avail_size + (align - 1)) % align) - align;
+        match f(&mut self.host_driver, guest_memory.slice_mut(addr, len as u64)) {
+            Some(len) => {
+                self.push_used(guest_memory, queue, id, len, count);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Pass the descriptors of the next buffer the guest made available on `queue` to `f`, along
+    /// with guest memory, for devices that both read and write parts of the same buffer. Each range
+    /// lies within guest memory, but `f` must check which of them the device may write to.
+    fn with_descriptors<F: FnOnce(&mut D, &mut MemoryRegion, &[(u64, u32, u16)]) -> Option<u32>>(&mut self, guest_memory: &mut MemoryRegion, queue: u32, f: F) -> bool {
+        let mut ranges = ArrayVec::new();
+        let (id, count) = match self.next_buffer(guest_memory, queue, false, &mut ranges) {
+            Some(buffer) => buffer,
+            None => return false,
+        };
 
-        let slice = guest_memory.slice_mut(pfn as u64 * 4096, (used_start + used_size) as u64);
-        let (desc, slice) = slice.split_at_mut(desc_size);
-        let (avail, slice) = slice.split_at_mut(used_size);
-        let (_, used) = slice.split_at_mut(used_start - desc_size - avail_size);
+        match f(&mut self.host_driver, guest_memory, &*ranges) {
+            Some(len) => {
+                self.push_used(guest_memory, queue, id, len, count);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Return buffer `id`, which spans `count` descriptors, to the guest through `queue`.
+    fn push_used(&mut self, guest_memory: &mut MemoryRegion, queue: u32, id: u32, len: u32, count: u16) {
+        let q = queue as usize;
+        let packed = self.packed();
+        let mut dt = self.get_queue(guest_memory, queue);
+
+        if packed {
+            let index = self.queue_next[q] as usize;
+            dt.set_packed_desc_id(index, id as u16);
+            dt.set_packed_desc_len(index, len);
+
+            // The guest must not see the descriptor as used before its other fields are written.
+            core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+            let flags = if self.queue_wrap[q] { VIRTQ_DESC_F_AVAIL | VIRTQ_DESC_F_USED } else { 0 };
+            dt.set_packed_desc_flags(index, flags);
+
+            let next = index + count as usize;
+            if next >= dt.queue_size {
+                self.queue_next[q] = (next - dt.queue_size) as u16;
+                self.queue_wrap[q] = !self.queue_wrap[q];
+            } else {
+                self.queue_next[q] = next as u16;
+            }
+        } else {
+            let used_idx = dt.used_idx();
+            let idx = used_idx as usize % dt.queue_size;
+            dt.set_used_ring_id(idx, id);
+            dt.set_used_ring_len(idx, len);
+
+            // The guest must not see the new index before the entry it covers.
+            core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+            dt.set_used_idx(used_idx.wrapping_add(1));
+        }
+    }
 
+    fn get_queue<'a>(&self, guest_memory: &'a mut MemoryRegion, queue: u32) -> DescriptorTable<'a> {
+        let queue = queue as usize;
         DescriptorTable {
-            desc,
-            avail,
-            used,
-            queue_size
+            guest_memory,
+            desc: self.queue_desc[queue],
+            avail: self.queue_driver[queue],
+            used: self.queue_device[queue],
+            queue_size: self.queue_num[queue] as usize,
         }
     }
 }
@@ -0,0 +1,545 @@
+// References:
+//
+// https://docs.oasis-open.org/virtio/virtio/v1.1/cs01/virtio-v1.1-cs01.html#x1-2390002
+// https://uefi.org/sites/default/files/resources/UEFI_Spec_2_8_final.pdf (section 5.3)
+
+use arrayvec::{ArrayString, ArrayVec};
+use byteorder::{ByteOrder, LittleEndian};
+use core::fmt::Write;
+use crate::constants::MAX_GUESTS;
+use crate::fdt::MachineMeta;
+use crate::memory_region::MemoryRegion;
+use crate::pmap;
+use crate::statics::SHARED_STATICS;
+use super::blk::*;
+use super::*;
+
+const DEVICE_ID_BLK: u32 = 2;
+
+/// Every request takes a header and a status descriptor on top of as many data buffers as a guest
+/// request can have.
+const HOST_QUEUE_SIZE: u64 = 32;
+
+// Layout of the memory shared with the host device. The used ring follows the available ring at
+// the next page boundary, as the legacy transport requires.
+const DESC_TABLE: u64 = 0x0;
+const AVAIL_RING: u64 = DESC_TABLE + HOST_QUEUE_SIZE * 16;
+const USED_RING: u64 = 0x1000;
+const REQUEST_HEADER: u64 = 0x2000;
+const REQUEST_STATUS: u64 = REQUEST_HEADER + REQUEST_HEADER_LEN as u64;
+const BLOCK_BUFFER: u64 = 0x3000;
+const DMA_LEN: u64 = 0x4000;
+
+/// Granularity at which guest writes are copied into an overlay.
+const BLOCK_SIZE: u64 = 4096;
+const SECTORS_PER_BLOCK: u64 = BLOCK_SIZE / SECTOR_SIZE;
+/// Number of entries in the hash table at the start of each overlay that says which blocks of the
+/// disk it holds. Every entry is a block number plus one (or zero if unused), followed by the
+/// index of the copy of that block.
+const OVERLAY_TABLE_ENTRIES: u64 = 65536;
+const OVERLAY_BLOCKS_OFFSET: u64 = OVERLAY_TABLE_ENTRIES * 16;
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+
+/// Driver for a host virtio-blk device whose sectors are divided up between the guests. Requests
+/// are carried out one at a time, waiting for the device to complete each before returning.
+pub struct HostDisk {
+    /// Index of the device among the host's virtio devices.
+    index: usize,
+    registers: MemoryRegion<u32>,
+    dma: MemoryRegion,
+    /// Next available ring index, which is also the used ring index of the next completion.
+    next_avail: u16,
+    flush: bool,
+    /// Size of the disk in sectors.
+    capacity: u64,
+    /// First sector and number of sectors of the window that each guest sees.
+    partitions: [Option<(u64, u64)>; MAX_GUESTS],
+}
+
+impl HostDisk {
+    /// Take over the first of the host's virtio devices that is a block device, if any, using the
+    /// memory at host physical address `dma` to talk to it. The disk is split between `guests`
+    /// guests along its GPT partitions, or into equal parts if it has no partition table.
+    pub unsafe fn new(machine: &MachineMeta, dma: u64, guests: usize) -> Option<Self> {
+        let index = machine.virtio.iter().position(|d| {
+            let registers = MemoryRegion::<u32>::with_base_address(pmap::pa2va(d.base_address), 0, 0x1000);
+            registers[REG_MAGIC_VALUE] == MAGIC_VALUE && registers[REG_DEVICE_ID] == DEVICE_ID_BLK
+        })?;
+
+        let mut disk = Self {
+            index,
+            registers: MemoryRegion::with_base_address(pmap::pa2va(machine.virtio[index].base_address), 0, 0x1000),
+            dma: MemoryRegion::with_base_address(pmap::pa2va(dma), dma, DMA_LEN),
+            next_avail: 0,
+            flush: false,
+            capacity: 0,
+            partitions: [None; MAX_GUESTS],
+        };
+        for i in 0..DMA_LEN / 8 {
+            disk.dma[dma + i * 8] = 0;
+        }
+        if !disk.initialize() {
+            println!("WARN: Unable to initialize shared virtio-blk device");
+            return None;
+        }
+
+        let capacity = disk.registers[REG_CONFIG] as u64 | (disk.registers[REG_CONFIG + 4] as u64) << 32;
+        disk.capacity = capacity;
+        if machine.disk_overlay {
+            // Every guest sees the whole disk, with its writes kept in its own overlay.
+            for i in 0..guests.min(MAX_GUESTS) {
+                disk.partitions[i] = Some((0, capacity));
+            }
+        } else if !disk.read_partition_table(guests) {
+            let sectors = capacity / guests as u64;
+            for i in 0..guests.min(MAX_GUESTS) {
+                disk.partitions[i] = Some((i as u64 * sectors, sectors));
+            }
+        }
+        for (i, partition) in disk.partitions.iter_mut().enumerate() {
+            if let Some((start, sectors)) = *partition {
+                if start.checked_add(sectors).map(|end| end <= capacity) != Some(true) {
+                    println!("WARN: Partition for guest {} runs past the end of the shared disk", i + 1);
+                    *partition = None;
+                }
+            }
+        }
+        Some(disk)
+    }
+
+    /// Which of the host's virtio devices this is.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// First sector and number of sectors of the part of the disk given to guest slot `guest`.
+    pub fn partition(&self, guest: usize) -> Option<(u64, u64)> {
+        self.partitions[guest]
+    }
+
+    /// Bring the device up with a single request queue. Returns false if the device refused.
+    fn initialize(&mut self) -> bool {
+        let legacy = self.registers[REG_VERSION] == 1;
+        self.registers[REG_STATUS] = 0;
+        while unsafe { core::ptr::read_volatile(&self.registers[REG_STATUS]) } != 0 {}
+        self.registers[REG_STATUS] = STATUS_ACKNOWLEDGE;
+        self.registers[REG_STATUS] = STATUS_ACKNOWLEDGE | STATUS_DRIVER;
+
+        self.registers[REG_HOST_FEATURES_SEL] = 0;
+        self.flush = self.registers[REG_HOST_FEATURES] as u64 & VIRTIO_BLK_F_FLUSH != 0;
+        self.registers[REG_GUEST_FEATURES_SEL] = 0;
+        self.registers[REG_GUEST_FEATURES] = if self.flush { VIRTIO_BLK_F_FLUSH as u32 } else { 0 };
+        self.registers[REG_GUEST_FEATURES_SEL] = 1;
+        self.registers[REG_GUEST_FEATURES] = if legacy { 0 } else { (VIRTIO_F_VERSION_1 >> 32) as u32 };
+        if !legacy {
+            self.registers[REG_STATUS] = STATUS_ACKNOWLEDGE | STATUS_DRIVER | STATUS_FEATURES_OK;
+            if self.registers[REG_STATUS] & STATUS_FEATURES_OK == 0 {
+                return false;
+            }
+        }
+
+        self.registers[REG_QUEUE_SEL] = 0;
+        if (self.registers[REG_QUEUE_NUM_MAX] as u64) < HOST_QUEUE_SIZE {
+            return false;
+        }
+        self.registers[REG_QUEUE_NUM] = HOST_QUEUE_SIZE as u32;
+
+        // The device never needs to interrupt, since completions are waited for.
+        let base = self.dma.base();
+        LittleEndian::write_u16(self.dma.slice_mut(base + AVAIL_RING, 2), VIRTQ_AVAIL_F_NO_INTERRUPT);
+
+        if legacy {
+            self.registers[REG_GUEST_PAGE_SIZE] = 4096;
+            self.registers[REG_QUEUE_ALIGN] = 4096;
+            self.registers[REG_QUEUE_PFN] = ((base + DESC_TABLE) >> 12) as u32;
+        } else {
+            for &(low, addr) in &[(REG_QUEUE_DESC_LOW, base + DESC_TABLE),
+                                  (REG_QUEUE_DRIVER_LOW, base + AVAIL_RING),
+                                  (REG_QUEUE_DEVICE_LOW, base + USED_RING)] {
+                self.registers[low] = addr as u32;
+                self.registers[low + 4] = (addr >> 32) as u32;
+            }
+            self.registers[REG_QUEUE_READY] = 1;
+        }
+
+        let status = self.registers[REG_STATUS];
+        self.registers[REG_STATUS] = status | STATUS_DRIVER_OK;
+        true
+    }
+
+    /// Fill in `partitions` from the GPT on the disk, one partition per guest in table order.
+    /// Returns false if the disk has no valid GPT.
+    fn read_partition_table(&mut self, guests: usize) -> bool {
+        if self.read_sector(1).is_none() {
+            return false;
+        }
+        let header = self.sector_buffer();
+        if &header[..8] != GPT_SIGNATURE {
+            return false;
+        }
+        let entries_lba = LittleEndian::read_u64(&header[72..]);
+        let entries = LittleEndian::read_u32(&header[80..]) as u64;
+        let entry_size = LittleEndian::read_u32(&header[84..]) as u64;
+        if entry_size < 128 || entry_size > SECTOR_SIZE || SECTOR_SIZE % entry_size != 0 {
+            return false;
+        }
+
+        let mut guest = 0;
+        for i in 0..entries {
+            if guest == guests.min(MAX_GUESTS) {
+                break;
+            }
+            if self.read_sector(entries_lba + i * entry_size / SECTOR_SIZE).is_none() {
+                return false;
+            }
+
+            let entry = &self.sector_buffer()[(i * entry_size % SECTOR_SIZE) as usize..];
+            let first = LittleEndian::read_u64(&entry[32..]);
+            let last = LittleEndian::read_u64(&entry[40..]);
+            if entry[..16].iter().any(|&b| b != 0) && last >= first {
+                self.partitions[guest] = Some((first, last - first + 1));
+                guest += 1;
+            }
+        }
+        true
+    }
+
+    fn sector_buffer(&self) -> &[u8] {
+        self.dma.slice(self.dma.base() + BLOCK_BUFFER, SECTOR_SIZE)
+    }
+
+    fn read_sector(&mut self, sector: u64) -> Option<()> {
+        let buffer = self.dma.base() + BLOCK_BUFFER;
+        match self.submit(VIRTIO_BLK_T_IN, sector, &[(buffer, SECTOR_SIZE as u32, true)]) {
+            VIRTIO_BLK_S_OK => Some(()),
+            _ => None,
+        }
+    }
+
+    fn block_buffer(&self) -> &[u8] {
+        self.dma.slice(self.dma.base() + BLOCK_BUFFER, BLOCK_SIZE)
+    }
+
+    /// Read block `block` of the disk to host physical address `pa`, or to the block buffer if
+    /// `pa` is None. The part of a final block past the end of the disk reads as zeroes.
+    fn read_block(&mut self, block: u64, pa: Option<u64>) -> Option<()> {
+        let pa = pa.unwrap_or(self.dma.base() + BLOCK_BUFFER);
+        let sectors = self.capacity.checked_sub(block * SECTORS_PER_BLOCK)?.min(SECTORS_PER_BLOCK);
+        let len = sectors * SECTOR_SIZE;
+        unsafe { core::ptr::write_bytes((pmap::pa2va(pa) + len) as *mut u8, 0, (BLOCK_SIZE - len) as usize) };
+        match self.submit(VIRTIO_BLK_T_IN, block * SECTORS_PER_BLOCK, &[(pa, len as u32, true)]) {
+            VIRTIO_BLK_S_OK => Some(()),
+            _ => None,
+        }
+    }
+
+    /// Have the device carry out a request on the host physical `buffers`, given as address, length
+    /// and whether the device writes to it, and wait for it to finish. Returns the request status.
+    fn submit(&mut self, request_type: u32, sector: u64, buffers: &[(u64, u32, bool)]) -> u8 {
+        assert!(buffers.len() + 2 <= HOST_QUEUE_SIZE as usize);
+        let base = self.dma.base();
+
+        let header = self.dma.slice_mut(base + REQUEST_HEADER, REQUEST_HEADER_LEN as u64);
+        LittleEndian::write_u32(&mut header[0..], request_type);
+        LittleEndian::write_u32(&mut header[4..], 0);
+        LittleEndian::write_u64(&mut header[8..], sector);
+        self.dma.slice_mut(base + REQUEST_STATUS, 1)[0] = 0xff;
+
+        let mut descriptors = ArrayVec::<[(u64, u32, bool); HOST_QUEUE_SIZE as usize]>::new();
+        descriptors.push((base + REQUEST_HEADER, REQUEST_HEADER_LEN, false));
+        descriptors.extend(buffers.iter().cloned());
+        descriptors.push((base + REQUEST_STATUS, 1, true));
+        for (i, &(addr, len, write)) in descriptors.iter().enumerate() {
+            let last = i == descriptors.len() - 1;
+            let desc = self.dma.slice_mut(base + DESC_TABLE + 16 * i as u64, 16);
+            LittleEndian::write_u64(&mut desc[0..], addr);
+            LittleEndian::write_u32(&mut desc[8..], len);
+            LittleEndian::write_u16(&mut desc[12..], if write { VIRTQ_DESC_F_WRITE } else { 0 } |
+                                    if last { 0 } else { VIRTQ_DESC_F_NEXT });
+            LittleEndian::write_u16(&mut desc[14..], i as u16 + 1);
+        }
+
+        // Publish the chain, which always starts at the first descriptor.
+        let slot = base + AVAIL_RING + 4 + 2 * (self.next_avail as u64 % HOST_QUEUE_SIZE);
+        LittleEndian::write_u16(self.dma.slice_mut(slot, 2), 0);
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+        self.next_avail = self.next_avail.wrapping_add(1);
+        LittleEndian::write_u16(self.dma.slice_mut(base + AVAIL_RING + 2, 2), self.next_avail);
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+        self.registers[REG_QUEUE_NOTIFY] = 0;
+
+        let used_idx = (pmap::pa2va(base + USED_RING + 2)) as *const u16;
+        while unsafe { core::ptr::read_volatile(used_idx) } != self.next_avail {}
+        core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+
+        let status = self.registers[REG_INTERRUPT_STATUS];
+        self.registers[REG_INTERRUPT_ACK] = status;
+        self.dma.slice(base + REQUEST_STATUS, 1)[0]
+    }
+}
+
+/// Sparse copy-on-write layer holding a guest's changes to the shared disk, so that the disk
+/// itself is never written.
+struct Overlay {
+    /// Hash table of the blocks held, followed by the copies of those blocks. Addressed by host
+    /// physical address.
+    memory: MemoryRegion,
+    /// Number of block copies in use.
+    blocks: u64,
+}
+
+impl Overlay {
+    fn new(memory: MemoryRegion) -> Self {
+        let mut overlay = Self { memory, blocks: 0 };
+        overlay.clear();
+        overlay
+    }
+
+    /// Forget every block written so far.
+    fn clear(&mut self) {
+        let base = self.memory.base();
+        for i in 0..OVERLAY_TABLE_ENTRIES * 2 {
+            self.memory[base + i * 8] = 0;
+        }
+        self.blocks = 0;
+    }
+
+    fn capacity(&self) -> u64 {
+        ((self.memory.len() - OVERLAY_BLOCKS_OFFSET) / BLOCK_SIZE).min(OVERLAY_TABLE_ENTRIES / 2)
+    }
+
+    /// Return the address of the table entry for `block`, which is either the one holding it or
+    /// the empty one where it would go.
+    fn entry(&self, block: u64) -> u64 {
+        let mut index = block.wrapping_mul(0x9e3779b97f4a7c15) >> (64 - OVERLAY_TABLE_ENTRIES.trailing_zeros());
+        loop {
+            let entry = self.memory.base() + index * 16;
+            let key = self.memory[entry];
+            if key == 0 || key == block + 1 {
+                return entry;
+            }
+            index = (index + 1) % OVERLAY_TABLE_ENTRIES;
+        }
+    }
+
+    /// Host physical address of the copy of `block`, if there is one.
+    fn lookup(&self, block: u64) -> Option<u64> {
+        let entry = self.entry(block);
+        match self.memory[entry] {
+            0 => None,
+            _ => Some(self.memory.base() + OVERLAY_BLOCKS_OFFSET + self.memory[entry + 8] * BLOCK_SIZE),
+        }
+    }
+
+    /// Host physical address where the next copy goes, or None if the overlay is full. It only
+    /// becomes part of the overlay once `insert` is called.
+    fn next_copy(&self) -> Option<u64> {
+        if self.blocks == self.capacity() {
+            return None;
+        }
+        Some(self.memory.base() + OVERLAY_BLOCKS_OFFSET + self.blocks * BLOCK_SIZE)
+    }
+
+    /// Record that the copy at `next_copy` holds `block`, which must not already have one, and
+    /// return its address.
+    fn insert(&mut self, block: u64) -> u64 {
+        let copy = self.next_copy().unwrap();
+        let entry = self.entry(block);
+        self.memory[entry] = block + 1;
+        self.memory[entry + 8] = self.blocks;
+        self.blocks += 1;
+        copy
+    }
+}
+
+/// Virtio block device giving a guest its own window of a disk shared with the other guests.
+pub struct PartitionDriver {
+    guest: usize,
+    start: u64,
+    sectors: u64,
+    /// Where writes go instead of the disk, if the guests share the whole disk.
+    overlay: Option<Overlay>,
+}
+
+impl PartitionDriver {
+    /// Create the device for guest slot `guest`, or return None if the shared disk has no room for
+    /// it. If the guest is given `overlay` memory, the disk is only ever read and writes are kept
+    /// there instead.
+    pub fn new(guest: usize, overlay: Option<MemoryRegion>) -> Option<Self> {
+        let (start, sectors) = SHARED_STATICS.shared_disk.lock().as_ref()?.partition(guest)?;
+        Some(Self { guest, start, sectors, overlay: overlay.map(Overlay::new) })
+    }
+
+    /// Carry out the request made up of `descriptors` on the shared disk. Returns the number of
+    /// bytes written to guest memory.
+    fn handle_request(&mut self, guest_memory: &mut MemoryRegion, descriptors: &[(u64, u32, u16)]) -> u32 {
+        let request = match Request::parse(guest_memory, descriptors) {
+            Some(request) => request,
+            None => return 0,
+        };
+
+        let (status, written) = match request.request_type {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT if self.overlay.is_some() => {
+                match self.transfer_with_overlay(guest_memory, &request, request.request_type == VIRTIO_BLK_T_OUT) {
+                    Some(written) => (VIRTIO_BLK_S_OK, written),
+                    None => (VIRTIO_BLK_S_IOERR, 0),
+                }
+            }
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT => {
+                let write = request.request_type == VIRTIO_BLK_T_OUT;
+                match self.remap(guest_memory, &request, write) {
+                    Some((sector, buffers, len)) => {
+                        let mut disk = SHARED_STATICS.shared_disk.lock();
+                        match disk.as_mut().unwrap().submit(request.request_type, sector, &buffers) {
+                            VIRTIO_BLK_S_OK if !write => (VIRTIO_BLK_S_OK, len),
+                            status => (status, 0),
+                        }
+                    }
+                    None => (VIRTIO_BLK_S_IOERR, 0),
+                }
+            }
+            VIRTIO_BLK_T_FLUSH => {
+                let mut disk = SHARED_STATICS.shared_disk.lock();
+                let disk = disk.as_mut().unwrap();
+                if disk.flush && self.overlay.is_none() {
+                    (disk.submit(VIRTIO_BLK_T_FLUSH, 0, &[]), 0)
+                } else {
+                    (VIRTIO_BLK_S_OK, 0)
+                }
+            }
+            VIRTIO_BLK_T_GET_ID => {
+                let mut id = ArrayString::<[u8; 20]>::new();
+                let _ = write!(id, "rvirt-part{}", self.guest + 1);
+                write_id(guest_memory, &request, id.as_bytes())
+            }
+            _ => (VIRTIO_BLK_S_UNSUPP, 0),
+        };
+        request.complete(guest_memory, status, written)
+    }
+
+    /// Check that a read or write stays within the guest's window, and translate it into a sector
+    /// of the host disk and host physical buffers. Also returns the total length of the buffers.
+    fn remap(&self, guest_memory: &MemoryRegion, request: &Request, write: bool)
+             -> Option<(u64, ArrayVec<[(u64, u32, bool); 16]>, u32)> {
+        let mut buffers = ArrayVec::new();
+        let mut len = 0u64;
+        for &(addr, size, flags) in request.data.iter() {
+            if write == (flags & VIRTQ_DESC_F_WRITE != 0) {
+                return None;
+            }
+
+            // Guest memory is contiguous in host memory, so each buffer is as well.
+            let host_pa = pmap::va2pa(guest_memory.slice(addr, size as u64).as_ptr() as u64);
+            buffers.push((host_pa, size, !write));
+            len += size as u64;
+        }
+
+        let end = request.sector.checked_add((len + SECTOR_SIZE - 1) / SECTOR_SIZE)?;
+        if end > self.sectors || len > u32::max_value() as u64 {
+            return None;
+        }
+        Some((self.start + request.sector, buffers, len as u32))
+    }
+
+    /// Carry out a read or write a block at a time, taking blocks from the overlay where it has
+    /// them and sending writes there. Returns the number of bytes written to guest memory, or None
+    /// if the request was out of range or failed.
+    fn transfer_with_overlay(&mut self, guest_memory: &mut MemoryRegion, request: &Request, write: bool) -> Option<u32> {
+        let overlay = self.overlay.as_mut().unwrap();
+        let mut disk = SHARED_STATICS.shared_disk.lock();
+        let disk = disk.as_mut().unwrap();
+
+        let mut offset = request.sector.checked_mul(SECTOR_SIZE)?;
+        let mut written = 0;
+        for &(addr, len, flags) in request.data.iter() {
+            let end = offset.checked_add(len as u64)?;
+            if end > self.sectors * SECTOR_SIZE || write == (flags & VIRTQ_DESC_F_WRITE != 0) {
+                return None;
+            }
+
+            while offset < end {
+                let block = offset / BLOCK_SIZE;
+                let within = offset % BLOCK_SIZE;
+                let chunk = (BLOCK_SIZE - within).min(end - offset);
+                let buffer = guest_memory.slice_mut(addr + len as u64 - (end - offset), chunk);
+
+                if write {
+                    let copy = match overlay.lookup(block) {
+                        Some(copy) => copy,
+                        None => {
+                            // Only keep the copy if the rest of the block could be read into it.
+                            let copy = overlay.next_copy()?;
+                            if chunk < BLOCK_SIZE {
+                                disk.read_block(block, Some(copy))?;
+                            }
+                            overlay.insert(block)
+                        }
+                    };
+                    overlay.memory.slice_mut(copy + within, chunk).copy_from_slice(buffer);
+                } else {
+                    match overlay.lookup(block) {
+                        Some(copy) => buffer.copy_from_slice(overlay.memory.slice(copy + within, chunk)),
+                        None if offset % SECTOR_SIZE == 0 && chunk % SECTOR_SIZE == 0 => {
+                            let pa = pmap::va2pa(buffer.as_ptr() as u64);
+                            if disk.submit(VIRTIO_BLK_T_IN, offset / SECTOR_SIZE, &[(pa, chunk as u32, true)]) != VIRTIO_BLK_S_OK {
+                                return None;
+                            }
+                        }
+                        None => {
+                            disk.read_block(block, None)?;
+                            buffer.copy_from_slice(&disk.block_buffer()[within as usize..][..chunk as usize]);
+                        }
+                    }
+                    written += chunk as u32;
+                }
+                offset += chunk;
+            }
+        }
+        Some(written)
+    }
+}
+
+impl Driver for PartitionDriver {
+    const DEVICE_ID: u32 = DEVICE_ID_BLK;
+    const FEATURES: u64 = VIRTIO_BLK_F_FLUSH;
+    const QUEUE_NUM_MAX: u32 = 64;
+
+    fn interrupt(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) -> bool {
+        false
+    }
+    fn doorbell(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion, queue: u32) {
+        if queue != REQUEST_QUEUE || !device.queue_enabled(queue) {
+            return;
+        }
+
+        let mut completed = false;
+        while device.with_descriptors(guest_memory, REQUEST_QUEUE, |driver, guest_memory, descriptors| {
+            Some(driver.handle_request(guest_memory, descriptors))
+        }) {
+            completed = true;
+        }
+        if completed {
+            device.signal_used(guest_memory, REQUEST_QUEUE);
+        }
+    }
+
+    fn read_config_u8(device: &GuestDevice<Self>, _guest_memory: &mut MemoryRegion, offset: u64) -> u8 {
+        match offset {
+            0..=7 => device.host_driver.sectors.to_le_bytes()[offset as usize],
+            _ => 0
+        }
+    }
+    fn write_config_u8(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion, _offset: u64, _value: u8) {}
+
+    fn reset(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) {}
+}
+
+impl GuestDevice<PartitionDriver> {
+    /// Throw away everything the guest has written, as when it restarts.
+    pub fn discard_overlay(&mut self) {
+        if let Some(ref mut overlay) = self.host_driver.overlay {
+            overlay.clear();
+        }
+    }
+}
@@ -0,0 +1,137 @@
+// References:
+//
+// https://docs.oasis-open.org/virtio/virtio/v1.1/cs01/virtio-v1.1-cs01.html#x1-2700004
+// https://tools.ietf.org/html/rfc8439 (ChaCha20)
+
+use crate::fdt::MachineMeta;
+use crate::memory_region::MemoryRegion;
+use crate::statics::SHARED_STATICS;
+use super::*;
+
+const REQUEST_QUEUE: u32 = 0;
+
+/// Number of timing measurements mixed in at boot.
+const JITTER_SAMPLES: usize = 256;
+
+fn quarter_round(s: &mut [u32; 16], a: usize, b: usize, c: usize, d: usize) {
+    s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(16);
+    s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(12);
+    s[a] = s[a].wrapping_add(s[b]); s[d] = (s[d] ^ s[a]).rotate_left(8);
+    s[c] = s[c].wrapping_add(s[d]); s[b] = (s[b] ^ s[c]).rotate_left(7);
+}
+
+fn chacha20_block(key: &[u32; 8], counter: u64, nonce: u32) -> [u32; 16] {
+    let mut state = [0x61707865, 0x3320646e, 0x79622d32, 0x6b206574,
+                     key[0], key[1], key[2], key[3], key[4], key[5], key[6], key[7],
+                     counter as u32, (counter >> 32) as u32, nonce, 0];
+    let input = state;
+    for _ in 0..10 {
+        quarter_round(&mut state, 0, 4, 8, 12);
+        quarter_round(&mut state, 1, 5, 9, 13);
+        quarter_round(&mut state, 2, 6, 10, 14);
+        quarter_round(&mut state, 3, 7, 11, 15);
+        quarter_round(&mut state, 0, 5, 10, 15);
+        quarter_round(&mut state, 1, 6, 11, 12);
+        quarter_round(&mut state, 2, 7, 8, 13);
+        quarter_round(&mut state, 3, 4, 9, 14);
+    }
+    for i in 0..16 {
+        state[i] = state[i].wrapping_add(input[i]);
+    }
+    state
+}
+
+/// Timing noise from the cycle and time counters.
+fn jitter() -> u64 {
+    csrr!(cycle) ^ csrr!(time).rotate_left(32)
+}
+
+/// Random number generator shared by every guest: ChaCha20 run from a key that is replaced after
+/// every use, so that earlier output can't be recovered from the current state.
+pub struct Entropy {
+    key: [u32; 8],
+    counter: u64,
+}
+
+impl Entropy {
+    pub const fn new() -> Self {
+        Self { key: [0; 8], counter: 0 }
+    }
+
+    /// Stir `value` into the key.
+    fn mix(&mut self, value: u64) {
+        self.key[0] ^= value as u32;
+        self.key[1] ^= (value >> 32) as u32;
+        self.rekey();
+    }
+
+    fn rekey(&mut self) {
+        let block = chacha20_block(&self.key, 0, 1);
+        self.key.copy_from_slice(&block[..8]);
+    }
+
+    /// Seed the generator from the random bytes the bootloader placed in the device tree, if any,
+    /// and from how long a series of device register reads happened to take.
+    pub fn seed(&mut self, machine: &MachineMeta) {
+        for chunk in machine.rng_seed.chunks(8) {
+            let mut value = [0; 8];
+            value[..chunk.len()].copy_from_slice(chunk);
+            self.mix(u64::from_le_bytes(value));
+        }
+
+        let plic = crate::pmap::pa2va(machine.plic_address) as *const u32;
+        for _ in 0..JITTER_SAMPLES {
+            let start = jitter();
+            unsafe { core::ptr::read_volatile(plic) };
+            self.mix(jitter().wrapping_sub(start));
+        }
+    }
+
+    pub fn fill(&mut self, buffer: &mut [u8]) {
+        self.mix(jitter());
+        for chunk in buffer.chunks_mut(64) {
+            let block = chacha20_block(&self.key, self.counter, 0);
+            self.counter = self.counter.wrapping_add(1);
+            for (i, byte) in chunk.iter_mut().enumerate() {
+                *byte = (block[i / 4] >> (8 * (i % 4))) as u8;
+            }
+        }
+        self.rekey();
+    }
+}
+
+/// Entropy device handing out bytes from the hypervisor's random number generator.
+pub struct RngDriver;
+
+impl Driver for RngDriver {
+    const DEVICE_ID: u32 = 4;
+    const FEATURES: u64 = 0;
+    const QUEUE_NUM_MAX: u32 = 64;
+
+    fn interrupt(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) -> bool {
+        false
+    }
+    fn doorbell(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion, queue: u32) {
+        if queue != REQUEST_QUEUE || !device.queue_enabled(queue) {
+            return;
+        }
+
+        let mut filled = false;
+        while device.with_buffer_mut(guest_memory, REQUEST_QUEUE, |_, buffer| {
+            SHARED_STATICS.entropy.lock().fill(buffer);
+            Some(buffer.len() as u32)
+        }) {
+            filled = true;
+        }
+        if filled {
+            device.signal_used(guest_memory, REQUEST_QUEUE);
+        }
+    }
+
+    fn read_config_u8(_device: &GuestDevice<Self>, _guest_memory: &mut MemoryRegion, _offset: u64) -> u8 {
+        0
+    }
+    fn write_config_u8(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion, _offset: u64, _value: u8) {}
+
+    fn reset(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) {}
+}
@@ -0,0 +1,304 @@
+// References:
+//
+// https://docs.oasis-open.org/virtio/virtio/v1.1/cs01/virtio-v1.1-cs01.html#x1-1940001
+
+use crate::constants::MAX_GUESTS;
+use crate::memory_region::MemoryRegion;
+use crate::statics::SHARED_STATICS;
+use crate::riscv;
+use super::*;
+
+const RECEIVE_QUEUE: u32 = 0;
+const TRANSMIT_QUEUE: u32 = 1;
+
+/// Size of the `virtio_net_hdr` that precedes every packet.
+const VIRTIO_NET_HDR_LEN: usize = 12;
+const VIRTIO_MTU: u16 = 1500;
+
+/// Largest Ethernet frame that can cross the switch, not counting the FCS.
+const MAX_FRAME_LEN: usize = VIRTIO_MTU as usize + 14;
+/// Number of frames that can be waiting for each guest.
+const RING_FRAMES: usize = 16;
+const MAC_TABLE_SIZE: usize = 32;
+
+/// Port connecting the switch to the network outside the machine, through the host NIC that is
+/// given to the first guest.
+pub const UPLINK: usize = MAX_GUESTS;
+/// One port per guest, followed by the uplink.
+const PORTS: usize = MAX_GUESTS + 1;
+
+/// Frames waiting to be picked up by one guest.
+#[derive(Copy, Clone)]
+struct Ring {
+    frames: [[u8; MAX_FRAME_LEN]; RING_FRAMES],
+    lens: [u16; RING_FRAMES],
+    head: usize,
+    len: usize,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Self {
+            frames: [[0; MAX_FRAME_LEN]; RING_FRAMES],
+            lens: [0; RING_FRAMES],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// Returns false if the ring is full, in which case the frame is dropped.
+    fn push(&mut self, frame: &[u8]) -> bool {
+        if self.len == RING_FRAMES {
+            return false;
+        }
+
+        let slot = (self.head + self.len) % RING_FRAMES;
+        self.frames[slot][..frame.len()].copy_from_slice(frame);
+        self.lens[slot] = frame.len() as u16;
+        self.len += 1;
+        true
+    }
+
+    fn front(&self) -> Option<&[u8]> {
+        if self.len == 0 {
+            return None;
+        }
+        Some(&self.frames[self.head][..self.lens[self.head] as usize])
+    }
+
+    fn pop(&mut self) {
+        self.head = (self.head + 1) % RING_FRAMES;
+        self.len -= 1;
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Port {
+    /// Whether the guest has a device connected to this port.
+    attached: bool,
+    /// Host hart that runs the first vCPU of the guest, which is interrupted when frames arrive.
+    hart: Option<u64>,
+    ring: Ring,
+}
+
+/// Learning Ethernet switch connecting one port per guest. With `rvirt.uplink=1`, the host NIC
+/// behind the first guest's network device is attached to the uplink port as well, and frames for
+/// addresses not seen behind any guest are sent out through it. Otherwise frames only ever travel
+/// between guests.
+pub struct Switch {
+    ports: [Port; PORTS],
+    /// Which port each recently seen source address was behind.
+    macs: [Option<([u8; 6], usize)>; MAC_TABLE_SIZE],
+    next_mac: usize,
+}
+
+impl Switch {
+    pub const fn new() -> Self {
+        Self {
+            ports: [Port { attached: false, hart: None, ring: Ring::new() }; PORTS],
+            macs: [None; MAC_TABLE_SIZE],
+            next_mac: 0,
+        }
+    }
+
+    pub fn attach(&mut self, port: usize) {
+        self.ports[port].attached = true;
+    }
+
+    /// Frame at the head of the queue for `port`, if there is one.
+    pub fn front(&self, port: usize) -> Option<&[u8]> {
+        self.ports[port].ring.front()
+    }
+
+    /// Remove the frame returned by `front`.
+    pub fn pop(&mut self, port: usize) {
+        self.ports[port].ring.pop();
+    }
+
+    /// Record that the first vCPU of the guest behind `port` runs on host hart `hart`.
+    pub fn set_hart(&mut self, port: usize, hart: u64) {
+        self.ports[port].hart = Some(hart);
+    }
+
+    /// Drop everything queued for `port` and forget which addresses were behind it.
+    fn reset_port(&mut self, port: usize) {
+        self.ports[port].ring = Ring::new();
+        for entry in self.macs.iter_mut() {
+            if let Some((_, p)) = *entry {
+                if p == port {
+                    *entry = None;
+                }
+            }
+        }
+    }
+
+    fn learn(&mut self, mac: [u8; 6], port: usize) {
+        if let Some(entry) = self.macs.iter_mut().flatten().find(|e| e.0 == mac) {
+            entry.1 = port;
+            return;
+        }
+
+        self.macs[self.next_mac] = Some((mac, port));
+        self.next_mac = (self.next_mac + 1) % MAC_TABLE_SIZE;
+    }
+
+    /// Send a frame received on port `source` towards its destination, flooding it to every other
+    /// port if the destination is a group address or hasn't been seen yet. Returns a mask of the
+    /// ports that were given the frame.
+    pub fn forward(&mut self, source: usize, frame: &[u8]) -> u32 {
+        if frame.len() < 14 || frame.len() > MAX_FRAME_LEN {
+            return 0;
+        }
+
+        let mut destination = [0; 6];
+        let mut mac = [0; 6];
+        destination.copy_from_slice(&frame[..6]);
+        mac.copy_from_slice(&frame[6..12]);
+        if mac[0] & 1 == 0 {
+            self.learn(mac, source);
+        }
+
+        let known = self.macs.iter().flatten().find(|e| e.0 == destination).map(|e| e.1);
+        let mut delivered = 0;
+        for port in 0..PORTS {
+            let wanted = match known {
+                Some(p) if destination[0] & 1 == 0 => p == port,
+                _ => true,
+            };
+            if wanted && port != source && self.ports[port].attached && self.ports[port].ring.push(frame) {
+                delivered |= 1 << port;
+            }
+        }
+        delivered
+    }
+
+    /// Interrupt the harts behind the ports in `mask` so that they pick up their frames. Called
+    /// with the switch unlocked. The uplink is driven by the first guest's device, so it is served
+    /// by that guest's hart.
+    pub fn notify(mask: u32) {
+        for port in 0..PORTS {
+            if mask & (1 << port) != 0 {
+                let hart_port = if port == UPLINK { 0 } else { port };
+                if let Some(hart) = SHARED_STATICS.switch.lock().ports[hart_port].hart {
+                    riscv::sbi::send_ipi_to_hart(hart);
+                }
+            }
+        }
+    }
+}
+
+/// Virtio-net device connecting a guest to the inter-guest switch.
+pub struct SwitchPortDriver {
+    port: usize,
+    mac: [u8; 6],
+}
+
+impl SwitchPortDriver {
+    /// Create the device for guest slot `port`. Each one gets a distinct, locally administered
+    /// address.
+    pub fn new(port: usize) -> Self {
+        SHARED_STATICS.switch.lock().attach(port);
+        Self {
+            port,
+            mac: [0x02, 0, 0, 0, 0, port as u8 + 1],
+        }
+    }
+}
+
+impl Driver for SwitchPortDriver {
+    const DEVICE_ID: u32 = 1;
+    const FEATURES: u64 = VIRTIO_NET_F_MAC | VIRTIO_NET_F_MTU;
+    const QUEUE_NUM_MAX: u32 = 64;
+
+    fn interrupt(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) -> bool {
+        false
+    }
+    fn doorbell(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion, queue: u32) {
+        if !device.queue_enabled(queue) {
+            return;
+        }
+
+        match queue {
+            RECEIVE_QUEUE => device.poll_input(guest_memory),
+            TRANSMIT_QUEUE => {
+                let mut transmitted = false;
+                let mut notify = 0;
+                while device.with_buffer(guest_memory, TRANSMIT_QUEUE, |driver, buffers| {
+                    let mut frame = [0; MAX_FRAME_LEN];
+                    let mut skip = VIRTIO_NET_HDR_LEN;
+                    let mut len = 0;
+                    for buffer in buffers {
+                        let data = &buffer[skip.min(buffer.len())..];
+                        skip -= skip.min(buffer.len());
+                        if len + data.len() > frame.len() {
+                            // Too large to be a valid frame, so drop it.
+                            return Some(0);
+                        }
+                        frame[len..][..data.len()].copy_from_slice(data);
+                        len += data.len();
+                    }
+
+                    notify |= SHARED_STATICS.switch.lock().forward(driver.port, &frame[..len]);
+                    Some(0)
+                }) {
+                    transmitted = true;
+                }
+                if transmitted {
+                    device.signal_used(guest_memory, TRANSMIT_QUEUE);
+                }
+                Switch::notify(notify);
+            }
+            _ => {}
+        }
+    }
+
+    fn read_config_u8(device: &GuestDevice<Self>, _guest_memory: &mut MemoryRegion, offset: u64) -> u8 {
+        match offset {
+            0..=5 => device.host_driver.mac[offset as usize],
+            10 => VIRTIO_MTU.to_le_bytes()[0],
+            11 => VIRTIO_MTU.to_le_bytes()[1],
+            _ => 0
+        }
+    }
+    fn write_config_u8(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion, _offset: u64, _value: u8) {}
+
+    fn reset(device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) {
+        SHARED_STATICS.switch.lock().reset_port(device.host_driver.port);
+    }
+}
+
+impl GuestDevice<SwitchPortDriver> {
+    /// Move frames the switch has queued for this guest into buffers it has made available.
+    pub fn poll_input(&mut self, guest_memory: &mut MemoryRegion) {
+        if !self.driver_ok() || !self.queue_enabled(RECEIVE_QUEUE) {
+            return;
+        }
+
+        let port = self.host_driver.port;
+        let mut received = false;
+        let mut switch = SHARED_STATICS.switch.lock();
+        while let Some(frame) = switch.ports[port].ring.front() {
+            if !self.with_buffer_mut(guest_memory, RECEIVE_QUEUE, |_, buffer| {
+                if buffer.len() < VIRTIO_NET_HDR_LEN + frame.len() {
+                    return Some(0);
+                }
+
+                for byte in &mut buffer[..VIRTIO_NET_HDR_LEN] {
+                    *byte = 0;
+                }
+                buffer[10] = 1; // num_buffers
+                buffer[VIRTIO_NET_HDR_LEN..][..frame.len()].copy_from_slice(frame);
+                Some((VIRTIO_NET_HDR_LEN + frame.len()) as u32)
+            }) {
+                break;
+            }
+            switch.ports[port].ring.pop();
+            received = true;
+        }
+        drop(switch);
+
+        if received {
+            self.signal_used(guest_memory, RECEIVE_QUEUE);
+        }
+    }
+}
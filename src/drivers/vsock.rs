@@ -0,0 +1,344 @@
+// References:
+//
+// https://docs.oasis-open.org/virtio/virtio/v1.1/cs01/virtio-v1.1-cs01.html#x1-39000010
+
+use arrayvec::{ArrayString, ArrayVec};
+use byteorder::{ByteOrder, LittleEndian};
+use crate::management;
+use crate::memory_region::MemoryRegion;
+use super::*;
+
+const RECEIVE_QUEUE: u32 = 0;
+const TRANSMIT_QUEUE: u32 = 1;
+
+/// Context id that virtio-vsock reserves for the host, which here is the hypervisor.
+const HOST_CID: u64 = 2;
+/// Port on the hypervisor side that the management interface listens on.
+pub const MANAGEMENT_PORT: u32 = 1024;
+
+const HEADER_LEN: usize = 44;
+const VIRTIO_VSOCK_TYPE_STREAM: u16 = 1;
+
+const VIRTIO_VSOCK_OP_REQUEST: u16 = 1;
+const VIRTIO_VSOCK_OP_RESPONSE: u16 = 2;
+const VIRTIO_VSOCK_OP_RST: u16 = 3;
+const VIRTIO_VSOCK_OP_SHUTDOWN: u16 = 4;
+const VIRTIO_VSOCK_OP_RW: u16 = 5;
+const VIRTIO_VSOCK_OP_CREDIT_UPDATE: u16 = 6;
+const VIRTIO_VSOCK_OP_CREDIT_REQUEST: u16 = 7;
+
+/// Receive buffer space advertised to the guest. Commands are carried out as soon as their line is
+/// complete, so this is only ever used up by a guest that sends faster than replies go back.
+const BUFFER_SIZE: u32 = 4096;
+/// Most payload bytes put in a single packet to the guest.
+const MAX_PAYLOAD: usize = 1024;
+const MAX_CONNECTIONS: usize = 4;
+
+/// A stream connection from the guest to the management port.
+struct Connection {
+    /// Port on the guest side.
+    port: u32,
+    /// Receive buffer space of the guest, and how many bytes sent to it the guest has consumed, as
+    /// of its latest packet.
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+    /// Payload bytes sent to the guest and received from it.
+    tx_cnt: u32,
+    fwd_cnt: u32,
+    /// Value of `fwd_cnt` in the last packet sent to the guest.
+    reported_fwd_cnt: u32,
+
+    /// Partial command, and whether some of it had to be dropped.
+    line: ArrayVec<[u8; 256]>,
+    line_overflowed: bool,
+    /// Replies, of which the first `output_sent` bytes have already been sent.
+    output: ArrayString<[u8; 2048]>,
+    output_sent: usize,
+}
+
+impl Connection {
+    fn new(port: u32) -> Self {
+        Self {
+            port,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+            tx_cnt: 0,
+            fwd_cnt: 0,
+            reported_fwd_cnt: 0,
+            line: ArrayVec::new(),
+            line_overflowed: false,
+            output: ArrayString::new(),
+            output_sent: 0,
+        }
+    }
+
+    /// Number of bytes that can be sent before the guest runs out of buffer space.
+    fn credit(&self) -> u32 {
+        self.peer_buf_alloc.saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+}
+
+/// Socket device connecting a control guest to the management interface of the hypervisor. The
+/// guest can open stream connections to the hypervisor's management port and nowhere else.
+pub struct VsockDriver {
+    /// Slot of the guest in `SHARED_STATICS.guests`.
+    guest: usize,
+    connections: [Option<Connection>; MAX_CONNECTIONS],
+    /// Packets without payload waiting to be sent, as operation, hypervisor port and guest port.
+    control_packets: ArrayVec<[(u16, u32, u32); 16]>,
+}
+
+impl VsockDriver {
+    pub fn new(guest: usize) -> Self {
+        Self {
+            guest,
+            connections: [None, None, None, None],
+            control_packets: ArrayVec::new(),
+        }
+    }
+
+    /// Context id of the guest. The first three are reserved, so guest N gets N + 2.
+    fn guest_cid(&self) -> u64 {
+        self.guest as u64 + 3
+    }
+
+    fn connection(&mut self, port: u32) -> Option<&mut Connection> {
+        self.connections.iter_mut().filter_map(|c| c.as_mut()).find(|c| c.port == port)
+    }
+
+    /// Queue a packet without payload for the guest. If too many are already waiting, it is
+    /// dropped, which for a reset only means the guest has to time out the connection instead.
+    fn send_control(&mut self, op: u16, host_port: u32, guest_port: u32) {
+        let _ = self.control_packets.try_push((op, host_port, guest_port));
+    }
+
+    /// Handle a packet the guest sent, made up of `buffers`.
+    fn receive_packet(&mut self, buffers: &[&[u8]]) {
+        let mut bytes = buffers.iter().flat_map(|b| b.iter().cloned());
+        let mut header = [0; HEADER_LEN];
+        for byte in header.iter_mut() {
+            match bytes.next() {
+                Some(b) => *byte = b,
+                None => return,
+            }
+        }
+
+        let src_cid = LittleEndian::read_u64(&header[0..]);
+        let dst_cid = LittleEndian::read_u64(&header[8..]);
+        let src_port = LittleEndian::read_u32(&header[16..]);
+        let dst_port = LittleEndian::read_u32(&header[20..]);
+        let len = LittleEndian::read_u32(&header[24..]);
+        let socket_type = LittleEndian::read_u16(&header[28..]);
+        let op = LittleEndian::read_u16(&header[30..]);
+        let buf_alloc = LittleEndian::read_u32(&header[36..]);
+        let fwd_cnt = LittleEndian::read_u32(&header[40..]);
+
+        if op == VIRTIO_VSOCK_OP_RST {
+            if let Some(slot) = self.connections.iter_mut().find(|c| c.as_ref().map(|c| c.port) == Some(src_port)) {
+                *slot = None;
+            }
+            return;
+        }
+        if src_cid != self.guest_cid() || dst_cid != HOST_CID || socket_type != VIRTIO_VSOCK_TYPE_STREAM
+            || dst_port != MANAGEMENT_PORT {
+            self.send_control(VIRTIO_VSOCK_OP_RST, dst_port, src_port);
+            return;
+        }
+
+        if op == VIRTIO_VSOCK_OP_REQUEST {
+            // A new request from a port that is still connected replaces the old connection.
+            let slot = self.connections.iter().position(|c| c.as_ref().map(|c| c.port) == Some(src_port))
+                .or_else(|| self.connections.iter().position(|c| c.is_none()));
+            match slot {
+                Some(slot) => {
+                    let mut connection = Connection::new(src_port);
+                    connection.peer_buf_alloc = buf_alloc;
+                    connection.peer_fwd_cnt = fwd_cnt;
+                    self.connections[slot] = Some(connection);
+                    self.send_control(VIRTIO_VSOCK_OP_RESPONSE, dst_port, src_port);
+                }
+                None => self.send_control(VIRTIO_VSOCK_OP_RST, dst_port, src_port),
+            }
+            return;
+        }
+
+        let guest = self.guest;
+        let connection = match self.connection(src_port) {
+            Some(connection) => connection,
+            None => {
+                self.send_control(VIRTIO_VSOCK_OP_RST, dst_port, src_port);
+                return;
+            }
+        };
+        connection.peer_buf_alloc = buf_alloc;
+        connection.peer_fwd_cnt = fwd_cnt;
+
+        match op {
+            VIRTIO_VSOCK_OP_RW => {
+                for byte in bytes.take(len as usize) {
+                    connection.fwd_cnt = connection.fwd_cnt.wrapping_add(1);
+                    if byte != b'\n' {
+                        connection.line_overflowed |= connection.line.try_push(byte).is_err();
+                        continue;
+                    }
+
+                    if connection.output_sent == connection.output.len() {
+                        connection.output.clear();
+                        connection.output_sent = 0;
+                    }
+                    match core::str::from_utf8(&connection.line) {
+                        Ok(_) if connection.line_overflowed => {
+                            let _ = connection.output.try_push_str("error: line too long\n");
+                        }
+                        Ok(line) => management::execute(guest, line.trim_end_matches('\r'), &mut connection.output),
+                        Err(_) => {
+                            let _ = connection.output.try_push_str("error: unknown command\n");
+                        }
+                    }
+                    connection.line.clear();
+                    connection.line_overflowed = false;
+                }
+
+                // Replies carry the new count, but without any the guest would eventually stall.
+                let unreported = connection.fwd_cnt.wrapping_sub(connection.reported_fwd_cnt);
+                if connection.output_sent == connection.output.len() && unreported >= BUFFER_SIZE / 2 {
+                    self.send_control(VIRTIO_VSOCK_OP_CREDIT_UPDATE, dst_port, src_port);
+                }
+            }
+            VIRTIO_VSOCK_OP_SHUTDOWN => {
+                if let Some(slot) = self.connections.iter_mut().find(|c| c.as_ref().map(|c| c.port) == Some(src_port)) {
+                    *slot = None;
+                }
+                self.send_control(VIRTIO_VSOCK_OP_RST, dst_port, src_port);
+            }
+            VIRTIO_VSOCK_OP_CREDIT_REQUEST => self.send_control(VIRTIO_VSOCK_OP_CREDIT_UPDATE, dst_port, src_port),
+            _ => {}
+        }
+    }
+
+    /// Write the next packet for the guest into the buffer made up of `descriptors`. Returns the
+    /// number of bytes written, or None if there is nothing to send or the buffer can't take it.
+    fn send_packet(&mut self, guest_memory: &mut MemoryRegion, descriptors: &[(u64, u32, u16)]) -> Option<u32> {
+        let in_guest_memory = |&(addr, len, flags): &(u64, u32, u16)| {
+            flags & VIRTQ_DESC_F_WRITE != 0 && guest_memory.in_region(addr) &&
+                (len == 0 || addr.checked_add(len as u64 - 1).map(|end| guest_memory.in_region(end)).unwrap_or(false))
+        };
+        if !descriptors.iter().all(in_guest_memory) {
+            return None;
+        }
+        let capacity = descriptors.iter().map(|&(_, len, _)| len as usize).sum::<usize>();
+        if capacity < HEADER_LEN {
+            return None;
+        }
+
+        let mut packet = [0; HEADER_LEN + MAX_PAYLOAD];
+        let (op, guest_port, payload_len) = if !self.control_packets.is_empty() {
+            let (op, _, guest_port) = self.control_packets.remove(0);
+            (op, guest_port, 0)
+        } else {
+            let connection = self.connections.iter_mut().filter_map(|c| c.as_mut())
+                .find(|c| c.output_sent < c.output.len() && c.credit() > 0)?;
+            let output = &connection.output.as_bytes()[connection.output_sent..];
+            let payload_len = output.len().min(capacity - HEADER_LEN).min(MAX_PAYLOAD)
+                .min(connection.credit() as usize);
+            packet[HEADER_LEN..][..payload_len].copy_from_slice(&output[..payload_len]);
+            connection.output_sent += payload_len;
+            connection.tx_cnt = connection.tx_cnt.wrapping_add(payload_len as u32);
+            (VIRTIO_VSOCK_OP_RW, connection.port, payload_len)
+        };
+
+        let guest_cid = self.guest_cid();
+        let fwd_cnt = match self.connection(guest_port) {
+            Some(connection) => {
+                connection.reported_fwd_cnt = connection.fwd_cnt;
+                connection.fwd_cnt
+            }
+            None => 0,
+        };
+        LittleEndian::write_u64(&mut packet[0..], HOST_CID);
+        LittleEndian::write_u64(&mut packet[8..], guest_cid);
+        LittleEndian::write_u32(&mut packet[16..], MANAGEMENT_PORT);
+        LittleEndian::write_u32(&mut packet[20..], guest_port);
+        LittleEndian::write_u32(&mut packet[24..], payload_len as u32);
+        LittleEndian::write_u16(&mut packet[28..], VIRTIO_VSOCK_TYPE_STREAM);
+        LittleEndian::write_u16(&mut packet[30..], op);
+        LittleEndian::write_u32(&mut packet[36..], BUFFER_SIZE);
+        LittleEndian::write_u32(&mut packet[40..], fwd_cnt);
+
+        let mut remaining = &packet[..HEADER_LEN + payload_len];
+        for &(addr, len, _) in descriptors {
+            let n = remaining.len().min(len as usize);
+            guest_memory.slice_mut(addr, n as u64).copy_from_slice(&remaining[..n]);
+            remaining = &remaining[n..];
+        }
+        Some((HEADER_LEN + payload_len) as u32)
+    }
+}
+
+impl Driver for VsockDriver {
+    const DEVICE_ID: u32 = 19;
+    const FEATURES: u64 = 0;
+    const QUEUE_NUM_MAX: u32 = 64;
+
+    fn interrupt(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) -> bool {
+        false
+    }
+    fn doorbell(device: &mut GuestDevice<Self>, guest_memory: &mut MemoryRegion, queue: u32) {
+        if !device.queue_enabled(queue) {
+            return;
+        }
+
+        match queue {
+            RECEIVE_QUEUE => device.poll_input(guest_memory),
+            TRANSMIT_QUEUE => {
+                let mut transmitted = false;
+                while device.with_buffer(guest_memory, TRANSMIT_QUEUE, |driver, buffers| {
+                    driver.receive_packet(buffers);
+                    Some(0)
+                }) {
+                    transmitted = true;
+                }
+                if transmitted {
+                    device.signal_used(guest_memory, TRANSMIT_QUEUE);
+                }
+
+                // Replies are ready as soon as the commands have been carried out.
+                device.poll_input(guest_memory);
+            }
+            // Nothing is ever sent on the event queue, since the transport is never reset.
+            _ => {}
+        }
+    }
+
+    fn read_config_u8(device: &GuestDevice<Self>, _guest_memory: &mut MemoryRegion, offset: u64) -> u8 {
+        match offset {
+            0..=7 => device.host_driver.guest_cid().to_le_bytes()[offset as usize],
+            _ => 0
+        }
+    }
+    fn write_config_u8(_device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion, _offset: u64, _value: u8) {}
+
+    fn reset(device: &mut GuestDevice<Self>, _guest_memory: &mut MemoryRegion) {
+        device.host_driver.connections = [None, None, None, None];
+        device.host_driver.control_packets.clear();
+    }
+}
+
+impl GuestDevice<VsockDriver> {
+    /// Send whatever packets are waiting for the guest, as far as its receive buffers allow.
+    pub fn poll_input(&mut self, guest_memory: &mut MemoryRegion) {
+        if !self.driver_ok() || !self.queue_enabled(RECEIVE_QUEUE) {
+            return;
+        }
+
+        let mut received = false;
+        while self.with_descriptors(guest_memory, RECEIVE_QUEUE, |driver, guest_memory, descriptors| {
+            driver.send_packet(guest_memory, descriptors)
+        }) {
+            received = true;
+        }
+        if received {
+            self.signal_used(guest_memory, RECEIVE_QUEUE);
+        }
+    }
+}
@@ -61,10 +61,64 @@ pub struct MachineMeta {
 
     pub virtio: ArrayVec<[Device; 16]>,
 
+    /// Cadence GEM Ethernet controller, as found on the HiFive Unleashed, and its MAC address.
+    pub macb: Option<Device>,
+    pub macb_mac: [u8; 6],
+
     pub bootargs: ArrayString<[u8; 256]>,
 
     pub initrd_start: u64,
     pub initrd_end: u64,
+
+    /// Random bytes from the bootloader for seeding the hypervisor's random number generator.
+    pub rng_seed: ArrayVec<[u8; 64]>,
+
+    /// Number of vCPUs to give each guest (`rvirt.vcpus=N`).
+    pub guest_vcpus: u64,
+    /// Number of guests to run, or zero for enough to give every host hart one vCPU
+    /// (`rvirt.guests=N`).
+    pub guests: u64,
+    /// Timer ticks a vCPU runs for before another on the same hart gets a turn, or zero for the
+    /// default (`rvirt.timeslice=N`).
+    pub time_slice: u64,
+    /// Whether to split the first host virtio-blk device between the guests instead of passing it
+    /// through to one of them (`rvirt.shared_disk=1`).
+    pub shared_disk: bool,
+    /// Whether every guest should instead see the whole of that disk, with its writes kept in
+    /// memory rather than on the disk (`rvirt.disk_overlay=1`).
+    pub disk_overlay: bool,
+    /// Guest that may manage the others through a vsock connection to the hypervisor, or zero for
+    /// none (`rvirt.control=N`).
+    pub control_guest: u64,
+    /// Size in MB of the memory window shared between guests, or zero for none (`rvirt.shmem=N`).
+    pub shmem_size: u64,
+    /// Guest whose first vCPU the GDB stub debugs, or zero for the first guest (`rvirt.gdb=N`).
+    pub gdb_guest: u64,
+    /// Whether the Cadence GEM given to the first guest should also connect the switch between
+    /// guests to the network (`rvirt.uplink=1`).
+    pub uplink: bool,
+}
+
+impl MachineMeta {
+    /// Apply a hypervisor option of the form `rvirt.<name>=<value>` taken from the bootargs. Such
+    /// options are consumed here and not passed on to guests.
+    fn parse_option(&mut self, arg: &str) {
+        let mut parts = arg.trim_start_matches("rvirt.").splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let value = parts.next().and_then(|v| v.parse::<u64>().ok());
+        match (name, value) {
+            ("vcpus", Some(n)) => self.guest_vcpus = n,
+            ("guests", Some(n)) => self.guests = n,
+            ("timeslice", Some(n)) => self.time_slice = n,
+            ("shared_disk", Some(n)) => self.shared_disk = n != 0,
+            ("disk_overlay", Some(n)) => self.disk_overlay = n != 0,
+            ("control", Some(n)) => self.control_guest = n,
+            ("shmem", Some(n)) => self.shmem_size = n,
+            ("gdb", Some(n)) => self.gdb_guest = n,
+            ("uplink", Some(n)) => self.uplink = n != 0,
+            _ => {}
+        }
+    }
 }
 
 #[repr(C)]
@@ -178,6 +232,7 @@ impl<'a> Fdt<'a> {
         let mut initrd_start: Option<u64> = None;
         let mut initrd_end: Option<u64> = None;
         let mut plic: Option<u64> = None;
+        let mut macb: (Option<(u64, u64)>, Option<u64>) = (None, None);
 
         let mut meta = MachineMeta::default();
 
@@ -196,9 +251,24 @@ impl<'a> Fdt<'a> {
                 FdtVisit::Property { name, prop } => match (path, name) {
                     ("/chosen", "linux,initrd-end") => initrd_end = Some(prop.read_int()),
                     ("/chosen", "linux,initrd-start") => initrd_start = Some(prop.read_int()),
+                    ("/chosen", "rng-seed") |
+                    ("/chosen", "kaslr-seed") => {
+                        for &byte in prop.value_slice().iter() {
+                            let _ = meta.rng_seed.try_push(byte);
+                        }
+                    }
                     ("/chosen", "bootargs") => {
-                        meta.bootargs.push_str(prop.value_str()
-                                               .expect("Unable to parse bootargs string"))
+                        let bootargs = prop.value_str().expect("Unable to parse bootargs string");
+                        for arg in bootargs.split(' ').filter(|a| !a.is_empty()) {
+                            if arg.starts_with("rvirt.") {
+                                meta.parse_option(arg);
+                            } else {
+                                if !meta.bootargs.is_empty() {
+                                    meta.bootargs.push(' ');
+                                }
+                                meta.bootargs.push_str(arg);
+                            }
+                        }
                     }
                     ("/memory", "reg") => {
                         let region = prop.read_range();
@@ -239,6 +309,16 @@ impl<'a> Fdt<'a> {
                         let index = virtio_address_map.index_of(unit_addresses[1].unwrap_or(0));
                         virtio[index].1 = Some(prop.read_int());
                     }
+                    // The GEM has a second register range for its clock controls, so only the first
+                    // one is read here.
+                    ("/soc/ethernet", "reg") => {
+                        macb.0 = Some(((prop.read_cell(0) as u64) << 32 | prop.read_cell(1) as u64,
+                                       (prop.read_cell(2) as u64) << 32 | prop.read_cell(3) as u64));
+                    }
+                    ("/soc/ethernet", "interrupts") => macb.1 = Some(prop.read_cell(0) as u64),
+                    ("/soc/ethernet", "local-mac-address") => {
+                        meta.macb_mac.copy_from_slice(&prop.value_slice()[..6]);
+                    }
                     ("/cpus/cpu", "reg") => {
                         let index = virtio_address_map.index_of(unit_addresses[2].unwrap_or(0));
                         cpus[index].0 = Some(prop.read_int());
@@ -283,10 +363,14 @@ impl<'a> Fdt<'a> {
         }
         meta.virtio.sort_unstable_by_key(|v| v.base_address);
 
+        if let (Some((base_address, size)), Some(irq)) = macb {
+            meta.macb = Some(Device { base_address, size, irq });
+        }
+
         meta
     }
 
-    pub fn initialize_guest(&mut self, guest_memory_size: u64, bootargs: &str) {
+    pub fn initialize_guest(&mut self, guest_memory_size: u64, bootargs: &str, vcpus: u64, shmem_size: u64) {
         self.walk(|path, unit_addresses, v| match v {
             FdtVisit::Property { name, prop } => match (path, name) {
                 ("/chosen", "bootargs") => {
@@ -304,9 +388,27 @@ impl<'a> Fdt<'a> {
                     BigEndian::write_u64(&mut new_region[8..], guest_memory_size);
                     prop.set(&new_region);
                 }
+                // The first range is the shared window, and the second its registers.
+                ("/shmem", "reg") => BigEndian::write_u64(&mut prop.value_slice()[8..], shmem_size),
+                // Each hart has two entries (M-mode and S-mode) of two cells each.
+                ("/soc/interrupt-controller", "interrupts-extended") |
+                ("/soc/clint", "interrupts-extended") => {
+                    let len = 16 * vcpus as usize;
+                    if prop.len() > len {
+                        prop.truncate(len);
+                    }
+                }
                 _ => {},
             }
-            FdtVisit::Node { .. } => {}
+            FdtVisit::Node { mask } => {
+                // The guest device tree lists the maximum number of harts, so hide the extra ones.
+                if path == "/cpus/cpu" && unit_addresses[2].unwrap_or(0) >= vcpus {
+                    *mask = true;
+                }
+                if path == "/shmem" && shmem_size == 0 {
+                    *mask = true;
+                }
+            }
         });
     }
 
@@ -468,10 +570,21 @@ impl<'a> Property<'a> {
         assert_eq!(value.len(), self.len());
         self.0[12..].copy_from_slice(value);
     }
+
+    /// Shrink the property to `len` bytes, and fill the space freed up with FDT_NOP tokens.
+    pub fn truncate(&mut self, len: usize) {
+        assert!(len <= self.len());
+
+        let old_end = 12 + round4(self.len());
+        BigEndian::write_u32(&mut self.0[4..], len as u32);
+        for i in (12 + round4(len)..old_end).step_by(4) {
+            BigEndian::write_u32(&mut self.0[i..], FDT_NOP);
+        }
+    }
 }
 
 enum FdtVisit<'a> {
-    Node { #[allow(unused)] mask: &'a mut bool },
+    Node { mask: &'a mut bool },
     Property {
         name: &'a str,
         prop: &'a mut Property<'a>,
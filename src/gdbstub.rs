@@ -0,0 +1,522 @@
+//! Stub for the GDB remote serial protocol, through which GDB can debug the first vCPU of a guest as
+//! if it were a bare-metal target. Pressing Ctrl-A g on the host console stops that vCPU and hands
+//! the console over to the stub, after which GDB can be connected to the same serial port. The
+//! console goes back to the guests once GDB detaches.
+//!
+//! Only the registers and memory of the target vCPU are visible. Breakpoints are placed by writing
+//! `c.ebreak` into guest memory, and single-stepping by placing temporary breakpoints at every
+//! instruction that could come next.
+//!
+//! While the target is stopped, its host hart serves GDB from inside the trap handler with the
+//! target's context held, so every other vCPU time-sliced onto that hart, whichever guest it belongs
+//! to, is stopped along with it. vCPUs on other host harts keep running; any that reach a
+//! breakpoint wait there until it is removed.
+
+// References:
+//
+// https://sourceware.org/gdb/current/onlinedocs/gdb/Remote-Protocol.html
+
+use arrayvec::{ArrayString, ArrayVec};
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use crate::context::{Context, REQUEST_FENCE_I};
+use crate::riscv::bits::*;
+use crate::statics::SHARED_STATICS;
+use crate::{pmap, riscv, trap};
+
+const MAX_BREAKPOINTS: usize = 32;
+/// Largest packet GDB may send, as advertised in reply to `qSupported`.
+const PACKET_SIZE: usize = 1024;
+/// Most bytes of memory returned by one `m` packet.
+const MAX_MEMORY_READ: u64 = 512;
+
+const SIGINT: u8 = 2;
+const SIGTRAP: u8 = 5;
+
+/// GDB interrupts a running target by sending this byte outside of any packet.
+const INTERRUPT: u8 = 0x03;
+const C_EBREAK: u16 = 0x9002;
+
+// GDB's numbering of RISC-V registers.
+const REG_PC: u64 = 32;
+const REG_FIRST_FP: u64 = 33;
+const REG_FIRST_CSR: u64 = 65;
+
+#[derive(Copy, Clone)]
+struct Breakpoint {
+    va: u64,
+    /// Guest physical address the breakpoint was written to, and the two bytes it replaced.
+    pa: u64,
+    original: u16,
+    /// Set for the breakpoints placed to single-step, which are removed when the target stops.
+    temporary: bool,
+}
+
+pub struct GdbStub {
+    /// Slot of the guest whose first vCPU is debugged.
+    guest: AtomicU64,
+    attached: AtomicBool,
+    /// Set on attaching, until the target vCPU has stopped.
+    stop_requested: AtomicBool,
+    breakpoints: Mutex<[Option<Breakpoint>; MAX_BREAKPOINTS]>,
+}
+
+impl GdbStub {
+    pub const fn new() -> Self {
+        Self {
+            guest: AtomicU64::new(0),
+            attached: AtomicBool::new(false),
+            stop_requested: AtomicBool::new(false),
+            breakpoints: Mutex::new([None; MAX_BREAKPOINTS]),
+        }
+    }
+
+    /// Debug the guest in slot `guest` rather than the first one. Must be called before attaching.
+    pub fn set_target(&self, guest: usize) {
+        self.guest.store(guest as u64, Ordering::SeqCst);
+    }
+
+    pub fn attached(&self) -> bool {
+        self.attached.load(Ordering::SeqCst)
+    }
+
+    fn is_target(&self, state: &Context) -> bool {
+        state.guest_index as u64 == self.guest.load(Ordering::SeqCst) && state.vcpu == 0
+    }
+}
+
+/// Attach the stub to its target, which stops the next time it traps into the hypervisor. Returns
+/// the id of the target guest. Called with the console lock held.
+pub fn attach() -> u64 {
+    let stub = &SHARED_STATICS.gdb;
+    stub.stop_requested.store(true, Ordering::SeqCst);
+    stub.attached.store(true, Ordering::SeqCst);
+    stub.guest.load(Ordering::SeqCst) + 1
+}
+
+/// Called on every return to a guest. If `state` is the target, stop it if GDB asked for it to be.
+pub fn poll(state: &mut Context) {
+    let stub = &SHARED_STATICS.gdb;
+    if !stub.attached() || !stub.is_target(state) {
+        return;
+    }
+
+    if stub.stop_requested.swap(false, Ordering::SeqCst) {
+        stop(state, SIGTRAP, false);
+    } else if SHARED_STATICS.uart_writer.lock().getchar() == Some(INTERRUPT) {
+        stop(state, SIGINT, true);
+    }
+}
+
+/// Handle a breakpoint exception. Returns false if it wasn't caused by one of the stub's
+/// breakpoints, in which case it belongs to the guest.
+pub fn handle_breakpoint(state: &mut Context) -> bool {
+    let stub = &SHARED_STATICS.gdb;
+    if !stub.attached() || state.guest_index as u64 != stub.guest.load(Ordering::SeqCst) {
+        return false;
+    }
+
+    let pa = match translate(state, csrr!(sepc)) {
+        Some(pa) => pa,
+        None => return false,
+    };
+    if !stub.breakpoints.lock().iter().flatten().any(|b| b.pa == pa) {
+        return false;
+    }
+
+    // Other vCPUs return to the breakpoint without advancing past it, until it is removed.
+    if stub.is_target(state) {
+        stop(state, SIGTRAP, true);
+    }
+    true
+}
+
+/// Guest physical address that `va` refers to for the target, if it is backed by guest memory.
+fn translate(state: &Context, va: u64) -> Option<u64> {
+    let pa = if state.csrs.satp & SATP_MODE == 0 {
+        va
+    } else {
+        let page = pmap::translate_guest_address(&state.guest_memory, (state.csrs.satp & SATP_PPN) << 12, va)?;
+        (page.guest_pa & !0xfff) | (va & 0xfff)
+    };
+    if state.guest_memory.in_region(pa) { Some(pa) } else { None }
+}
+
+fn read_byte(state: &Context, breakpoints: &[Option<Breakpoint>], va: u64) -> Option<u8> {
+    let pa = translate(state, va)?;
+    // Breakpoints are hidden from GDB, which expects to see the original instructions.
+    for b in breakpoints.iter().flatten() {
+        if pa == b.pa || pa == b.pa + 1 {
+            return Some(b.original.to_le_bytes()[(pa - b.pa) as usize]);
+        }
+    }
+    Some(state.guest_memory.slice(pa, 1)[0])
+}
+
+fn write_byte(state: &mut Context, breakpoints: &mut [Option<Breakpoint>], va: u64, value: u8) -> bool {
+    let pa = match translate(state, va) {
+        Some(pa) => pa,
+        None => return false,
+    };
+    for b in breakpoints.iter_mut().flatten() {
+        if pa == b.pa || pa == b.pa + 1 {
+            let mut original = b.original.to_le_bytes();
+            original[(pa - b.pa) as usize] = value;
+            b.original = u16::from_le_bytes(original);
+            return true;
+        }
+    }
+    state.guest_memory.slice_mut(pa, 1)[0] = value;
+    true
+}
+
+fn insert_breakpoint(state: &mut Context, va: u64, temporary: bool) -> bool {
+    let mut breakpoints = SHARED_STATICS.gdb.breakpoints.lock();
+    let pa = match (translate(state, va), translate(state, va + 1)) {
+        (Some(pa), Some(next)) if next == pa + 1 => pa,
+        _ => return false,
+    };
+    if let Some(b) = breakpoints.iter_mut().flatten().find(|b| b.pa == pa) {
+        b.temporary = b.temporary && temporary;
+        return true;
+    }
+
+    let slot = match breakpoints.iter_mut().find(|b| b.is_none()) {
+        Some(slot) => slot,
+        None => return false,
+    };
+    let bytes = state.guest_memory.slice_mut(pa, 2);
+    let original = u16::from_le_bytes([bytes[0], bytes[1]]);
+    bytes.copy_from_slice(&C_EBREAK.to_le_bytes());
+    *slot = Some(Breakpoint { va, pa, original, temporary });
+    true
+}
+
+/// Remove every breakpoint that `filter` selects, restoring the instructions they replaced.
+fn remove_breakpoints<F: Fn(&Breakpoint) -> bool>(state: &mut Context, filter: F) {
+    for slot in SHARED_STATICS.gdb.breakpoints.lock().iter_mut() {
+        if let Some(b) = *slot {
+            if filter(&b) {
+                state.guest_memory.slice_mut(b.pa, 2).copy_from_slice(&b.original.to_le_bytes());
+                *slot = None;
+            }
+        }
+    }
+}
+
+/// Make every vCPU of the guest see instructions that were just written.
+fn sync_instructions(state: &mut Context) {
+    trap::send_vcpu_requests(state, u64::max_value(), REQUEST_FENCE_I, false);
+}
+
+fn sign_extend(value: u64, bits: u32) -> u64 {
+    ((value << (64 - bits)) as i64 >> (64 - bits)) as u64
+}
+
+/// Addresses of the instructions that could run after the one at `pc`.
+fn next_pcs(state: &Context, pc: u64) -> ArrayVec<[u64; 2]> {
+    let mut targets = ArrayVec::new();
+    let breakpoints = SHARED_STATICS.gdb.breakpoints.lock();
+    let half = |va: u64| Some(read_byte(state, &breakpoints[..], va)? as u32 | (read_byte(state, &breakpoints[..], va + 1)? as u32) << 8);
+    let low = match half(pc) {
+        Some(low) => low,
+        None => return targets,
+    };
+    let reg = |r: u32| state.saved_registers.get(r);
+
+    if low & 0x3 != 0x3 {
+        let insn = low as u64;
+        let (op, funct3, rs1, rs2) = (insn & 0x3, insn >> 13, (insn >> 7) as u32 & 0x1f, (insn >> 2) & 0x1f);
+        match (op, funct3) {
+            // c.j
+            (1, 5) => {
+                let imm = (insn >> 12 & 1) << 11 | (insn >> 11 & 1) << 4 | (insn >> 9 & 3) << 8 | (insn >> 8 & 1) << 10
+                    | (insn >> 7 & 1) << 6 | (insn >> 6 & 1) << 7 | (insn >> 3 & 7) << 1 | (insn >> 2 & 1) << 5;
+                targets.push(pc.wrapping_add(sign_extend(imm, 12)));
+            }
+            // c.beqz, c.bnez
+            (1, 6) | (1, 7) => {
+                let imm = (insn >> 12 & 1) << 8 | (insn >> 10 & 3) << 3 | (insn >> 5 & 3) << 6 | (insn >> 3 & 3) << 1
+                    | (insn >> 2 & 1) << 5;
+                targets.push(pc + 2);
+                targets.push(pc.wrapping_add(sign_extend(imm, 9)));
+            }
+            // c.jr, c.jalr
+            (2, 4) if rs1 != 0 && rs2 == 0 => targets.push(reg(rs1) & !1),
+            _ => targets.push(pc + 2),
+        }
+        return targets;
+    }
+
+    let insn = match half(pc + 2) {
+        Some(high) => (low | high << 16) as u64,
+        None => return targets,
+    };
+    let rs1 = (insn >> 15) as u32 & 0x1f;
+    match insn & 0x7f {
+        // jal
+        0x6f => {
+            let imm = (insn >> 31 & 1) << 20 | (insn >> 21 & 0x3ff) << 1 | (insn >> 20 & 1) << 11 | (insn >> 12 & 0xff) << 12;
+            targets.push(pc.wrapping_add(sign_extend(imm, 21)));
+        }
+        // jalr
+        0x67 => targets.push(reg(rs1).wrapping_add(sign_extend(insn >> 20, 12)) & !1),
+        // conditional branches
+        0x63 => {
+            let imm = (insn >> 31 & 1) << 12 | (insn >> 25 & 0x3f) << 5 | (insn >> 8 & 0xf) << 1 | (insn >> 7 & 1) << 11;
+            targets.push(pc + 4);
+            targets.push(pc.wrapping_add(sign_extend(imm, 13)));
+        }
+        _ => match insn {
+            // sret, emulated by the hypervisor
+            0x10200073 => targets.push(state.csrs.sepc),
+            // ecall, which the hypervisor handles itself in S-mode
+            0x00000073 => {
+                targets.push(pc + 4);
+                targets.push(state.csrs.stvec & TVEC_BASE);
+            }
+            _ => targets.push(pc + 4),
+        },
+    }
+    targets
+}
+
+fn read_register(state: &mut Context, reg: u64) -> Option<u64> {
+    match reg {
+        0..=31 => Some(state.saved_registers.get(reg as u32)),
+        REG_PC => Some(csrr!(sepc)),
+        r if r >= REG_FIRST_CSR && r < REG_FIRST_CSR + 0x1000 => state.get_csr((r - REG_FIRST_CSR) as u32),
+        _ => None,
+    }
+}
+
+fn write_register(state: &mut Context, reg: u64, value: u64) -> bool {
+    match reg {
+        0..=31 => state.saved_registers.set(reg as u32, value),
+        REG_PC => riscv::set_sepc(value),
+        r if r >= REG_FIRST_CSR && r < REG_FIRST_CSR + 0x1000 => return state.set_csr((r - REG_FIRST_CSR) as u32, value),
+        _ => return false,
+    }
+    true
+}
+
+fn hex_digit(ch: u8) -> Option<u8> {
+    (ch as char).to_digit(16).map(|d| d as u8)
+}
+
+fn parse_hex(s: &str) -> Option<u64> {
+    u64::from_str_radix(s, 16).ok()
+}
+
+/// Parse a register value, which GDB sends as target-endian bytes.
+fn parse_register(s: &str) -> Option<u64> {
+    if s.len() != 16 {
+        return None;
+    }
+    let mut bytes = [0u8; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(s.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(u64::from_le_bytes(bytes))
+}
+
+fn write_register_value(reply: &mut dyn Write, value: u64) {
+    for byte in value.to_le_bytes().iter() {
+        let _ = write!(reply, "{:02x}", byte);
+    }
+}
+
+/// Wait for the next byte from GDB, handling requests from other vCPUs in the meantime so that
+/// they aren't held up by the target being stopped.
+fn wait_byte(state: &mut Context) -> u8 {
+    loop {
+        if let Some(ch) = SHARED_STATICS.uart_writer.lock().getchar() {
+            return ch;
+        }
+        trap::handle_vcpu_requests(state);
+    }
+}
+
+/// Read the next packet with a valid checksum into `buffer`, and acknowledge it.
+fn receive_packet(state: &mut Context, buffer: &mut ArrayVec<[u8; PACKET_SIZE]>) {
+    loop {
+        while wait_byte(state) != b'$' {}
+
+        buffer.clear();
+        let mut checksum = 0u8;
+        let mut overflow = false;
+        loop {
+            let ch = wait_byte(state);
+            if ch == b'#' {
+                break;
+            }
+            checksum = checksum.wrapping_add(ch);
+            overflow |= buffer.try_push(ch).is_err();
+        }
+        let high = hex_digit(wait_byte(state));
+        let low = hex_digit(wait_byte(state));
+
+        let ok = !overflow && high.and_then(|h| low.map(|l| h << 4 | l)) == Some(checksum);
+        SHARED_STATICS.uart_writer.lock().putchar(if ok { b'+' } else { b'-' });
+        if ok {
+            return;
+        }
+    }
+}
+
+fn send_packet(data: &str) {
+    let checksum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+    let mut writer = SHARED_STATICS.uart_writer.lock();
+    let _ = write!(writer, "${}#{:02x}", data, checksum);
+}
+
+/// Put the console back in the hands of the guests, removing every breakpoint.
+fn detach(state: &mut Context) {
+    remove_breakpoints(state, |_| true);
+    sync_instructions(state);
+    SHARED_STATICS.gdb.attached.store(false, Ordering::SeqCst);
+    SHARED_STATICS.uart_writer.lock().resume_guests();
+}
+
+/// Keep the target stopped, serving requests from GDB, until it is told to continue, step or
+/// detach. If `report` is set, GDB is waiting to be told that the target stopped with `signal`.
+fn stop(state: &mut Context, signal: u8, report: bool) {
+    remove_breakpoints(state, |b| b.temporary);
+    sync_instructions(state);
+
+    let mut reply = ArrayString::<[u8; 2048]>::new();
+    if report {
+        let _ = write!(reply, "S{:02x}", signal);
+        send_packet(&reply);
+    }
+
+    let mut buffer = ArrayVec::new();
+    loop {
+        receive_packet(state, &mut buffer);
+        let packet = core::str::from_utf8(&buffer).unwrap_or("");
+        let command = packet.get(..1).unwrap_or("");
+        let args = packet.get(1..).unwrap_or("");
+        reply.clear();
+
+        let _ = match command {
+            "?" => write!(reply, "S{:02x}", signal),
+            "g" => {
+                for reg in 0..=REG_PC {
+                    write_register_value(&mut reply, read_register(state, reg).unwrap());
+                }
+                Ok(())
+            }
+            "G" => {
+                for reg in 0..=REG_PC {
+                    let start = reg as usize * 16;
+                    match args.get(start..start + 16).and_then(parse_register) {
+                        Some(value) => { write_register(state, reg, value); }
+                        None => break,
+                    }
+                }
+                write!(reply, "OK")
+            }
+            "p" => match parse_hex(args).map(|reg| (reg, read_register(state, reg))) {
+                Some((_, Some(value))) => {
+                    write_register_value(&mut reply, value);
+                    Ok(())
+                }
+                // Floating point registers live in hardware and aren't made available.
+                Some((reg, None)) if reg >= REG_FIRST_FP => write!(reply, "xxxxxxxxxxxxxxxx"),
+                _ => write!(reply, "E01"),
+            },
+            "P" => {
+                let mut parts = args.splitn(2, '=');
+                let reg = parts.next().and_then(parse_hex);
+                let value = parts.next().and_then(parse_register);
+                match (reg, value) {
+                    (Some(reg), Some(value)) if write_register(state, reg, value) => write!(reply, "OK"),
+                    _ => write!(reply, "E01"),
+                }
+            }
+            "m" => {
+                let mut parts = args.splitn(2, ',');
+                let addr = parts.next().and_then(parse_hex);
+                let len = parts.next().and_then(parse_hex);
+                if let (Some(addr), Some(len)) = (addr, len) {
+                    let breakpoints = SHARED_STATICS.gdb.breakpoints.lock();
+                    for va in addr..addr.saturating_add(len.min(MAX_MEMORY_READ)) {
+                        match read_byte(state, &breakpoints[..], va) {
+                            Some(byte) => { let _ = write!(reply, "{:02x}", byte); }
+                            None => break,
+                        }
+                    }
+                }
+                // A short reply means that only part of the range could be read.
+                if reply.is_empty() { write!(reply, "E14") } else { Ok(()) }
+            }
+            "M" => {
+                let mut parts = args.splitn(2, ':');
+                let mut header = parts.next().unwrap_or("").splitn(2, ',');
+                let addr = header.next().and_then(parse_hex);
+                let data = parts.next().unwrap_or("");
+                let mut written = addr.is_some() && data.len() % 2 == 0;
+                if let Some(addr) = addr {
+                    let mut breakpoints = SHARED_STATICS.gdb.breakpoints.lock();
+                    for i in 0..data.len() / 2 {
+                        let byte = data.get(2 * i..2 * i + 2).and_then(|b| u8::from_str_radix(b, 16).ok());
+                        written = written && match byte {
+                            Some(byte) => write_byte(state, &mut breakpoints[..], addr + i as u64, byte),
+                            None => false,
+                        };
+                    }
+                }
+                // The guest may be about to run what was written.
+                sync_instructions(state);
+                if written { write!(reply, "OK") } else { write!(reply, "E14") }
+            }
+            "Z" | "z" => {
+                let mut parts = args.splitn(3, ',');
+                let kind = parts.next();
+                let addr = parts.next().and_then(parse_hex);
+                match (kind, addr) {
+                    (Some("0"), Some(addr)) => {
+                        let done = if command == "Z" {
+                            insert_breakpoint(state, addr, false)
+                        } else {
+                            remove_breakpoints(state, |b| b.va == addr && !b.temporary);
+                            true
+                        };
+                        sync_instructions(state);
+                        if done { write!(reply, "OK") } else { write!(reply, "E14") }
+                    }
+                    // Hardware breakpoints and watchpoints aren't supported.
+                    _ => Ok(()),
+                }
+            }
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    riscv::set_sepc(addr);
+                }
+                if command == "s" {
+                    for pc in next_pcs(state, csrr!(sepc)) {
+                        insert_breakpoint(state, pc, true);
+                    }
+                    sync_instructions(state);
+                }
+                return;
+            }
+            "D" => {
+                send_packet("OK");
+                detach(state);
+                return;
+            }
+            "k" => {
+                detach(state);
+                return;
+            }
+            "H" => write!(reply, "OK"),
+            "q" if args.starts_with("Supported") => write!(reply, "PacketSize={:x}", PACKET_SIZE),
+            "q" if args == "Attached" => write!(reply, "1"),
+            // Anything else is unsupported, which GDB is told with an empty reply.
+            _ => Ok(()),
+        };
+        send_packet(&reply);
+    }
+}
@@ -100,17 +100,25 @@ pub mod riscv;
 pub mod print;
 
 pub mod backtrace;
+pub mod console_log;
 pub mod constants;
 pub mod context;
 pub mod drivers;
 pub mod elf;
 pub mod fdt;
+pub mod gdbstub;
+pub mod management;
 pub mod memory_region;
+pub mod monitor;
 pub mod pfault;
 pub mod plic;
 pub mod pmap;
+pub mod sbi;
+pub mod scheduler;
+pub mod shmem;
 pub mod statics;
 pub mod sum;
+pub mod trace;
 pub mod trap;
 pub mod virtio;
 
@@ -119,4 +127,4 @@ pub use constants::SYMBOL_PA2VA_OFFSET;
 pub use fdt::*;
 pub use riscv::bits::*;
 pub use pmap::{pa2va};
-pub use statics::{__SHARED_STATICS_IMPL, IpiReason, SHARED_STATICS};
+pub use statics::{__SHARED_STATICS_IMPL, IpiReason, SHARED_STATICS, VcpuAssignment};
@@ -0,0 +1,175 @@
+//! Management interface through which a privileged control guest can inspect and manage the others.
+//! The control guest reaches it by opening a vsock stream connection to the hypervisor (see
+//! `drivers::vsock`) and sending commands one per line. Each command gets one or more lines back:
+//!
+//! ```text
+//!  list              one line per guest: its id, whether it is paused, and its number of vCPUs
+//!  stats <guest>     number of traps taken out of the guest, by cause
+//!  pause <guest>     stop scheduling the guest's vCPUs, leaving their state untouched
+//!  resume <guest>    undo `pause`
+//!  reboot <guest>    reload the guest's kernel and start it again, as an SBI reboot would
+//! ```
+//!
+//! Commands run while the control guest's lock is held, so nothing here may take the lock of any
+//! guest. Instead, the state that commands need is kept in a `GuestControl` per guest that the
+//! guest's own vCPUs act upon.
+
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::constants::MAX_GUESTS;
+use crate::riscv::bits::*;
+use crate::statics::SHARED_STATICS;
+use crate::riscv;
+
+const EXIT_TIMER: usize = 0;
+const EXIT_SOFTWARE: usize = 1;
+const EXIT_EXTERNAL: usize = 2;
+const EXIT_PAGE_FAULT: usize = 3;
+const EXIT_ILLEGAL_INSN: usize = 4;
+const EXIT_ENV_CALL: usize = 5;
+const EXIT_OTHER: usize = 6;
+const EXIT_KINDS: usize = 7;
+const EXIT_NAMES: [&str; EXIT_KINDS] = ["timer", "software", "external", "page_fault", "illegal_insn", "ecall", "other"];
+
+/// State of a guest that the management interface reads and changes. Everything in it is atomic so
+/// that it can be used without taking the guest's lock.
+pub struct GuestControl {
+    /// Number of vCPUs, or zero if the guest doesn't exist.
+    vcpus: AtomicU64,
+    /// Mask of the host harts that the guest's vCPUs are assigned to.
+    harts: AtomicU64,
+    /// While set, none of the guest's vCPUs are scheduled.
+    paused: AtomicBool,
+    /// Set by the `reboot` command until one of the guest's vCPUs carries it out.
+    reboot_requested: AtomicBool,
+    /// Number of traps taken out of the guest, indexed by EXIT_*.
+    exits: [AtomicU64; EXIT_KINDS],
+}
+
+impl GuestControl {
+    pub const fn new() -> Self {
+        Self {
+            vcpus: AtomicU64::new(0),
+            harts: AtomicU64::new(0),
+            paused: AtomicBool::new(false),
+            reboot_requested: AtomicBool::new(false),
+            exits: [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0),
+                    AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)],
+        }
+    }
+
+    /// Record that the guest exists and has `vcpus` vCPUs.
+    pub fn set_vcpus(&self, vcpus: u64) {
+        self.vcpus.store(vcpus, Ordering::SeqCst);
+    }
+
+    /// Record that one of the guest's vCPUs runs on host hart `hartid`.
+    pub fn add_hart(&self, hartid: u64) {
+        self.harts.fetch_or(1 << hartid, Ordering::SeqCst);
+    }
+
+    pub fn vcpus(&self) -> u64 {
+        self.vcpus.load(Ordering::SeqCst)
+    }
+
+    pub fn paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Returns whether a reboot has been requested, so that the caller can carry it out.
+    pub fn take_reboot_request(&self) -> bool {
+        self.reboot_requested.load(Ordering::Relaxed) && self.reboot_requested.swap(false, Ordering::SeqCst)
+    }
+
+    /// Whether the guest's vCPUs should be scheduled just so one of them can carry out a reboot,
+    /// even though they are stopped.
+    pub fn reboot_requested(&self) -> bool {
+        self.reboot_requested.load(Ordering::SeqCst)
+    }
+
+    /// Count a trap with the given `scause` out of the guest.
+    pub fn count_exit(&self, cause: u64) {
+        let kind = if (cause as isize) < 0 {
+            match cause & 0xff {
+                0x1 => EXIT_SOFTWARE,
+                0x5 => EXIT_TIMER,
+                0x9 => EXIT_EXTERNAL,
+                _ => EXIT_OTHER,
+            }
+        } else {
+            match cause {
+                SCAUSE_INSN_PAGE_FAULT | SCAUSE_LOAD_PAGE_FAULT | SCAUSE_STORE_PAGE_FAULT => EXIT_PAGE_FAULT,
+                SCAUSE_ILLEGAL_INSN => EXIT_ILLEGAL_INSN,
+                SCAUSE_ENV_CALL => EXIT_ENV_CALL,
+                _ => EXIT_OTHER,
+            }
+        };
+        self.exits[kind].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Interrupt every hart the guest's vCPUs run on, so that they notice a change in scheduling.
+    pub fn kick(&self) {
+        let harts = self.harts.load(Ordering::SeqCst);
+        for hartid in 0..64 {
+            if harts & (1 << hartid) != 0 {
+                riscv::sbi::send_ipi_to_hart(hartid);
+            }
+        }
+    }
+}
+
+/// Carry out the command on `line`, sent by the control guest in slot `caller`, and append the reply
+/// to `output`. Replies that don't fit are cut short.
+pub fn execute(caller: usize, line: &str, output: &mut dyn Write) {
+    let mut words = line.split_whitespace();
+    let command = match words.next() {
+        Some(command) => command,
+        None => return,
+    };
+    let target = words.next()
+        .and_then(|id| id.parse::<usize>().ok())
+        .filter(|&id| id >= 1 && id <= MAX_GUESTS)
+        .map(|id| (id - 1, SHARED_STATICS.guest_control(id - 1)))
+        .filter(|(_, control)| control.vcpus.load(Ordering::SeqCst) != 0);
+
+    let _ = match (command, target) {
+        ("list", _) => {
+            for i in 0..MAX_GUESTS {
+                let control = SHARED_STATICS.guest_control(i);
+                let vcpus = control.vcpus.load(Ordering::SeqCst);
+                if vcpus != 0 {
+                    let state = if control.paused() { "paused" } else { "running" };
+                    let _ = writeln!(output, "{} {} vcpus={}", i + 1, state, vcpus);
+                }
+            }
+            Ok(())
+        }
+        ("stats", Some((_, control))) => {
+            for (name, count) in EXIT_NAMES.iter().zip(control.exits.iter()) {
+                let _ = write!(output, "{}={} ", name, count.load(Ordering::Relaxed));
+            }
+            writeln!(output)
+        }
+        // Nothing could resume the control guest once it was paused.
+        ("pause", Some((index, _))) if index == caller => writeln!(output, "error: can't pause the control guest"),
+        ("pause", Some((_, control))) => {
+            control.paused.store(true, Ordering::SeqCst);
+            control.kick();
+            writeln!(output, "ok")
+        }
+        ("resume", Some((_, control))) => {
+            control.paused.store(false, Ordering::SeqCst);
+            control.kick();
+            writeln!(output, "ok")
+        }
+        // A paused guest is resumed by rebooting it, since otherwise nothing would carry out the reboot.
+        ("reboot", Some((_, control))) => {
+            control.reboot_requested.store(true, Ordering::SeqCst);
+            control.paused.store(false, Ordering::SeqCst);
+            control.kick();
+            writeln!(output, "ok")
+        }
+        ("stats", None) | ("pause", None) | ("resume", None) | ("reboot", None) => writeln!(output, "error: no such guest"),
+        _ => writeln!(output, "error: unknown command"),
+    };
+}
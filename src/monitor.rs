@@ -0,0 +1,244 @@
+//! Monitor for interacting with the hypervisor at runtime, in the style of QEMU's. Pressing Ctrl-A c
+//! on the host console switches the console from the guests to the monitor, and pressing it again
+//! (or entering `quit`) switches back. Commands are entered one per line:
+//!
+//! ```text
+//!  list                   one line per guest: its id, whether it is paused, and its number of vCPUs
+//!  stats <guest>          number of traps taken out of the guest, by cause
+//!  regs <guest> [vcpu]    registers and CSRs of a vCPU
+//!  pt <guest> [vcpu]      the guest page table that a vCPU is using
+//!  shadow <guest> [vcpu]  the shadow page table that a vCPU is running on
+//!  log <guest>            the guest's recent console output, with the mtime of each line
+//!  trace                  have each hart print its trace of recent traps (see `trace`)
+//!  pmp                    PMP entries of the hart running the monitor
+//!  pause <guest>          stop scheduling the guest's vCPUs
+//!  resume <guest>         undo `pause`
+//!  reset <guest>          reload the guest's kernel and start it again
+//!  quit                   go back to the guests
+//! ```
+//!
+//! Commands are run with the console lock held, and possibly the lock of whichever guest happened
+//! to poll for input, so they can't print through `println!` or look at guests directly. The ones
+//! that manage guests go through `management`. The ones that inspect a vCPU are instead handed to
+//! the hart running it, which answers the next time it traps and prints the answer itself.
+//!
+//! Only M-mode can read the PMP registers, so `pmp` asks for them through an SBI call that RVirt's
+//! own M-mode code answers. When RVirt was booted by other firmware, the call fails and no PMP
+//! state can be shown.
+
+use arrayvec::ArrayString;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use crate::constants::MAX_GUESTS;
+use crate::context::Context;
+use crate::riscv::bits::*;
+use crate::scheduler::RUN_QUEUE;
+use crate::statics::SHARED_STATICS;
+use crate::{console_log, management, pmap, riscv, trace};
+
+pub const PROMPT: &str = "(rvirt) ";
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+enum Query {
+    Registers,
+    GuestPageTable,
+    ShadowPageTable,
+}
+
+/// A question about a vCPU, waiting for the hart that runs it to answer.
+pub struct VcpuQuery {
+    pending: AtomicBool,
+    /// Slot of the guest, index of the vCPU within it, and what to show.
+    query: Mutex<Option<(usize, usize, Query)>>,
+}
+
+impl VcpuQuery {
+    pub const fn new() -> Self {
+        Self {
+            pending: AtomicBool::new(false),
+            query: Mutex::new(None),
+        }
+    }
+
+    /// Take the query if it is about `context`.
+    fn take(&self, context: &Context) -> Option<Query> {
+        let mut query = self.query.lock();
+        match *query {
+            Some((guest, vcpu, q)) if guest == context.guest_index && vcpu == context.vcpu => {
+                *query = None;
+                self.pending.store(false, Ordering::SeqCst);
+                Some(q)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Carry out the command on `line`, writing its output to `output`. Returns false once the user
+/// asks to leave the monitor.
+pub fn execute(line: &str, output: &mut dyn Write) -> bool {
+    let mut words = line.split_whitespace();
+    let command = match words.next() {
+        Some(command) => command,
+        None => return true,
+    };
+    let guest = words.next();
+    let vcpu = words.next().map(|v| v.parse::<usize>().ok());
+
+    let query = match command {
+        "regs" => Query::Registers,
+        "pt" => Query::GuestPageTable,
+        "shadow" => Query::ShadowPageTable,
+        "log" => {
+            match guest.and_then(|id| id.parse::<usize>().ok()).filter(|&id| id >= 1 && id <= MAX_GUESTS) {
+                Some(guest) => console_log::write_log(guest - 1, output),
+                None => { let _ = writeln!(output, "error: no such guest"); }
+            }
+            return true;
+        }
+        "trace" => {
+            if trace::ENABLED {
+                // Every hart prints its own trace as it next traps, so make sure they all do.
+                trace::request_dump();
+                for guest in 0..MAX_GUESTS {
+                    SHARED_STATICS.guest_control(guest).kick();
+                }
+            } else {
+                let _ = writeln!(output, "error: built without the trace feature");
+            }
+            return true;
+        }
+        "pmp" => {
+            write_pmp(output);
+            return true;
+        }
+        "quit" | "exit" => return false,
+        "help" => {
+            let _ = writeln!(output, "commands: list, stats <guest>, regs <guest> [vcpu], pt <guest> [vcpu], \
+                                      shadow <guest> [vcpu], log <guest>, trace, pmp, pause <guest>, resume <guest>, \
+                                      reset <guest>, quit");
+            return true;
+        }
+        _ => {
+            // The monitor acts on behalf of no guest, so unlike the control guest it may pause any.
+            let mut translated = ArrayString::<[u8; 64]>::new();
+            let command = if command == "reset" { "reboot" } else { command };
+            let _ = write!(translated, "{} {}", command, guest.unwrap_or(""));
+            management::execute(MAX_GUESTS, &translated, output);
+            return true;
+        }
+    };
+
+    let guest = guest.and_then(|id| id.parse::<usize>().ok()).filter(|&id| id >= 1 && id <= MAX_GUESTS);
+    let (guest, vcpu) = match (guest, vcpu.unwrap_or(Some(0))) {
+        (Some(guest), Some(vcpu)) if (vcpu as u64) < SHARED_STATICS.guest_control(guest - 1).vcpus() => (guest - 1, vcpu),
+        _ => {
+            let _ = writeln!(output, "error: no such vcpu");
+            return true;
+        }
+    };
+
+    let monitor_query = &SHARED_STATICS.monitor_query;
+    *monitor_query.query.lock() = Some((guest, vcpu, query));
+    monitor_query.pending.store(true, Ordering::SeqCst);
+    SHARED_STATICS.guest_control(guest).kick();
+    true
+}
+
+/// Print the enabled PMP entries of this hart, with the range of physical addresses each covers.
+fn write_pmp(output: &mut dyn Write) {
+    let mut registers = [0; 18];
+    if !riscv::sbi::read_pmp(&mut registers) {
+        let _ = writeln!(output, "error: PMP registers are only available when RVirt provides its own M-mode code");
+        return;
+    }
+
+    let mut enabled = false;
+    for entry in 0..16 {
+        let config = (registers[entry / 8] >> (entry % 8 * 8)) as u8;
+        let address = registers[2 + entry];
+        let (mode, start, end) = match (config >> 3) & 3 {
+            0 => continue,
+            1 => ("TOR", if entry == 0 { 0 } else { registers[1 + entry] << 2 }, (address << 2).wrapping_sub(1)),
+            2 => ("NA4", address << 2, (address << 2) + 3),
+            _ if (!address).trailing_zeros() >= 61 => ("NAPOT", 0, !0),
+            _ => {
+                let size = 8u64 << (!address).trailing_zeros();
+                let start = (address << 2) & !(size - 1);
+                ("NAPOT", start, start + (size - 1))
+            }
+        };
+        enabled = true;
+        let _ = writeln!(output, "pmp{:<2} {}{}{}{} {:<5} {:#018x}-{:#018x}", entry,
+                         if config & 0x1 != 0 { 'r' } else { '-' },
+                         if config & 0x2 != 0 { 'w' } else { '-' },
+                         if config & 0x4 != 0 { 'x' } else { '-' },
+                         if config & 0x80 != 0 { 'l' } else { '-' },
+                         mode, start, end);
+    }
+    if !enabled {
+        let _ = writeln!(output, "no PMP entries enabled");
+    }
+}
+
+/// Called on every trap, including while the vCPUs on this hart are paused or stopped. Answers the
+/// pending query if it is about `state` or one of the vCPUs switched out on this hart.
+pub fn poll(state: &mut Context) {
+    let monitor_query = &SHARED_STATICS.monitor_query;
+    if !monitor_query.pending.load(Ordering::SeqCst) {
+        return;
+    }
+
+    if let Some(query) = monitor_query.take(state) {
+        answer(state, true, query);
+        return;
+    }
+    for context in RUN_QUEUE.lock().as_mut().unwrap().contexts.iter_mut() {
+        if let Some(query) = monitor_query.take(context) {
+            answer(context, false, query);
+            return;
+        }
+    }
+}
+
+/// Print the answer to `query`. Registers of the running context are live on the trap stack and
+/// in `sepc`, while those of switched out contexts were copied into `switched_out`.
+fn answer(context: &mut Context, running: bool, query: Query) {
+    println!("guest {} vcpu {}:", context.guest_index + 1, context.vcpu);
+    match query {
+        Query::Registers => {
+            let mut registers = context.switched_out.registers;
+            let mut pc = context.switched_out.pc;
+            if running {
+                for i in 1..32 {
+                    registers[i] = context.saved_registers.get(i as u32);
+                }
+                pc = csrr!(sepc);
+            }
+
+            for i in (0..32).step_by(4) {
+                println!("x{:<2} {:#018x}  x{:<2} {:#018x}  x{:<2} {:#018x}  x{:<2} {:#018x}",
+                         i, registers[i], i + 1, registers[i + 1], i + 2, registers[i + 2], i + 3, registers[i + 3]);
+            }
+            let csrs = &context.csrs;
+            println!("pc       {:#018x}  mode     {}", pc, if context.smode { "S" } else { "U" });
+            println!("sstatus  {:#018x}  sie      {:#018x}  sip      {:#018x}", csrs.sstatus, csrs.sie, csrs.sip);
+            println!("stvec    {:#018x}  sscratch {:#018x}  sepc     {:#018x}", csrs.stvec, csrs.sscratch, csrs.sepc);
+            println!("scause   {:#018x}  stval    {:#018x}  satp     {:#018x}", csrs.scause, csrs.stval, csrs.satp);
+            println!("mtimecmp {:#018x}", csrs.mtimecmp);
+        }
+        Query::GuestPageTable => {
+            if context.csrs.satp & SATP_MODE == 0 {
+                println!("paging disabled");
+            } else {
+                pmap::print_guest_page_table(&context.guest_memory, (context.csrs.satp & SATP_PPN) << 12, 2, 0);
+            }
+        }
+        Query::ShadowPageTable => {
+            let root = context.shadow();
+            println!("root {:?} at {:#x}", root, context.shadow_page_tables.root_pa(root));
+            context.shadow_page_tables.print(root);
+        }
+    }
+}
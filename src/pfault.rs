@@ -1,6 +1,7 @@
 use crate::context::Context;
 use crate::riscv::bits::SATP_PPN;
-use crate::{pmap::*, riscv, virtio};
+use crate::statics::SHARED_STATICS;
+use crate::{pmap::*, riscv, shmem, virtio};
 use riscv_decode::Instruction;
 
 /// Perform any handling required in response to a guest page fault. Returns true if the fault could
@@ -37,8 +38,14 @@ pub fn handle_page_fault(state: &mut Context, cause: u64, instruction: Option<u3
             _ => unreachable!(),
         }
 
-        if state.guest_memory.in_region(translation.guest_pa) {
-            let host_pa = translation.guest_pa + state.guest_shift;
+        // Pages of the window shared between guests are mapped the same way as guest memory.
+        let host_pa = if state.guest_memory.in_region(translation.guest_pa) {
+            Some(translation.guest_pa + state.guest_shift)
+        } else {
+            SHARED_STATICS.shmem.host_address(translation.guest_pa)
+        };
+
+        if let Some(host_pa) = host_pa {
 
             // Set A and D bits
             let new_pte = if (translation.pte_value & PTE_DIRTY) == 0 && access == PTE_WRITE {
@@ -92,9 +99,17 @@ pub fn handle_page_fault(state: &mut Context, cause: u64, instruction: Option<u3
             }
 
             return true;
-        } else if access != PTE_EXECUTE && state.smode {
+        } else if access != PTE_EXECUTE {
             let pa = (translation.guest_pa & !0xfff) | (guest_va & 0xfff);
             if let Some(instruction) = instruction {
+                // Unlike the other devices, the doorbell registers may be mapped into user space.
+                if shmem::is_register_access(state, pa) {
+                    return shmem::handle_register_access(state, cause, pa, instruction);
+                }
+                if !state.smode {
+                    return false;
+                }
+
                 if is_uart_access(pa) {
                     return handle_uart_access(state, pa, instruction);
                 }
@@ -120,12 +135,12 @@ fn is_uart_access(guest_pa: u64) -> bool {
 fn handle_uart_access(state: &mut Context, guest_pa: u64, instruction: u32) -> bool {
     match riscv_decode::decode(instruction).ok() {
         Some(Instruction::Lb(i)) => {
-            let value = state.uart.read(&state.host_clint, guest_pa) as u64;
+            let value = state.guest.lock().as_mut().unwrap().uart.read(&state.host_clint, guest_pa) as u64;
             state.saved_registers.set(i.rd(), value);
         }
         Some(Instruction::Sb(i)) => {
             let value = (state.saved_registers.get(i.rs2()) & 0xff) as u8;
-            state.uart.write(&state.host_clint, guest_pa, value);
+            state.guest.lock().as_mut().unwrap().uart.write(&state.host_clint, guest_pa, value);
         }
         Some(instr) => {
             println!("UART: Instruction {:?} used to target addr {:#x} from pc {:#x}", instr, guest_pa, csrr!(sepc));
@@ -144,7 +159,7 @@ fn is_plic_access(guest_pa: u64) -> bool {
 fn handle_plic_access(state: &mut Context, guest_pa: u64, instruction: u32) -> bool {
     match riscv_decode::decode(instruction).ok() {
         Some(Instruction::Lw(i)) => {
-            let value = state.plic.read_u32(guest_pa) as i32 as i64 as u64;
+            let value = state.guest.lock().as_mut().unwrap().plic.read_u32(guest_pa) as i32 as i64 as u64;
             // println!("PLIC: Read value {:#x} at address {:#x}", value, guest_pa);
             state.saved_registers.set(i.rd(), value)
         }
@@ -152,8 +167,13 @@ fn handle_plic_access(state: &mut Context, guest_pa: u64, instruction: u32) -> b
             let value = state.saved_registers.get(i.rs2()) as u32;
             // println!("PLIC: Writing {:#x} to address {:#x}", value, guest_pa);
 
+            let guest = state.guest;
+            let mut guest = guest.lock();
+            let guest = guest.as_mut().unwrap();
+
             let mut clear_seip = false;
-            state.plic.write_u32(guest_pa, value, &mut clear_seip);
+            guest.plic.write_u32(guest_pa, value, &mut clear_seip);
+            guest.notify_external_interrupts(state.vcpu);
             if clear_seip {
                 state.csrs.sip &= !0x200;
             }
@@ -5,6 +5,11 @@ use crate::constants::MAX_GUEST_HARTS;
 /// have one M-mode context and one S-mode context.
 const MAX_CONTEXTS: usize = MAX_GUEST_HARTS * 2;
 
+/// Index of the PLIC context used for supervisor external interrupts on the given hart.
+pub fn supervisor_context(hart: usize) -> usize {
+    2 * hart + 1
+}
+
 pub struct PlicState {
     base: u64,
     source_priority: [u32; 512],
@@ -55,7 +60,7 @@ impl PlicState {
                         }
 
                         for j in 0..32 {
-                            if self.pending[i] & (1 << j) != 0 {
+                            if self.pending[i] & self.enable[hart][i] & (1 << j) != 0 {
                                 let interrupt = i*32 + j;
                                 if self.source_priority[interrupt] > max_priority {
                                     max_priority = self.source_priority[interrupt];
@@ -114,17 +119,16 @@ impl PlicState {
         }
     }
 
-    pub fn interrupt_pending(&self) -> bool {
-        const CONTEXT: usize = 1; // TODO: shouldn't be a constant
-
-        let threshold = self.thresholds[CONTEXT];
+    /// Returns whether an interrupt enabled for `context` is pending above that context's threshold.
+    pub fn interrupt_pending(&self, context: usize) -> bool {
+        let threshold = self.thresholds[context];
         for i in 0..self.pending.len() {
-            if self.pending[i] == 0 {
+            if self.pending[i] & self.enable[context][i] == 0 {
                 continue;
             }
 
             for j in 0..32 {
-                if self.pending[i] & (1 << j) != 0 {
+                if self.pending[i] & self.enable[context][i] & (1 << j) != 0 {
                     if self.source_priority[i*32 + j] > threshold {
                         return true;
                     }
@@ -2,7 +2,7 @@ use crate::fdt::MachineMeta;
 use crate::context::Context;
 use crate::constants::SYMBOL_PA2VA_OFFSET;
 use crate::memory_region::{MemoryRegion, PageTableRegion};
-use crate::riscv;
+use crate::{riscv, trace};
 use arr_macro::arr;
 use arrayvec::ArrayVec;
 use core::ptr;
@@ -23,6 +23,14 @@ mod segment_layout {
     pub const PT_REGION_OFFSET: u64 = HEAP_OFFSET + HEAP_SIZE;
     pub const PT_REGION_SIZE: u64 = 32 << 20;
     pub const VM_RESERVATION_SIZE: u64 = PT_REGION_OFFSET + PT_REGION_SIZE; // 64MB
+    /// Buffers that host devices driven by the hypervisor access by physical address. They sit at
+    /// the very end of a guest's segment, just past guest memory. The Ethernet controller uses the
+    /// first quarter, the rings of passthrough virtio devices the second, and in the first guest's
+    /// segment, a disk shared between guests uses the second half.
+    pub const DMA_SIZE: u64 = 2 << 20;
+    /// When guests write to a copy-on-write overlay of the shared disk, each overlay takes this
+    /// much from the end of the guest's memory, just before the DMA region.
+    pub const OVERLAY_SIZE: u64 = 128 << 20;
 }
 pub use segment_layout::*;
 
@@ -158,6 +166,11 @@ impl PageTables {
         self.root_page_tables[i]
     }
 
+    /// Print the entries of the page table rooted at `root`.
+    pub fn print(&self, root: PageTableRoot) {
+        print_page_table(&self.region, self.root_pa(root), 2);
+    }
+
     pub fn install_root(&self, root: PageTableRoot) {
         let new_satp = (8 << 60) | (self.root_pa(root) >> 12);
         if csrr!(satp) != new_satp {
@@ -334,12 +347,17 @@ pub fn translate_host_address(addr: u64) -> Option<PageTableWalk> {
     walk_page_table(root_page_table, addr, |pa| Some(unsafe { *(pa2va(pa) as *const u64) }))
 }
 
-pub unsafe fn init(hart_base_pa: u64, shared_segments_shift: u64, machine: &MachineMeta) -> (PageTables, MemoryRegion, u64) {
-    assert_eq!(hart_base_pa % HART_SEGMENT_SIZE, 0);
+/// Set up the shadow page tables and guest memory for a vCPU. The hypervisor data and stack are
+/// mapped from `host_base_pa`, which is shared by all vCPUs scheduled on the current hart.
+pub unsafe fn init(host_base_pa: u64, hart_base_pa: u64, guest_memory_pa: u64, guest_memory_size: u64,
+                   shared_segments_shift: u64, machine: &MachineMeta) -> (PageTables, MemoryRegion, u64) {
+    assert_eq!(host_base_pa % VM_RESERVATION_SIZE, 0);
+    assert_eq!(hart_base_pa % VM_RESERVATION_SIZE, 0);
+    assert_eq!(guest_memory_pa % HPAGE_SIZE, 0);
 
     let gpm_offset = machine.physical_memory_offset;
-    let gpm_size = HART_SEGMENT_SIZE.checked_sub(VM_RESERVATION_SIZE).unwrap();
-    let guest_shift = VM_RESERVATION_SIZE + hart_base_pa.checked_sub(machine.physical_memory_offset).unwrap();
+    let gpm_size = guest_memory_size;
+    let guest_shift = guest_memory_pa.checked_sub(machine.physical_memory_offset).unwrap();
     assert_eq!(gpm_offset, 0x80000000);
     assert!(gpm_size > 64 * 1024 * 1024);
 
@@ -357,9 +375,10 @@ pub unsafe fn init(hart_base_pa: u64, shared_segments_shift: u64, machine: &Mach
         let va = pa2va(shadow_page_tables.root_pa(root));
         ptr::write_bytes(va as *mut u8, 0, PAGE_SIZE as usize);
 
-        *((va + DIRECT_MAP_PT_INDEX + 0 * 8) as *mut u64) = (0 << 28) | PTE_AD | PTE_RWV;
-        *((va + DIRECT_MAP_PT_INDEX + 1 * 8) as *mut u64) = (1 << 28) | PTE_AD | PTE_RWV;
-        *((va + DIRECT_MAP_PT_INDEX + (hart_base_pa >> 30) * 8) as *mut u64) = (hart_base_pa >> 2) | PTE_AD | PTE_RWV;
+        // The whole direct map is needed since a hart may touch the memory of any guest it runs.
+        for i in 0..DIRECT_MAP_PAGES {
+            *((va + DIRECT_MAP_PT_INDEX + i * 8) as *mut u64) = (i << 28) | PTE_AD | PTE_RWV;
+        }
 
         // Hypervisor code + data
         let hp = 2 << 18;
@@ -370,9 +389,9 @@ pub unsafe fn init(hart_base_pa: u64, shared_segments_shift: u64, machine: &Mach
         shadow_page_tables.region.set_pte_unchecked(
             page+8, (0x20000000+sshift+hp) | PTE_AD | PTE_RWV);  // Shared data
         shadow_page_tables.region.set_pte_unchecked(
-            page+16, ((hart_base_pa>>2)) | PTE_AD | PTE_RWV);    // Data
+            page+16, ((host_base_pa>>2)) | PTE_AD | PTE_RWV);    // Data
         shadow_page_tables.region.set_pte_unchecked(
-            page+32, ((hart_base_pa>>2)+hp) | PTE_AD | PTE_RWV); // Stack
+            page+32, ((host_base_pa>>2)+hp) | PTE_AD | PTE_RWV); // Stack
     }
     shadow_page_tables.install_root(MPA);
 
@@ -402,7 +421,6 @@ pub unsafe fn init(hart_base_pa: u64, shared_segments_shift: u64, machine: &Mach
     (shadow_page_tables, guest_memory, guest_shift)
 }
 
-#[allow(unused)]
 pub fn print_page_table(page_table_region: &PageTableRegion, pt: u64, level: u8) {
     for i in 0..512 {
         let pte = page_table_region[pt + i*8];
@@ -419,7 +437,6 @@ pub fn print_page_table(page_table_region: &PageTableRegion, pt: u64, level: u8)
     }
 }
 
-#[allow(unused)]
 pub fn print_guest_page_table(guest_memory: &MemoryRegion, pt: u64, level: u8, base: u64) {
     if !guest_memory.in_region(pt) {
         println!("[SATP Invalid]");
@@ -463,28 +480,53 @@ pub fn flush_shadow_page_table(shadow_page_tables: &mut PageTables) {
     riscv::sfence_vma();
 }
 
+/// Remove any shadow mappings for the guest virtual address `va`.
+fn flush_shadow_page_table_address(shadow_page_tables: &mut PageTables, va: u64) {
+    if va < DIRECT_MAP_OFFSET && is_sv39(va) {
+        for &root in &[UVA, KVA, MVA] {
+            let pte_addr = shadow_page_tables.pte_for_addr(root, va);
+
+            match (shadow_page_tables.region[pte_addr] >> 8) & 0x3 {
+                0 => shadow_page_tables.region.set_invalid_pte(pte_addr, 0),
+                1 => for i in 0..512 {
+                    shadow_page_tables.region.set_invalid_pte(
+                        (pte_addr & !(PAGE_SIZE - 1)) + i * 8, 0)
+                }
+                _ => shadow_page_tables.clear_page_table_range(
+                    shadow_page_tables.root_pa(root), 0, DIRECT_MAP_PT_INDEX/8),
+            }
+        }
+        riscv::sfence_vma_addr(va);
+    }
+}
+
+/// Remove shadow mappings for guest virtual addresses in `start..end`. Large ranges are handled by
+/// flushing everything, which is cheaper than walking them a page at a time.
+pub fn flush_shadow_page_table_range(shadow_page_tables: &mut PageTables, start: u64, end: u64) {
+    const MAX_RANGE_PAGES: u64 = 64;
+
+    if end <= start {
+        return;
+    } else if end - start > MAX_RANGE_PAGES * PAGE_SIZE {
+        flush_shadow_page_table(shadow_page_tables);
+        return;
+    }
+
+    let first_page = start & !(PAGE_SIZE - 1);
+    for i in 0..(end - first_page + PAGE_SIZE - 1) / PAGE_SIZE {
+        flush_shadow_page_table_address(shadow_page_tables, first_page + i * PAGE_SIZE);
+    }
+}
+
 #[inline]
 pub fn handle_sfence_vma(state: &mut Context, instruction: RType) {
     if instruction.rs1() == 0 {
         flush_shadow_page_table(&mut state.shadow_page_tables);
+        trace::flush(state, 0, u64::max_value());
     } else {
         let va = state.saved_registers.get(instruction.rs1());
-        if va < DIRECT_MAP_OFFSET {
-            for &root in &[UVA, KVA, MVA] {
-                let pte_addr = state.shadow_page_tables.pte_for_addr(root, va);
-
-                match (state.shadow_page_tables.region[pte_addr] >> 8) & 0x3 {
-                    0 => state.shadow_page_tables.region.set_invalid_pte(pte_addr, 0),
-                    1 => for i in 0..512 {
-                        state.shadow_page_tables.region.set_invalid_pte(
-                            (pte_addr & !(PAGE_SIZE - 1)) + i * 8, 0)
-                    }
-                    _ => state.shadow_page_tables.clear_page_table_range(
-                        state.shadow_page_tables.root_pa(root), 0, DIRECT_MAP_PT_INDEX/8),
-                }
-            }
-            riscv::sfence_vma_addr(va);
-        }
+        flush_shadow_page_table_address(&mut state.shadow_page_tables, va);
+        trace::flush(state, va, va + 1);
     }
 }
 
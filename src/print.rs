@@ -1,8 +1,10 @@
+use arrayvec::ArrayVec;
 use core::{fmt, ptr};
 use spin::MutexGuard;
+use crate::constants::MAX_GUESTS;
 use crate::statics::SHARED_STATICS;
 use crate::fdt::UartType;
-use crate::pmap;
+use crate::{console_log, gdbstub, monitor, pmap};
 
 // see https://github.com/riscv/riscv-pk/blob/master/machine/uart16550.c
 // see: https://os.phil-opp.com/printing-to-screen
@@ -12,9 +14,97 @@ pub enum UartWriterInner {
     SiFive,
 }
 
+/// Who host console input is meant for.
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+pub enum ConsoleMode {
+    Guests,
+    /// The console is carrying the GDB remote protocol, and belongs to `gdbstub`.
+    Gdb,
+    /// Input is collected into lines of commands for `monitor`.
+    Monitor,
+}
+
+/// Host console input byte that introduces a command for the hypervisor, as with QEMU's Ctrl-A.
+pub const ESCAPE: u8 = 0x01;
+
+const MONITOR_LINE_LEN: usize = 128;
+const INPUT_QUEUE_LEN: usize = 256;
+
+/// Host console input waiting to be read by one guest.
+#[derive(Copy, Clone)]
+struct InputQueue {
+    bytes: [u8; INPUT_QUEUE_LEN],
+    head: usize,
+    len: usize,
+}
+
+impl InputQueue {
+    const fn new() -> Self {
+        Self { bytes: [0; INPUT_QUEUE_LEN], head: 0, len: 0 }
+    }
+
+    /// Input that doesn't fit is dropped.
+    fn push(&mut self, byte: u8) {
+        if self.len < INPUT_QUEUE_LEN {
+            self.bytes[(self.head + self.len) % INPUT_QUEUE_LEN] = byte;
+            self.len += 1;
+        }
+    }
+
+    fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let byte = self.bytes[self.head];
+        self.head = (self.head + 1) % INPUT_QUEUE_LEN;
+        self.len -= 1;
+        Some(byte)
+    }
+}
+
+/// Routes host console input to the guest that has focus. Every guest polls the host UART for
+/// input, so bytes are queued for the focused guest by whichever guest happens to read them first,
+/// rather than going to that one.
+pub struct InputRouter {
+    /// Slot of the guest that has focus.
+    focus: usize,
+    queues: [InputQueue; MAX_GUESTS],
+}
+
+impl InputRouter {
+    pub const fn new() -> Self {
+        Self {
+            focus: 0,
+            queues: [InputQueue::new(); MAX_GUESTS],
+        }
+    }
+
+    pub fn focus(&self) -> usize {
+        self.focus
+    }
+}
+
+/// Escape sequence for the colour that marks output from guest `guestid`.
+fn guest_colour(guestid: u64) -> &'static str {
+    match guestid {
+        1 => "\u{1b}[32m",
+        2 => "\u{1b}[34m",
+        _ => "\u{1b}[33m",
+    }
+}
+
 pub struct UartWriter {
     pub pa: u64,
     pub inner: UartWriterInner,
+    pub mode: ConsoleMode,
+    escape_pending: bool,
+    /// Monitor command typed so far.
+    monitor_line: [u8; MONITOR_LINE_LEN],
+    monitor_line_len: usize,
+    pub input: InputRouter,
+    /// Lines of output from each guest that were held back while the console belonged to the
+    /// monitor or the GDB stub, to be replayed from the console log when the guests get it back.
+    suppressed: [usize; MAX_GUESTS],
 }
 
 impl UartWriterInner {
@@ -86,6 +176,19 @@ impl UartWriterInner {
     }
 }
 impl UartWriter {
+    pub const fn new(pa: u64, inner: UartWriterInner) -> Self {
+        Self {
+            pa,
+            inner,
+            mode: ConsoleMode::Guests,
+            escape_pending: false,
+            monitor_line: [0; MONITOR_LINE_LEN],
+            monitor_line_len: 0,
+            input: InputRouter::new(),
+            suppressed: [0; MAX_GUESTS],
+        }
+    }
+
     #[cfg(not(feature = "physical_symbol_addresses"))]
     pub fn putchar(&mut self, ch: u8) {
         self.inner.putchar(pmap::pa2va(self.pa), ch);
@@ -100,6 +203,124 @@ impl UartWriter {
         self.inner.getchar(pmap::pa2va(self.pa))
     }
 
+    /// Next byte of host console input for guest `guestid`, or None if there is no more for it
+    /// right now. Input goes to whichever guest has focus. Ctrl-A followed by another key is a
+    /// command for the hypervisor instead: a digit gives focus to that guest and `n` to the next
+    /// one, `c` switches between the guests and the monitor, `g` hands the console over to the GDB
+    /// stub, and a second Ctrl-A passes a literal Ctrl-A on to the guests.
+    pub fn guest_input(&mut self, guestid: Option<u64>) -> Option<u8> {
+        use core::fmt::Write;
+        let guest = guestid.unwrap_or(1) as usize - 1;
+        loop {
+            if let Some(ch) = self.input.queues[guest].pop() {
+                return Some(ch);
+            }
+            if self.mode == ConsoleMode::Gdb {
+                return None;
+            }
+
+            let ch = self.getchar()?;
+            if self.escape_pending {
+                self.escape_pending = false;
+                match ch {
+                    ESCAPE if self.mode == ConsoleMode::Guests => self.input.queues[self.input.focus].push(ESCAPE),
+                    b'1'..=b'8' if self.mode == ConsoleMode::Guests => self.set_focus((ch - b'1') as usize),
+                    b'n' if self.mode == ConsoleMode::Guests => {
+                        let next = (1..=MAX_GUESTS).map(|i| (self.input.focus + i) % MAX_GUESTS)
+                            .find(|&i| SHARED_STATICS.guest_control(i).vcpus() != 0);
+                        if let Some(next) = next {
+                            self.set_focus(next);
+                        }
+                    }
+                    b'c' if self.mode == ConsoleMode::Guests => {
+                        let _ = write!(self, "\r\nrvirt monitor, enter 'help' for a list of commands\r\n{}", monitor::PROMPT);
+                        self.monitor_line_len = 0;
+                        self.mode = ConsoleMode::Monitor;
+                    }
+                    b'c' => {
+                        let _ = write!(self, "\r\n");
+                        self.resume_guests();
+                    }
+                    b'g' => {
+                        let guestid = gdbstub::attach();
+                        let _ = write!(self, "\r\nrvirt: debugging guest {}, connect GDB to this serial port\r\n", guestid);
+                        self.mode = ConsoleMode::Gdb;
+                    }
+                    _ => {}
+                }
+            } else if ch == ESCAPE {
+                self.escape_pending = true;
+            } else if self.mode == ConsoleMode::Monitor {
+                self.monitor_input(ch);
+            } else {
+                self.input.queues[self.input.focus].push(ch);
+            }
+        }
+    }
+
+    /// Give focus to the guest in slot `guest`, if there is one, and say so in its colour.
+    fn set_focus(&mut self, guest: usize) {
+        use core::fmt::Write;
+        if guest >= MAX_GUESTS || SHARED_STATICS.guest_control(guest).vcpus() == 0 {
+            return;
+        }
+        self.input.focus = guest;
+        let guestid = guest as u64 + 1;
+        let _ = write!(self, "\r\n{}\u{1b}[1m[{}]\u{1b}[0m input now goes to guest {}\r\n",
+                       guest_colour(guestid), guestid, guestid);
+    }
+
+    /// Echo and collect a byte of a monitor command, running the command once the line is complete.
+    fn monitor_input(&mut self, ch: u8) {
+        use core::fmt::Write;
+        match ch {
+            b'\r' | b'\n' => {
+                let _ = write!(self, "\r\n");
+                let line = self.monitor_line;
+                let len = self.monitor_line_len;
+                self.monitor_line_len = 0;
+
+                let line = core::str::from_utf8(&line[..len]).unwrap_or("");
+                if monitor::execute(line, self) {
+                    let _ = write!(self, "{}", monitor::PROMPT);
+                } else {
+                    self.resume_guests();
+                }
+            }
+            // Backspace, or delete as most terminals send it.
+            0x08 | 0x7f => if self.monitor_line_len > 0 {
+                self.monitor_line_len -= 1;
+                let _ = write!(self, "\x08 \x08");
+            }
+            b' '..=b'~' if self.monitor_line_len < MONITOR_LINE_LEN => {
+                self.monitor_line[self.monitor_line_len] = ch;
+                self.monitor_line_len += 1;
+                self.putchar(ch);
+            }
+            _ => {}
+        }
+    }
+
+    /// Give the console back to the guests, and print the output they wrote while they didn't have
+    /// it, as far as the console log still holds it.
+    pub fn resume_guests(&mut self) {
+        use core::fmt::Write;
+        self.mode = ConsoleMode::Guests;
+        for guest in 0..MAX_GUESTS {
+            let suppressed = core::mem::replace(&mut self.suppressed[guest], 0);
+            if suppressed == 0 {
+                continue;
+            }
+
+            let replayed = console_log::for_recent_lines(guest, suppressed, |line| {
+                write_guest_line(self, guest as u64 + 1, line);
+            });
+            if replayed < suppressed {
+                let _ = writeln!(self, "[{} lines from guest {} suppressed]", suppressed - replayed, guest + 1);
+            }
+        }
+    }
+
     pub unsafe fn init(&mut self, address: u64, ty: UartType) {
         if let UartWriterInner::Ns16550a { initialized: true } = self.inner {
             assert_eq!(self.pa, address);
@@ -150,15 +371,22 @@ pub mod macros {
 }
 
 pub fn guest_println(guestid: u64, line: &[u8]) {
-    use core::fmt::Write;
     let mut writer = SHARED_STATICS.uart_writer.lock();
-    match guestid {
-        1 => writer.write_str("\u{1b}[32m").unwrap(),
-        2 => writer.write_str("\u{1b}[34m").unwrap(),
-        _ => writer.write_str("\u{1b}[33m").unwrap(),
+    if writer.mode != ConsoleMode::Guests {
+        writer.suppressed[guestid as usize - 1] += 1;
+        return;
     }
+    write_guest_line(&mut writer, guestid, line);
+}
+
+/// Print a line of output from guest `guestid`, prefixed with its id.
+fn write_guest_line(writer: &mut UartWriter, guestid: u64, line: &[u8]) {
+    use core::fmt::Write;
+    // The guest with focus is marked with a '>' after its id.
+    let focused = writer.input.focus() as u64 + 1 == guestid;
+    writer.write_str(guest_colour(guestid)).unwrap();
     writer.write_str("\u{1b}[1m").unwrap();
-    writer.write_fmt(format_args!("[{}] ", guestid)).unwrap();
+    writer.write_fmt(format_args!("[{}]{}", guestid, if focused { ">" } else { " " })).unwrap();
     writer.write_str("\u{1b}[0m").unwrap();
     for &b in line {
         writer.putchar(b);
@@ -166,6 +394,55 @@ pub fn guest_println(guestid: u64, line: &[u8]) {
     writer.write_str("\n").unwrap();
 }
 
+/// Console output from a guest, whichever device it was written to. Output is collected into lines
+/// for the guest's console log and, with multiple guests, so that each one can be printed with a
+/// prefix naming its guest.
+pub struct ConsoleOutput {
+    pub guestid: Option<u64>,
+    line_buffer: ArrayVec<[u8; 256]>,
+}
+
+impl ConsoleOutput {
+    pub fn new(guestid: Option<u64>) -> Self {
+        Self {
+            guestid,
+            line_buffer: ArrayVec::new(),
+        }
+    }
+
+    pub fn output_byte(&mut self, value: u8) {
+        if self.guestid.is_none() {
+            let mut writer = SHARED_STATICS.uart_writer.lock();
+            if writer.mode == ConsoleMode::Guests {
+                writer.putchar(value);
+            }
+        }
+
+        let len = self.line_buffer.len();
+        if len > 0 && self.line_buffer[len - 1] == '\r' as u8 && value != '\n' as u8 {
+            self.end_line();
+        }
+        if value == '\n' as u8 || self.line_buffer.is_full() {
+            self.end_line();
+        } else {
+            self.line_buffer.push(value);
+        }
+    }
+
+    fn end_line(&mut self) {
+        if let Some(guestid) = self.guestid {
+            guest_println(guestid, &self.line_buffer);
+        } else {
+            let mut writer = SHARED_STATICS.uart_writer.lock();
+            if writer.mode != ConsoleMode::Guests {
+                writer.suppressed[0] += 1;
+            }
+        }
+        console_log::record(self.guestid.unwrap_or(1) as usize - 1, &self.line_buffer);
+        self.line_buffer.clear();
+    }
+}
+
 pub fn mwriter<'a>() -> Option<MutexGuard<'a, UartWriter>> {
     SHARED_STATICS.uart_writer.try_lock()
 }
@@ -177,10 +454,7 @@ const QEMU_VENDOR_ID: u64 = 0x00000000;
 pub fn early_guess_uart() {
     if csrr!(mvendorid) == QEMU_VENDOR_ID {
         let mut writer = SHARED_STATICS.uart_writer.lock();
-        *writer = UartWriter {
-            pa: 0x10000000,
-            inner: UartWriterInner::Ns16550a { initialized: false },
-        }
+        *writer = UartWriter::new(0x10000000, UartWriterInner::Ns16550a { initialized: false });
     } else {
         // probably SiFive; just use the value already configured.
     }
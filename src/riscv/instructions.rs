@@ -110,3 +110,82 @@ pub fn clear_sip(mask: u64) {
 pub fn set_sstatus_fs(new: u64) {
     unsafe { csrw!(sstatus, (new & STATUS_FS) | (csrr!(sstatus) & !STATUS_FS)) }
 }
+
+/// Save the floating point registers followed by `fcsr`. Requires `sstatus.FS` to not be Off.
+///
+/// rvirt is built for a target without the F and D extensions, so the assembler won't accept
+/// floating point instructions and they have to be emitted as raw words instead.
+pub unsafe fn save_fp_registers(area: &mut [u64; 33]) {
+    asm!(".word 0x00053027 // fsd f0, 0(a0)
+          .word 0x00153427 // fsd f1, 8(a0)
+          .word 0x00253827 // fsd f2, 16(a0)
+          .word 0x00353c27 // fsd f3, 24(a0)
+          .word 0x02453027 // fsd f4, 32(a0)
+          .word 0x02553427 // fsd f5, 40(a0)
+          .word 0x02653827 // fsd f6, 48(a0)
+          .word 0x02753c27 // fsd f7, 56(a0)
+          .word 0x04853027 // fsd f8, 64(a0)
+          .word 0x04953427 // fsd f9, 72(a0)
+          .word 0x04a53827 // fsd f10, 80(a0)
+          .word 0x04b53c27 // fsd f11, 88(a0)
+          .word 0x06c53027 // fsd f12, 96(a0)
+          .word 0x06d53427 // fsd f13, 104(a0)
+          .word 0x06e53827 // fsd f14, 112(a0)
+          .word 0x06f53c27 // fsd f15, 120(a0)
+          .word 0x09053027 // fsd f16, 128(a0)
+          .word 0x09153427 // fsd f17, 136(a0)
+          .word 0x09253827 // fsd f18, 144(a0)
+          .word 0x09353c27 // fsd f19, 152(a0)
+          .word 0x0b453027 // fsd f20, 160(a0)
+          .word 0x0b553427 // fsd f21, 168(a0)
+          .word 0x0b653827 // fsd f22, 176(a0)
+          .word 0x0b753c27 // fsd f23, 184(a0)
+          .word 0x0d853027 // fsd f24, 192(a0)
+          .word 0x0d953427 // fsd f25, 200(a0)
+          .word 0x0da53827 // fsd f26, 208(a0)
+          .word 0x0db53c27 // fsd f27, 216(a0)
+          .word 0x0fc53027 // fsd f28, 224(a0)
+          .word 0x0fd53427 // fsd f29, 232(a0)
+          .word 0x0fe53827 // fsd f30, 240(a0)
+          .word 0x0ff53c27 // fsd f31, 248(a0)
+          csrr t0, 0x003  // fcsr
+          sd t0, 256(a0)" :: "{a0}"(area.as_mut_ptr()) : "t0", "memory" : "volatile");
+}
+
+/// Restore state saved by `save_fp_registers`. Requires `sstatus.FS` to not be Off.
+pub unsafe fn restore_fp_registers(area: &[u64; 33]) {
+    asm!(".word 0x00053007 // fld f0, 0(a0)
+          .word 0x00853087 // fld f1, 8(a0)
+          .word 0x01053107 // fld f2, 16(a0)
+          .word 0x01853187 // fld f3, 24(a0)
+          .word 0x02053207 // fld f4, 32(a0)
+          .word 0x02853287 // fld f5, 40(a0)
+          .word 0x03053307 // fld f6, 48(a0)
+          .word 0x03853387 // fld f7, 56(a0)
+          .word 0x04053407 // fld f8, 64(a0)
+          .word 0x04853487 // fld f9, 72(a0)
+          .word 0x05053507 // fld f10, 80(a0)
+          .word 0x05853587 // fld f11, 88(a0)
+          .word 0x06053607 // fld f12, 96(a0)
+          .word 0x06853687 // fld f13, 104(a0)
+          .word 0x07053707 // fld f14, 112(a0)
+          .word 0x07853787 // fld f15, 120(a0)
+          .word 0x08053807 // fld f16, 128(a0)
+          .word 0x08853887 // fld f17, 136(a0)
+          .word 0x09053907 // fld f18, 144(a0)
+          .word 0x09853987 // fld f19, 152(a0)
+          .word 0x0a053a07 // fld f20, 160(a0)
+          .word 0x0a853a87 // fld f21, 168(a0)
+          .word 0x0b053b07 // fld f22, 176(a0)
+          .word 0x0b853b87 // fld f23, 184(a0)
+          .word 0x0c053c07 // fld f24, 192(a0)
+          .word 0x0c853c87 // fld f25, 200(a0)
+          .word 0x0d053d07 // fld f26, 208(a0)
+          .word 0x0d853d87 // fld f27, 216(a0)
+          .word 0x0e053e07 // fld f28, 224(a0)
+          .word 0x0e853e87 // fld f29, 232(a0)
+          .word 0x0f053f07 // fld f30, 240(a0)
+          .word 0x0f853f87 // fld f31, 248(a0)
+          ld t0, 256(a0)
+          csrw 0x003, t0  // fcsr" :: "{a0}"(area.as_ptr()) : "t0", "memory" : "volatile");
+}
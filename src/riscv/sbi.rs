@@ -24,3 +24,18 @@ pub fn send_ipi_to_hart(hart: u64) {
     let mask: u64 = 1 << hart;
     send_ipi(&mask as *const u64 as u64);
 }
+
+/// Call that RVirt's own M-mode code answers with the PMP registers of the calling hart. Other SBI
+/// implementations report it as unsupported.
+const RVIRT_READ_PMP: u64 = 0x0a52_5650;
+
+/// Read pmpcfg0, pmpcfg2 and pmpaddr0 through pmpaddr15 of this hart into `registers`, in that
+/// order. Returns false if the SBI implementation can't, as only RVirt's own M-mode code can.
+pub fn read_pmp(registers: &mut [u64; 18]) -> bool {
+    let error: u64;
+    unsafe {
+        asm!("ecall" : "={a0}"(error) : "{a0}"(registers.as_mut_ptr()), "{a7}"(RVIRT_READ_PMP)
+             : "a1", "memory" : "volatile");
+    }
+    error == 0
+}
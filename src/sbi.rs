@@ -0,0 +1,458 @@
+//! Emulation of the Supervisor Binary Interface (SBI) that guests use to make requests of the
+//! hypervisor. Both the legacy calling convention and the one introduced in v0.2 are supported.
+
+use crate::console_log;
+use crate::context::*;
+use crate::pmap::{self, PageTableRoot};
+use crate::riscv::bits::*;
+use crate::trap::{send_flush_range_requests, send_vcpu_requests, U64Bits};
+use crate::riscv;
+
+pub const SBI_SUCCESS: i64 = 0;
+pub const SBI_ERR_FAILED: i64 = -1;
+pub const SBI_ERR_NOT_SUPPORTED: i64 = -2;
+pub const SBI_ERR_INVALID_PARAM: i64 = -3;
+pub const SBI_ERR_DENIED: i64 = -4;
+pub const SBI_ERR_INVALID_ADDRESS: i64 = -5;
+pub const SBI_ERR_ALREADY_AVAILABLE: i64 = -6;
+
+pub const EXT_BASE: u64 = 0x10;
+pub const EXT_TIME: u64 = 0x54494D45;
+pub const EXT_IPI: u64 = 0x735049;
+pub const EXT_RFENCE: u64 = 0x52464E43;
+pub const EXT_HSM: u64 = 0x48534D;
+pub const EXT_SRST: u64 = 0x53525354;
+pub const EXT_DBCN: u64 = 0x4442434E;
+
+// Values returned by hart_get_status.
+pub const HART_STARTED: u64 = 0;
+pub const HART_STOPPED: u64 = 1;
+pub const HART_START_PENDING: u64 = 2;
+
+// Reset types accepted by system_reset.
+pub const RESET_TYPE_SHUTDOWN: u64 = 0;
+pub const RESET_TYPE_COLD_REBOOT: u64 = 1;
+pub const RESET_TYPE_WARM_REBOOT: u64 = 2;
+pub const RESET_REASON_SYSTEM_FAILURE: u32 = 1;
+
+/// Version of the SBI specification implemented, encoded as `major << 24 | minor`.
+const SPEC_VERSION: u64 = 2 << 24 | 0;
+
+/// rvirt doesn't have an implementation ID assigned by the SBI specification, so this is picked
+/// from outside the allocated range ("rvirt" in ASCII).
+const IMPL_ID: u64 = 0x72_76_69_72_74;
+const IMPL_VERSION: u64 = 0 << 16 | 1 << 8 | 0;
+
+/// Result of an SBI call, returned to the guest in a0 (error) and a1 (value).
+type SbiResult = Result<u64, i64>;
+
+const PAGE_SIZE: u64 = 4096;
+
+/// Handle an ecall made by the guest kernel. The extension ID is in a7 and, for non-legacy calls,
+/// the function ID is in a6.
+pub fn handle_ecall(state: &mut Context) {
+    let extension = state.saved_registers.get(17);
+    if extension < 0x10 {
+        handle_legacy_call(state, extension);
+        return;
+    }
+
+    let function = state.saved_registers.get(16);
+    let result = match extension {
+        EXT_BASE => handle_base(state, function),
+        EXT_TIME => handle_time(state, function),
+        EXT_IPI => handle_ipi(state, function),
+        EXT_RFENCE => handle_rfence(state, function),
+        EXT_HSM => handle_hsm(state, function),
+        EXT_SRST => handle_srst(state, function),
+        EXT_DBCN => handle_dbcn(state, function),
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    };
+
+    let (error, value) = match result {
+        Ok(value) => (SBI_SUCCESS, value),
+        Err(error) => (error, 0),
+    };
+    state.saved_registers.set(10, error as u64);
+    state.saved_registers.set(11, value);
+}
+
+/// Returns whether `extension` is implemented, for the probe_extension call.
+fn extension_available(extension: u64) -> bool {
+    match extension {
+        0..=8 => true,
+        EXT_BASE | EXT_TIME | EXT_IPI | EXT_RFENCE => true,
+        EXT_HSM | EXT_SRST | EXT_DBCN => true,
+        _ => false,
+    }
+}
+
+fn handle_base(state: &mut Context, function: u64) -> SbiResult {
+    match function {
+        0 => Ok(SPEC_VERSION),
+        1 => Ok(IMPL_ID),
+        2 => Ok(IMPL_VERSION),
+        3 => Ok(extension_available(state.saved_registers.get(10)) as u64),
+        // mvendorid, marchid and mimpid. The host's values aren't visible from S-mode, and zero is
+        // a legal answer for all three.
+        4 | 5 | 6 => Ok(0),
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+fn handle_time(state: &mut Context, function: u64) -> SbiResult {
+    match function {
+        0 => {
+            let stime_value = state.saved_registers.get(10);
+            set_timer(state, stime_value);
+            Ok(0)
+        }
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+fn handle_ipi(state: &mut Context, function: u64) -> SbiResult {
+    match function {
+        0 => {
+            let hart_mask = decode_hart_mask(state, state.saved_registers.get(10), state.saved_registers.get(11))?;
+            send_vcpu_requests(state, hart_mask, REQUEST_SOFTWARE_INTERRUPT, false);
+            Ok(0)
+        }
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+fn handle_rfence(state: &mut Context, function: u64) -> SbiResult {
+    let hart_mask = decode_hart_mask(state, state.saved_registers.get(10), state.saved_registers.get(11))?;
+    let start = state.saved_registers.get(12);
+    let size = state.saved_registers.get(13);
+
+    match function {
+        // remote_fence_i
+        0 => send_vcpu_requests(state, hart_mask, REQUEST_FENCE_I, true),
+        // remote_sfence_vma and remote_sfence_vma_asid. Guest ASIDs are ignored (the shadow page
+        // tables don't use them) so both flush the range for every address space.
+        1 | 2 => {
+            if (start == 0 && size == 0) || size == u64::max_value() {
+                send_vcpu_requests(state, hart_mask, REQUEST_FLUSH_SHADOW_PAGE_TABLES, true);
+            } else {
+                send_flush_range_requests(state, hart_mask, start, start.saturating_add(size));
+            }
+        }
+        // The hypervisor extension isn't supported, so neither are the remote_hfence calls.
+        _ => return Err(SBI_ERR_NOT_SUPPORTED),
+    }
+    Ok(0)
+}
+
+fn handle_hsm(state: &mut Context, function: u64) -> SbiResult {
+    let hartid = state.saved_registers.get(10);
+    match function {
+        // hart_start
+        0 => {
+            let start_addr = state.saved_registers.get(11);
+            let opaque = state.saved_registers.get(12);
+            if !state.guest_memory.in_region(start_addr) {
+                return Err(SBI_ERR_INVALID_ADDRESS);
+            }
+
+            let mut guest = state.guest.lock();
+            let vcpu = guest.as_mut().unwrap().vcpus.get_mut(hartid as usize).ok_or(SBI_ERR_INVALID_PARAM)?;
+            if vcpu.status != HartStatus::Stopped {
+                return Err(SBI_ERR_ALREADY_AVAILABLE);
+            }
+            vcpu.status = HartStatus::StartPending { start_addr, opaque };
+
+            // If the target's host hart is idle it needs waking up. One that hasn't come online yet
+            // will notice when it does.
+            if let Some(hartid) = vcpu.hartid {
+                riscv::sbi::send_ipi_to_hart(hartid);
+            }
+            Ok(0)
+        }
+        // hart_stop. The host hart goes back to running other vCPUs. This call never returns to the
+        // guest: if the vCPU is started again, it begins at the new start address instead.
+        1 => {
+            let guest = state.guest;
+            let mut guest = guest.lock();
+            let vcpu = &mut guest.as_mut().unwrap().vcpus[state.vcpu];
+            vcpu.status = HartStatus::Stopped;
+            vcpu.running = false;
+            Ok(0)
+        }
+        // hart_get_status
+        2 => {
+            let guest = state.guest.lock();
+            let vcpu = guest.as_ref().unwrap().vcpus.get(hartid as usize).ok_or(SBI_ERR_INVALID_PARAM)?;
+            Ok(match vcpu.status {
+                HartStatus::Started => HART_STARTED,
+                HartStatus::Stopped => HART_STOPPED,
+                HartStatus::StartPending { .. } => HART_START_PENDING,
+            })
+        }
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+fn handle_srst(state: &mut Context, function: u64) -> SbiResult {
+    match function {
+        // system_reset. Both arguments are 32 bits wide.
+        0 => {
+            let reset_type = state.saved_registers.get(10) as u32 as u64;
+            let reset_reason = state.saved_registers.get(11) as u32;
+            // Reasons other than "none" and "system failure" are reserved, except for the SBI
+            // implementation and vendor specific ranges at the top.
+            if reset_reason > RESET_REASON_SYSTEM_FAILURE && reset_reason < 0xE000_0000 {
+                return Err(SBI_ERR_INVALID_PARAM);
+            }
+            if reset_type > RESET_TYPE_WARM_REBOOT {
+                return Err(SBI_ERR_INVALID_PARAM);
+            }
+
+            if reset_reason == RESET_REASON_SYSTEM_FAILURE {
+                console_log::dump(state.guest_index);
+            }
+
+            match reset_type {
+                RESET_TYPE_SHUTDOWN => shutdown(state),
+                RESET_TYPE_COLD_REBOOT | RESET_TYPE_WARM_REBOOT => reboot(state),
+                _ => unreachable!(),
+            }
+            Ok(0)
+        }
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// Stop every vCPU of the guest and reset its devices. Only this guest is affected, except that
+/// when the host was started with a single guest and a test finisher, the whole machine exits.
+fn shutdown(state: &mut Context) {
+    if let Some(ref mut finisher) = state.test_finisher {
+        finisher.pass();
+    }
+
+    stop_guest(state);
+    match state.guest.lock().as_ref().unwrap().uart.output.guestid {
+        Some(guestid) => println!("Guest {} shut down", guestid),
+        None => println!("Guest shut down"),
+    }
+}
+
+/// Reload the guest kernel and device tree, and start the first vCPU at the kernel entry point as
+/// if the guest had just booted. Warm and cold reboots are the same thing here.
+pub fn reboot(state: &mut Context) {
+    stop_guest(state);
+
+    let guest = state.guest;
+    let mut guest = guest.lock();
+    let guest = guest.as_mut().unwrap();
+    let (entry, dtb, _) = unsafe {
+        load_guest(guest.image, guest.dtb_template, &state.guest_memory, state.guest_shift,
+                   &guest.bootargs, guest.vcpus.len() as u64)
+    };
+    guest.entry = entry;
+    guest.dtb = dtb;
+
+    let vcpu = &mut guest.vcpus[0];
+    vcpu.status = HartStatus::StartPending { start_addr: entry, opaque: dtb };
+    if state.vcpu != 0 {
+        if let Some(hartid) = vcpu.hartid {
+            riscv::sbi::send_ipi_to_hart(hartid);
+        }
+    }
+}
+
+/// Stop every vCPU of the guest, including the current one, and wait until none of the others can
+/// touch guest memory any more. Then reset the guest's devices.
+fn stop_guest(state: &mut Context) {
+    {
+        let mut guest = state.guest.lock();
+        for vcpu in guest.as_mut().unwrap().vcpus.iter_mut() {
+            vcpu.status = HartStatus::Stopped;
+        }
+    }
+    send_vcpu_requests(state, u64::max_value(), REQUEST_STOP, true);
+
+    let guest = state.guest;
+    let mut guest = guest.lock();
+    guest.as_mut().unwrap().reset_devices(&mut state.guest_memory);
+}
+
+fn handle_dbcn(state: &mut Context, function: u64) -> SbiResult {
+    let num_bytes = state.saved_registers.get(10);
+    let base_addr = state.saved_registers.get(11);
+    let base_addr_hi = state.saved_registers.get(12);
+
+    match function {
+        // console_write and console_read. On RV64 the whole address fits in base_addr_lo.
+        0 | 1 if base_addr_hi != 0 => Err(SBI_ERR_INVALID_PARAM),
+        0 => console_write(state, num_bytes, base_addr),
+        1 => console_read(state, num_bytes, base_addr),
+        // console_write_byte
+        2 => {
+            state.guest.lock().as_mut().unwrap().uart.output_byte(num_bytes as u8);
+            Ok(0)
+        }
+        _ => Err(SBI_ERR_NOT_SUPPORTED),
+    }
+}
+
+/// Write up to `num_bytes` from the guest buffer at `base_addr` to the guest's console. Output is
+/// line buffered like the emulated UART, so with multiple guests each line gets a guest prefix.
+fn console_write(state: &mut Context, num_bytes: u64, base_addr: u64) -> SbiResult {
+    let guest = state.guest;
+    let mut guest = guest.lock();
+    let uart = &mut guest.as_mut().unwrap().uart;
+
+    let mut written = 0;
+    while written < num_bytes {
+        let addr = base_addr.wrapping_add(written);
+        let len = (num_bytes - written).min(PAGE_SIZE - (addr & 0xfff));
+        match console_buffer_address(state, addr) {
+            Some(pa) => {
+                for &byte in state.guest_memory.slice(pa, len) {
+                    uart.output_byte(byte);
+                }
+            }
+            None if written == 0 => return Err(SBI_ERR_INVALID_PARAM),
+            None => break,
+        }
+        written += len;
+    }
+    Ok(written)
+}
+
+/// Copy whatever console input is available, up to `num_bytes`, into the guest buffer at
+/// `base_addr`. Doesn't block.
+fn console_read(state: &mut Context, num_bytes: u64, base_addr: u64) -> SbiResult {
+    let guest = state.guest;
+    let mut guest = guest.lock();
+    let uart = &mut guest.as_mut().unwrap().uart;
+    uart.fill_fifo();
+
+    let mut read = 0;
+    while read < num_bytes && uart.input_bytes_ready > 0 {
+        let addr = base_addr.wrapping_add(read);
+        let len = (num_bytes - read)
+            .min(PAGE_SIZE - (addr & 0xfff))
+            .min(uart.input_bytes_ready as u64);
+        match console_buffer_address(state, addr) {
+            Some(pa) => {
+                for byte in state.guest_memory.slice_mut(pa, len) {
+                    *byte = uart.take_input_byte().unwrap();
+                }
+            }
+            None if read == 0 => return Err(SBI_ERR_INVALID_PARAM),
+            None => break,
+        }
+        read += len;
+    }
+    Ok(read)
+}
+
+/// Find the guest physical address of byte `addr` of a debug console buffer. The specification
+/// has guests pass physical addresses, but when paging is enabled an address outside of guest
+/// memory is instead translated through the guest's page tables. The result is only valid up to
+/// the end of its page.
+fn console_buffer_address(state: &Context, addr: u64) -> Option<u64> {
+    if state.guest_memory.in_region(addr) {
+        return Some(addr);
+    }
+    if state.shadow() == PageTableRoot::MPA {
+        return None;
+    }
+
+    let root_page_table = (state.csrs.satp & SATP_PPN) << 12;
+    pmap::translate_guest_address(&state.guest_memory, root_page_table, addr & !0xfff)
+        .map(|translation| (translation.guest_pa & !0xfff) | (addr & 0xfff))
+        .filter(|&guest_pa| state.guest_memory.in_region(guest_pa))
+}
+
+/// Convert a `hart_mask` and `hart_mask_base` pair into a mask of vCPU indices. A base of -1 means
+/// every vCPU in the guest.
+fn decode_hart_mask(state: &Context, hart_mask: u64, hart_mask_base: u64) -> Result<u64, i64> {
+    let vcpus = state.guest.lock().as_ref().unwrap().vcpus.len();
+    let all_vcpus = (1u64 << vcpus) - 1;
+
+    if hart_mask_base == u64::max_value() {
+        return Ok(all_vcpus);
+    }
+    if hart_mask == 0 {
+        return Ok(0);
+    }
+    if hart_mask_base >= vcpus as u64 || (hart_mask << hart_mask_base) >> hart_mask_base != hart_mask {
+        return Err(SBI_ERR_INVALID_PARAM);
+    }
+
+    let mask = hart_mask << hart_mask_base;
+    if mask & !all_vcpus != 0 {
+        return Err(SBI_ERR_INVALID_PARAM);
+    }
+    Ok(mask)
+}
+
+fn set_timer(state: &mut Context, stime_value: u64) {
+    state.csrs.sip.set(IP_STIP, false);
+    state.csrs.mtimecmp = stime_value;
+    riscv::sbi::set_timer(state.csrs.mtimecmp);
+}
+
+fn handle_legacy_call(state: &mut Context, extension: u64) {
+    match extension {
+        0 => {
+            let stime_value = state.saved_registers.get(10);
+            set_timer(state, stime_value);
+        }
+        1 => {
+            let value = state.saved_registers.get(10) as u8;
+            state.guest.lock().as_mut().unwrap().uart.output_byte(value)
+        }
+        2 => {
+            let guest = state.guest;
+            let mut guest = guest.lock();
+            let uart = &mut guest.as_mut().unwrap().uart;
+            uart.fill_fifo();
+            let value = uart.take_input_byte().map(|b| b as u64).unwrap_or(u64::max_value());
+            state.saved_registers.set(10, value);
+        }
+        3 => state.csrs.sip.set(IP_SSIP, false),
+        4 => {
+            let hart_mask = read_hart_mask(state, state.saved_registers.get(10));
+            send_vcpu_requests(state, hart_mask, REQUEST_SOFTWARE_INTERRUPT, false);
+        }
+        5 => {
+            let hart_mask = read_hart_mask(state, state.saved_registers.get(10));
+            send_vcpu_requests(state, hart_mask, REQUEST_FENCE_I, true);
+        }
+        6 | 7 => {
+            // Current versions of the Linux kernel pass wrong arguments to these SBI calls. As
+            // a result, this function ignores the arguments and just does a global fence. This
+            // will eventually be fixed by https://patchwork.kernel.org/patch/10872353.
+            send_vcpu_requests(state, u64::max_value(), REQUEST_FLUSH_SHADOW_PAGE_TABLES, true);
+        }
+        8 => shutdown(state),
+        _ => state.saved_registers.set(10, SBI_ERR_NOT_SUPPORTED as u64),
+    }
+}
+
+/// Read the hart mask passed to a legacy SBI call. The argument is a guest virtual address (or a
+/// guest physical address if paging is disabled), and a null pointer means all harts.
+fn read_hart_mask(state: &Context, hart_mask_pointer: u64) -> u64 {
+    if hart_mask_pointer == 0 {
+        return u64::max_value();
+    }
+
+    let hart_mask = if state.shadow() == PageTableRoot::MPA {
+        state.guest_memory.get(hart_mask_pointer)
+    } else {
+        pmap::read64(&state.guest_memory, state.csrs.satp & SATP_PPN, hart_mask_pointer)
+    };
+
+    match hart_mask {
+        Some(hart_mask) => hart_mask,
+        None => {
+            println!("SBI: Unable to read hart mask at {:#x}", hart_mask_pointer);
+            0
+        }
+    }
+}
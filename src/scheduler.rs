@@ -0,0 +1,201 @@
+use arrayvec::ArrayVec;
+use spin::Mutex;
+use crate::constants::MAX_HART_VCPUS;
+use crate::context::{Context, ControlRegisters, HartStatus, CONTEXT};
+use crate::riscv::bits::*;
+use crate::statics::SHARED_STATICS;
+use crate::{pmap, riscv, trace, trap};
+
+/// Number of timer ticks a vCPU runs for before being preempted, unless overridden by
+/// `rvirt.timeslice=N`.
+pub const DEFAULT_TIME_SLICE: u64 = 100_000;
+
+/// Contexts of the vCPUs assigned to this hart other than the one currently running, which lives in
+/// CONTEXT. Lock ordering is CONTEXT first and then RUN_QUEUE.
+pub static RUN_QUEUE: Mutex<Option<RunQueue>> = Mutex::new(None);
+
+pub struct RunQueue {
+    /// Switched out contexts, in the order they will next run.
+    pub contexts: ArrayVec<[Context; MAX_HART_VCPUS]>,
+    time_slice: u64,
+    /// Time at which the running vCPU should be preempted.
+    slice_end: u64,
+    /// Switch to the next context before returning from the current trap.
+    switch_pending: bool,
+}
+
+/// Install the contexts for the current hart. If none of them has been started yet, wait until one
+/// is. That one becomes the running context, and it is up to the caller to jump into it using the
+/// returned entry point, a0 and a1.
+pub unsafe fn initialize(mut contexts: ArrayVec<[Context; MAX_HART_VCPUS]>, time_slice: u64) -> (u64, u64, u64) {
+    let index = loop {
+        if let Some(index) = contexts.iter().position(is_runnable) {
+            break index;
+        }
+        riscv::wfi();
+        riscv::clear_sip(IP_SSIP);
+    };
+
+    let mut current = contexts.remove(index);
+    take_start_request(&mut current);
+    current.guest.lock().as_mut().unwrap().vcpus[current.vcpu].running = true;
+    current.shadow_page_tables.install_root(current.shadow());
+    let entry = (current.switched_out.pc, current.switched_out.registers[10], current.switched_out.registers[11]);
+
+    let time_slice = if time_slice == 0 { DEFAULT_TIME_SLICE } else { time_slice };
+    let run_queue = RunQueue {
+        slice_end: current.host_clint.get_mtime() + time_slice,
+        contexts,
+        time_slice,
+        switch_pending: false,
+    };
+
+    // Memory backing for CONTEXT and RUN_QUEUE might not be in a valid state, so force_unlock()
+    // first, and avoid calling drop on the old contents. This is safe because no other hart will be
+    // trying to access this memory right now.
+    CONTEXT.force_unlock();
+    RUN_QUEUE.force_unlock();
+    core::mem::forget(CONTEXT.lock().replace(current));
+    core::mem::forget(RUN_QUEUE.lock().replace(run_queue));
+
+    entry
+}
+
+/// Called on every host timer interrupt. Returns the time at which the running vCPU's slice ends if
+/// there are other vCPUs waiting to run.
+pub fn timer(current_time: u64) -> Option<u64> {
+    let mut run_queue = RUN_QUEUE.lock();
+    let run_queue = run_queue.as_mut().unwrap();
+    if run_queue.contexts.is_empty() {
+        return None;
+    }
+
+    if current_time >= run_queue.slice_end {
+        run_queue.switch_pending = true;
+    }
+    Some(run_queue.slice_end)
+}
+
+/// Give up the rest of the current time slice, for instance because the guest executed a WFI.
+pub fn yield_hart() {
+    let mut run_queue = RUN_QUEUE.lock();
+    let run_queue = run_queue.as_mut().unwrap();
+    if !run_queue.contexts.is_empty() {
+        run_queue.switch_pending = true;
+    }
+}
+
+/// If a switch is pending or `state` has been stopped, move `state` to the back of the run queue and
+/// replace it with the first runnable context. Returns whether a switch happened, which it won't if
+/// no other context can run.
+pub fn maybe_switch(state: &mut Context) -> bool {
+    let mut run_queue = RUN_QUEUE.lock();
+    let run_queue = run_queue.as_mut().unwrap();
+    if !run_queue.switch_pending && is_runnable(state) {
+        return false;
+    }
+    run_queue.switch_pending = false;
+
+    let next = match run_queue.contexts.iter().position(is_runnable) {
+        Some(next) => next,
+        None => return false,
+    };
+
+    trap::set_vcpu_running(state, false);
+    save_guest_state(state);
+
+    let next = run_queue.contexts.remove(next);
+    let prev = core::mem::replace(state, next);
+    run_queue.contexts.push(prev);
+
+    take_start_request(state);
+    restore_guest_state(state);
+    trap::set_vcpu_running(state, true);
+
+    let time = state.host_clint.get_mtime();
+    run_queue.slice_end = time + run_queue.time_slice;
+    if state.csrs.mtimecmp <= time {
+        state.csrs.sip |= IP_STIP;
+    }
+    riscv::sbi::set_timer((time + 1_000_000).min(state.csrs.mtimecmp).min(run_queue.slice_end));
+
+    // Interrupts may have arrived while the context was switched out.
+    state.no_interrupt = false;
+    true
+}
+
+/// Returns whether the running context can continue, starting it first if another vCPU has asked
+/// for that since it stopped.
+pub fn ensure_running(state: &mut Context) -> bool {
+    if !is_runnable(state) {
+        return false;
+    }
+    if take_start_request(state) {
+        restore_guest_state(state);
+        trap::set_vcpu_running(state, true);
+    }
+    true
+}
+
+/// Paused guests never run. Otherwise stopped vCPUs don't either, unless the management interface
+/// asked for the guest to be rebooted, which one of them has to carry out.
+fn is_runnable(context: &Context) -> bool {
+    let control = SHARED_STATICS.guest_control(context.guest_index);
+    !control.paused() && (control.reboot_requested() ||
+        context.guest.lock().as_ref().unwrap().vcpus[context.vcpu].status != HartStatus::Stopped)
+}
+
+/// If a start is pending for `context`, reset it to the state the SBI specification requires of a
+/// newly started hart and mark it started. Returns whether that happened. The new register values
+/// are left in `switched_out` for the caller to restore.
+fn take_start_request(context: &mut Context) -> bool {
+    let guest = context.guest;
+    let mut guest = guest.lock();
+    let vcpu = &mut guest.as_mut().unwrap().vcpus[context.vcpu];
+    let (start_addr, opaque) = match vcpu.status {
+        HartStatus::StartPending { start_addr, opaque } => (start_addr, opaque),
+        _ => return false,
+    };
+    vcpu.status = HartStatus::Started;
+
+    context.csrs = ControlRegisters::new();
+    context.smode = true;
+    context.no_interrupt = true;
+    context.consecutive_page_fault_count = 0;
+    context.switched_out.registers = [0; 32];
+    context.switched_out.registers[10] = context.vcpu as u64;
+    context.switched_out.registers[11] = opaque;
+    context.switched_out.pc = start_addr;
+    context.switched_out.fs = 0;
+
+    // Whatever the vCPU had mapped before it stopped is gone now that paging is off, and the code
+    // it is about to run may have just been written by another hart.
+    pmap::flush_shadow_page_table(&mut context.shadow_page_tables);
+    trace::flush(context, 0, u64::max_value());
+    riscv::fence_i();
+    true
+}
+
+fn save_guest_state(state: &mut Context) {
+    for i in 1..32 {
+        state.switched_out.registers[i] = state.saved_registers.get(i as u32);
+    }
+    state.switched_out.pc = csrr!(sepc);
+    state.switched_out.fs = csrr!(sstatus) & STATUS_FS;
+    if state.switched_out.fs != 0 {
+        unsafe { riscv::save_fp_registers(&mut state.switched_out.fp_registers) }
+    }
+}
+
+fn restore_guest_state(state: &mut Context) {
+    for i in 1..32 {
+        let value = state.switched_out.registers[i];
+        state.saved_registers.set(i as u32, value);
+    }
+    riscv::set_sepc(state.switched_out.pc);
+    if state.switched_out.fs != 0 {
+        riscv::set_sstatus_fs(STATUS_FS);
+        unsafe { riscv::restore_fp_registers(&state.switched_out.fp_registers) }
+    }
+    riscv::set_sstatus_fs(state.switched_out.fs);
+}
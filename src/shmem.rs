@@ -0,0 +1,157 @@
+//! Memory window shared between guests, in the style of QEMU's ivshmem device. The same host memory
+//! appears at WINDOW_BASE in the physical address space of every guest, and is mapped into shadow
+//! page tables like ordinary guest memory, so guests can exchange data over it without copies.
+//!
+//! Alongside the window, each guest gets a page of registers at REGISTERS_BASE through which it can
+//! interrupt the others. Both are described to the guest by the `/shmem` node of its device tree.
+//!
+//! ```text
+//!  OFFSET  NAME      ACCESS        CONTENTS
+//!  0x00    ID        read          id of this guest
+//!  0x04    PEERS     read          mask of the guests sharing the window, bit N for guest N
+//!  0x08    DOORBELL  write         raise the shared memory interrupt of the guest whose id is written
+//!  0x0c    STATUS    read/clear    mask of the guests that rang this guest's doorbell; writing a
+//!                                  mask clears those bits
+//! ```
+
+use arr_macro::arr;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use riscv_decode::Instruction;
+use crate::constants::MAX_GUESTS;
+use crate::context::{Context, Guest};
+use crate::riscv::bits::{SCAUSE_LOAD_ACCESS_FAULT, SCAUSE_STORE_ACCESS_FAULT, SCAUSE_STORE_PAGE_FAULT};
+use crate::statics::SHARED_STATICS;
+use crate::{riscv, trap};
+
+/// Guest physical address of the shared window.
+pub const WINDOW_BASE: u64 = 0x40000000;
+/// Largest window that fits below guest memory.
+pub const MAX_WINDOW_SIZE: u64 = 0x40000000;
+/// Guest physical address of the doorbell registers.
+pub const REGISTERS_BASE: u64 = 0x10008000;
+/// Guest interrupt raised by the doorbell.
+pub const IRQ: u32 = 5;
+
+const REG_ID: u64 = 0x00;
+const REG_PEERS: u64 = 0x04;
+const REG_DOORBELL: u64 = 0x08;
+const REG_STATUS: u64 = 0x0c;
+
+pub struct SharedMemory {
+    /// Host physical address of the window, and its size, which is zero if there is no window.
+    host_pa: AtomicU64,
+    size: AtomicU64,
+    /// Mask of the guest slots attached to the window.
+    attached: AtomicU64,
+    /// Host hart that the first vCPU of each guest runs on, which gets the doorbell interrupts.
+    harts: [AtomicU64; MAX_GUESTS],
+    /// Mask of the guest slots that rang each guest's doorbell, as read through STATUS.
+    status: [AtomicU64; MAX_GUESTS],
+    /// Whether each guest's doorbell was rung since its interrupt was last raised.
+    rung: [AtomicBool; MAX_GUESTS],
+}
+
+impl SharedMemory {
+    pub const fn new() -> Self {
+        Self {
+            host_pa: AtomicU64::new(0),
+            size: AtomicU64::new(0),
+            attached: AtomicU64::new(0),
+            harts: arr![AtomicU64::new(0); 8],
+            status: arr![AtomicU64::new(0); 8],
+            rung: arr![AtomicBool::new(false); 8],
+        }
+    }
+
+    /// Make the `size` bytes of host memory at `host_pa` the shared window. Must be called before
+    /// any guest is created.
+    pub fn set_window(&self, host_pa: u64, size: u64) {
+        assert!(size <= MAX_WINDOW_SIZE && size % 4096 == 0);
+        self.host_pa.store(host_pa, Ordering::SeqCst);
+        self.size.store(size, Ordering::SeqCst);
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    /// Give the guest in slot `guest` access to the window, with its doorbell interrupts sent to host
+    /// hart `hartid`.
+    pub fn attach(&self, guest: usize, hartid: u64) {
+        if self.size() != 0 {
+            self.harts[guest].store(hartid, Ordering::SeqCst);
+            self.attached.fetch_or(1 << guest, Ordering::SeqCst);
+        }
+    }
+
+    /// Host physical address backing `guest_pa`, if it lies within the window.
+    pub fn host_address(&self, guest_pa: u64) -> Option<u64> {
+        if guest_pa >= WINDOW_BASE && guest_pa - WINDOW_BASE < self.size() {
+            Some(self.host_pa.load(Ordering::SeqCst) + (guest_pa - WINDOW_BASE))
+        } else {
+            None
+        }
+    }
+
+    /// Ring the doorbell of the guest in slot `target` on behalf of the one in slot `source`.
+    fn ring(&self, source: usize, target: usize) {
+        if target >= MAX_GUESTS || self.attached.load(Ordering::SeqCst) & (1 << target) == 0 {
+            return;
+        }
+
+        self.status[target].fetch_or(1 << source, Ordering::SeqCst);
+        if !self.rung[target].swap(true, Ordering::SeqCst) {
+            riscv::sbi::send_ipi_to_hart(self.harts[target].load(Ordering::SeqCst));
+        }
+    }
+}
+
+#[inline(always)]
+pub fn is_register_access(state: &Context, guest_pa: u64) -> bool {
+    guest_pa >= REGISTERS_BASE && guest_pa < REGISTERS_BASE + 0x1000
+        && SHARED_STATICS.shmem.attached.load(Ordering::SeqCst) & (1 << state.guest_index) != 0
+}
+
+/// Emulate an access to the doorbell registers, which caused a page fault with `cause`. Guest ids
+/// start at one, so bit N of a register value corresponds to slot N - 1. Accesses other than word
+/// loads and stores get an access fault, as the page may be mapped into user space.
+pub fn handle_register_access(state: &mut Context, cause: u64, guest_pa: u64, instruction: u32) -> bool {
+    let shmem = &SHARED_STATICS.shmem;
+    let guest = state.guest_index;
+    match riscv_decode::decode(instruction).ok() {
+        Some(Instruction::Lw(i)) => {
+            let value = match guest_pa - REGISTERS_BASE {
+                REG_ID => guest as u64 + 1,
+                REG_PEERS => shmem.attached.load(Ordering::SeqCst) << 1,
+                REG_STATUS => shmem.status[guest].load(Ordering::SeqCst) << 1,
+                _ => 0,
+            };
+            state.saved_registers.set(i.rd(), value);
+        }
+        Some(Instruction::Sw(i)) => {
+            let value = state.saved_registers.get(i.rs2()) as u32 as u64;
+            match guest_pa - REGISTERS_BASE {
+                REG_DOORBELL if value >= 1 => shmem.ring(guest, value as usize - 1),
+                REG_STATUS => { shmem.status[guest].fetch_and(!(value >> 1), Ordering::SeqCst); }
+                _ => {}
+            }
+        }
+        _ => {
+            let cause = if cause == SCAUSE_STORE_PAGE_FAULT { SCAUSE_STORE_ACCESS_FAULT } else { SCAUSE_LOAD_ACCESS_FAULT };
+            trap::forward_exception(state, cause, csrr!(sepc));
+            return true;
+        }
+    }
+    riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
+    true
+}
+
+/// Raise the shared memory interrupt of the guest that `state` belongs to if its doorbell was rung.
+/// Called on timer and software interrupts, and returns whether the interrupt was made pending.
+pub fn poll_doorbell(state: &Context, guest: &mut Guest) -> bool {
+    if !SHARED_STATICS.shmem.rung[state.guest_index].swap(false, Ordering::SeqCst) {
+        return false;
+    }
+    guest.plic.set_pending(IRQ, true);
+    true
+}
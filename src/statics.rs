@@ -1,8 +1,19 @@
 use arr_macro::arr;
-use core::sync::atomic::AtomicBool;
+use arrayvec::ArrayVec;
+use core::sync::atomic::{AtomicBool, AtomicU64};
 use spin::Mutex;
+use crate::console_log::ConsoleLog;
 use crate::constants::*;
+use crate::context::Guest;
+use crate::drivers::partition::HostDisk;
+use crate::drivers::rng::Entropy;
+use crate::drivers::switch::Switch;
+use crate::gdbstub::GdbStub;
+use crate::management::GuestControl;
+use crate::memory_region::MemoryRegion;
+use crate::monitor::VcpuQuery;
 use crate::print::{self, UartWriter};
+use crate::shmem::SharedMemory;
 use crate::pmap;
 
 #[derive(Copy, Clone, Debug)]
@@ -12,7 +23,6 @@ pub enum IpiReason {
         a1: u64,
         a2: u64,
         a3: u64,
-        a4: u64,
         sp: u64,
         satp: u64,
     }
@@ -24,6 +34,45 @@ pub struct Shared {
     pub ipi_reason_array: [Mutex<Option<IpiReason>>; MAX_HOST_HARTS],
     pub uart_writer: Mutex<UartWriter>,
     pub hart_lottery: AtomicBool,
+    pub vcpu_assignments: [Mutex<Option<ArrayVec<[VcpuAssignment; MAX_HART_VCPUS]>>>; MAX_HOST_HARTS],
+    pub guests: [Mutex<Option<Guest>>; MAX_GUESTS],
+    /// Virtual Ethernet switch between guests. Taken after the lock of any guest.
+    pub switch: Mutex<Switch>,
+    /// Disk image for the emulated block device, until the first guest claims it.
+    pub ram_disk: Mutex<Option<MemoryRegion>>,
+    /// Host block device split between the guests. Taken after the lock of any guest.
+    pub shared_disk: Mutex<Option<HostDisk>>,
+    /// Random number generator behind every guest's entropy device. Taken after the lock of any
+    /// guest.
+    pub entropy: Mutex<Entropy>,
+    /// State of each guest that the management interface reads and changes without taking the
+    /// guest's lock.
+    pub guest_controls: [GuestControl; MAX_GUESTS],
+    /// Memory window mapped into every guest, and the doorbells that go with it.
+    pub shmem: SharedMemory,
+    /// Debugger attached over the host console, if any.
+    pub gdb: GdbStub,
+    /// Question from the monitor about a vCPU, for the hart running it to answer. Taken after any
+    /// other lock.
+    pub monitor_query: VcpuQuery,
+    /// Recent console output of each guest. Taken after the console lock.
+    pub console_log: Mutex<ConsoleLog>,
+    /// Mask of the host harts that keep a trace.
+    pub trace_harts: AtomicU64,
+    /// Mask of the host harts that have been asked to print their trace.
+    pub trace_dumps: AtomicU64,
+}
+
+/// A vCPU that the boot hart has assigned to another hart.
+#[derive(Copy, Clone)]
+pub struct VcpuAssignment {
+    /// Guest the vCPU belongs to, or u64::max_value() if it is the only guest.
+    pub guestid: u64,
+    pub vcpu: u64,
+    /// Total number of vCPUs in the guest.
+    pub vcpus: u64,
+    /// Start of the hypervisor reservation for this vCPU.
+    pub hart_base_pa: u64,
 }
 
 pub struct ConditionalPointer(u64);
@@ -34,6 +83,19 @@ pub struct ConditionalPointer(u64);
 #[cfg(not(feature = "physical_symbol_addresses"))]
 pub const SHARED_STATICS: ConditionalPointer = ConditionalPointer(SUPERVISOR_SHARED_STATIC_ADDRESS);
 
+impl ConditionalPointer {
+    /// Return the state shared by all vCPUs of the guest in slot `index`. Unlike going through
+    /// `Deref`, the lifetime of the result isn't tied to a temporary so it can be stored.
+    pub fn guest(&self, index: usize) -> &'static Mutex<Option<Guest>> {
+        unsafe { &(*(self.0 as *const Shared)).guests[index] }
+    }
+
+    /// Like `guest`, but for the management state of the guest in slot `index`.
+    pub fn guest_control(&self, index: usize) -> &'static GuestControl {
+        unsafe { &(*(self.0 as *const Shared)).guest_controls[index] }
+    }
+}
+
 impl core::ops::Deref for ConditionalPointer {
     type Target = Shared;
 
@@ -63,9 +125,19 @@ pub static __SHARED_STATICS_IMPL: Shared = Shared {
     boot_page_tables: make_boot_page_tables_array(),
     ipi_reason_array: arr![Mutex::new(None); 16],
     // see also: print::early_guess_uart
-    uart_writer: Mutex::new(UartWriter {
-        pa: 0x10000000,
-        inner: print::UartWriterInner::Ns16550a { initialized: false },
-    }),
+    uart_writer: Mutex::new(UartWriter::new(0x10000000, print::UartWriterInner::Ns16550a { initialized: false })),
     hart_lottery: AtomicBool::new(true),
+    vcpu_assignments: arr![Mutex::new(None); 16],
+    guests: arr![Mutex::new(None); 8],
+    switch: Mutex::new(Switch::new()),
+    ram_disk: Mutex::new(None),
+    shared_disk: Mutex::new(None),
+    entropy: Mutex::new(Entropy::new()),
+    guest_controls: arr![GuestControl::new(); 8],
+    shmem: SharedMemory::new(),
+    gdb: GdbStub::new(),
+    monitor_query: VcpuQuery::new(),
+    console_log: Mutex::new(ConsoleLog::new()),
+    trace_harts: AtomicU64::new(0),
+    trace_dumps: AtomicU64::new(0),
 };
@@ -10,11 +10,19 @@
 #![feature(start)]
 #![feature(try_blocks)]
 
+use arrayvec::ArrayVec;
 use rvirt::*;
+use rvirt::context::HostClint;
+use rvirt::drivers::partition::HostDisk;
+use rvirt::memory_region::MemoryRegion;
 
 // mandatory rust environment setup
 #[lang = "eh_personality"] extern fn eh_personality() {}
-#[panic_handler] fn panic(info: &::core::panic::PanicInfo) -> ! { println!("{}", info); loop {}}
+#[panic_handler] fn panic(info: &::core::panic::PanicInfo) -> ! {
+    println!("{}", info);
+    console_log::dump_all();
+    loop {}
+}
 #[start] fn start(_argc: isize, _argv: *const *const u8) -> isize {0}
 #[no_mangle] fn abort() -> ! { println!("Abort!"); loop {}}
 
@@ -28,6 +36,14 @@ static GUEST_KERNEL: [u8; include_bytes!(env!("RVIRT_GUEST_KERNEL")).len()] =
 #[cfg(not(feature = "embed_guest_kernel"))]
 static GUEST_KERNEL: [u8; 0] = [];
 
+#[link_section = ".initrd"]
+#[cfg(feature = "embed_guest_disk")]
+static GUEST_DISK: [u8; include_bytes!(env!("RVIRT_GUEST_DISK")).len()] =
+    *include_bytes!(env!("RVIRT_GUEST_DISK"));
+
+#[cfg(not(feature = "embed_guest_disk"))]
+static GUEST_DISK: [u8; 0] = [];
+
 global_asm!(include_str!("scode.S"));
 
 extern {
@@ -64,10 +80,15 @@ unsafe fn sstart2(hartid: u64, device_tree_blob: u64, shared_segments_shift: u64
         SHARED_STATICS.uart_writer.lock().init(machine.uart_address, ty);
     }
 
+    // When the guest kernel is embedded, an init RAM disk holds the image for the guest's block
+    // device instead, unless that is embedded too.
+    let initrd_is_disk = cfg!(feature = "embed_guest_kernel") && !cfg!(feature = "embed_guest_disk")
+        && machine.initrd_start != machine.initrd_end;
+
     // Do some sanity checks now that the UART is initialized and we have a better chance of
     // successfully printing output.
     assert!(machine.initrd_end <= machine.physical_memory_offset + pmap::HART_SEGMENT_SIZE);
-    assert!(machine.initrd_end - machine.initrd_start <= pmap::HEAP_SIZE);
+    assert!(initrd_is_disk || machine.initrd_end - machine.initrd_start <= pmap::HEAP_SIZE);
     assert!(machine.harts.iter().any(|h| h.hartid == hartid));
     if !cfg!(feature = "embed_guest_kernel") && machine.initrd_end == 0 {
         println!("WARN: No guest kernel provided. Make sure to pass one with `-initrd or compile with --features embed_guest_kernel`");
@@ -86,55 +107,158 @@ unsafe fn sstart2(hartid: u64, device_tree_blob: u64, shared_segments_shift: u64
     if !single_hart {
         guest_harts.retain(|h| h.hartid != hartid);
     }
-    let single_guest = guest_harts.len() == 1;
     assert!(guest_harts.len() != 0);
 
-    assert!(1 + guest_harts.len() as u64 <= (machine.physical_memory_size >> 30));
+    // Unless told otherwise, run enough guests to give every hart one vCPU. Any extra vCPUs are
+    // time-sliced with the others.
+    let vcpus = (machine.guest_vcpus as usize).max(1).min(constants::MAX_GUEST_HARTS);
+    let num_guests = match machine.guests {
+        0 => (guest_harts.len() + vcpus - 1) / vcpus,
+        n => n as usize,
+    };
+    let single_guest = num_guests == 1;
+
+    assert!(1 + num_guests as u64 <= (machine.physical_memory_size >> 30));
+    assert!(1 + num_guests as u64 <= pmap::DIRECT_MAP_PAGES);
+    assert!(num_guests <= constants::MAX_GUESTS);
+    assert!(num_guests * vcpus <= guest_harts.len() * constants::MAX_HART_VCPUS);
+
+    // The memory window shared between guests is taken from the end of memory.
+    let guests_end = machine.physical_memory_offset + pmap::HART_SEGMENT_SIZE * (num_guests as u64 + 1);
+    let mut memory_end = (machine.physical_memory_offset + machine.physical_memory_size)
+        .min(pmap::DIRECT_MAP_PAGES << 30);
+    if machine.shmem_size != 0 {
+        let size = (machine.shmem_size << 20).min(shmem::MAX_WINDOW_SIZE);
+        if memory_end - guests_end < size {
+            println!("WARN: Not enough memory for a {} MB shared memory window", size >> 20);
+        } else {
+            memory_end -= size;
+            core::ptr::write_bytes(pa2va(memory_end) as *mut u8, 0, size as usize);
+            SHARED_STATICS.shmem.set_window(memory_end, size);
+        }
+    }
 
-    let mut guestid = 1;
-    for hart in guest_harts {
-        let hart_base_pa = machine.physical_memory_offset + pmap::HART_SEGMENT_SIZE * guestid;
+    // Copy the disk image into the memory following the last guest's segment, where the guest can
+    // write to it without disturbing anything else.
+    let disk_image: Option<&[u8]> = if cfg!(feature = "embed_guest_disk") {
+        Some(&GUEST_DISK[..])
+    } else if initrd_is_disk {
+        Some(core::slice::from_raw_parts(pa2va(machine.initrd_start) as *const u8,
+                                         (machine.initrd_end - machine.initrd_start) as usize))
+    } else {
+        None
+    };
+    if let Some(image) = disk_image {
+        let disk_pa = guests_end;
+        let disk_len = (image.len() as u64 + 511) & !511;
+        if disk_pa + disk_len > memory_end {
+            println!("WARN: Not enough memory for the {} byte guest disk image", image.len());
+        } else {
+            core::ptr::copy(image.as_ptr(), pa2va(disk_pa) as *mut u8, image.len());
+            core::ptr::write_bytes(pa2va(disk_pa + image.len() as u64) as *mut u8, 0,
+                                   (disk_len - image.len() as u64) as usize);
+            *SHARED_STATICS.ram_disk.lock() = Some(MemoryRegion::with_base_address(pa2va(disk_pa), 0, disk_len));
+        }
+    }
+
+    SHARED_STATICS.entropy.lock().seed(&machine);
+    SHARED_STATICS.console_log.lock().set_clock(HostClint::new(machine.clint_address));
 
-        let mut irq_mask = 0;
-        for j in 0..4 {
-            let index = ((guestid-1) * 4 + j) as usize;
-            if index < machine.virtio.len() {
-                let irq = machine.virtio[index].irq;
-                assert!(irq < 32);
-                irq_mask |= 1u32 << irq;
+    match machine.gdb_guest {
+        0 => {}
+        n if n as usize <= num_guests => SHARED_STATICS.gdb.set_target(n as usize - 1),
+        n => println!("WARN: No guest {} for the GDB stub to debug", n),
+    }
+
+    // Take over the host disk that the guests share before any of them are created. It is driven
+    // through the second half of the first guest's DMA region.
+    let mut shared_disk = None;
+    if machine.shared_disk || machine.disk_overlay {
+        let dma = machine.physical_memory_offset + pmap::HART_SEGMENT_SIZE * 2 - pmap::DMA_SIZE / 2;
+        match HostDisk::new(&machine, dma, num_guests) {
+            Some(disk) => {
+                shared_disk = Some(disk.index());
+                *SHARED_STATICS.shared_disk.lock() = Some(disk);
             }
+            None => println!("WARN: No virtio-blk device to share between guests"),
         }
+    }
+
+    // vCPUs are dealt out to harts round-robin. Within each guest's segment, the hypervisor
+    // reservation for each vCPU comes first, followed by guest memory.
+    for (h, hart) in guest_harts.iter().enumerate() {
+        let mut assignments = ArrayVec::<[VcpuAssignment; constants::MAX_HART_VCPUS]>::new();
+        let mut irq_mask = 0u64;
+        for j in (h..num_guests * vcpus).step_by(guest_harts.len()) {
+            let (guest, vcpu) = ((j / vcpus) as u64, (j % vcpus) as u64);
+            let guest_base_pa = machine.physical_memory_offset + pmap::HART_SEGMENT_SIZE * (guest + 1);
+            let hart_base_pa = guest_base_pa + pmap::VM_RESERVATION_SIZE * vcpu;
+
+            // Host interrupts for the guest's devices are only delivered to its first vCPU.
+            if vcpu == 0 {
+                for j in 0..4 {
+                    let index = (guest * 4 + j) as usize;
+                    if index < machine.virtio.len() && Some(index) != shared_disk {
+                        let irq = machine.virtio[index].irq;
+                        assert!(irq < 64);
+                        irq_mask |= 1 << irq;
+                    }
+                }
+
+                // The Ethernet controller is driven on behalf of the first guest.
+                if let (0, Some(macb)) = (guest, &machine.macb) {
+                    assert!(macb.irq < 64);
+                    irq_mask |= 1 << macb.irq;
+                }
+
+                if machine.initrd_start == machine.initrd_end || initrd_is_disk {
+                    core::ptr::copy(&GUEST_KERNEL as *const _ as *const u8,
+                                    pa2va(hart_base_pa + pmap::HEAP_OFFSET) as *mut u8,
+                                    GUEST_KERNEL.len());
+                } else {
+                    core::ptr::copy(pa2va(machine.initrd_start) as *const u8,
+                                    pa2va(hart_base_pa + pmap::HEAP_OFFSET) as *mut u8,
+                                    (machine.initrd_end - machine.initrd_start) as usize);
+                }
+            }
+
+            assignments.push(VcpuAssignment {
+                guestid: if !single_guest { guest + 1 } else { u64::max_value() },
+                vcpu,
+                vcpus: vcpus as u64,
+                hart_base_pa,
+            });
+        }
+
+        if assignments.is_empty() {
+            continue;
+        }
+
+        // The hart's own data and stack live in the reservation of the first vCPU it runs.
+        let host_base_pa = assignments[0].hart_base_pa;
 
         *(pa2va(machine.plic_address + 0x200000 + 0x1000 * hart.plic_context) as *mut u32) = 0;
-        *(pa2va(machine.plic_address + 0x2000 + 0x80 * hart.plic_context) as *mut u32) = irq_mask;
-        *(pa2va(machine.plic_address + 0x2000 + 0x80 * hart.plic_context + 4) as *mut u32) = 0;
+        *(pa2va(machine.plic_address + 0x2000 + 0x80 * hart.plic_context) as *mut u32) = irq_mask as u32;
+        *(pa2va(machine.plic_address + 0x2000 + 0x80 * hart.plic_context + 4) as *mut u32) = (irq_mask >> 32) as u32;
 
-        (*(pa2va(hart_base_pa) as *mut [u64; 1024])) = pmap::make_boot_page_table(hart_base_pa);
+        (*(pa2va(host_base_pa) as *mut [u64; 1024])) = pmap::make_boot_page_table(host_base_pa);
         for i in 512..1024 {
-            *(pa2va(hart_base_pa + i * 8) as *mut u64) += shared_segments_shift >> 2;
+            *(pa2va(host_base_pa + i * 8) as *mut u64) += shared_segments_shift >> 2;
         }
 
         core::ptr::copy(pa2va(device_tree_blob) as *const u8,
-                        pa2va(hart_base_pa + 4096*2) as *mut u8,
+                        pa2va(host_base_pa + 4096*2) as *mut u8,
                         fdt.total_size() as usize);
-        if machine.initrd_start == machine.initrd_end {
-            core::ptr::copy(&GUEST_KERNEL as *const _ as *const u8,
-                            pa2va(hart_base_pa + pmap::HEAP_OFFSET) as *mut u8,
-                            GUEST_KERNEL.len());
-        } else {
-            core::ptr::copy(pa2va(machine.initrd_start) as *const u8,
-                            pa2va(hart_base_pa + pmap::HEAP_OFFSET) as *mut u8,
-                            (machine.initrd_end - machine.initrd_start) as usize);
-        }
+
+        *SHARED_STATICS.vcpu_assignments[hart.hartid as usize].lock() = Some(assignments);
 
         let reason = IpiReason::TriggerHartEntry {
             a0: hart.hartid,
-            a1: hart_base_pa + 4096*2,
+            a1: host_base_pa + 4096*2,
             a2: shared_segments_shift,
-            a3: hart_base_pa,
-            a4: if !single_guest { guestid as u64 } else { u64::max_value() },
-            sp: hart_base_pa + (4<<20) + pmap::DIRECT_MAP_OFFSET,
-            satp: 8 << 60 | (hart_base_pa >> 12),
+            a3: host_base_pa,
+            sp: host_base_pa + (4<<20) + pmap::DIRECT_MAP_OFFSET,
+            satp: 8 << 60 | (host_base_pa >> 12),
         };
 
         *SHARED_STATICS.ipi_reason_array[hart.hartid as usize].lock() = Some(reason);
@@ -143,8 +267,6 @@ unsafe fn sstart2(hartid: u64, device_tree_blob: u64, shared_segments_shift: u64
         } else {
             riscv::sbi::send_ipi_to_hart(hart.hartid);
         }
-
-        guestid += 1;
     }
 
     loop {}
@@ -153,10 +275,10 @@ unsafe fn sstart2(hartid: u64, device_tree_blob: u64, shared_segments_shift: u64
 #[no_mangle]
 unsafe fn hart_entry2(hartid: u64) {
     let reason = { SHARED_STATICS.ipi_reason_array.get_unchecked(hartid as usize).lock().take() };
-    if let Some(IpiReason::TriggerHartEntry { a0, a1, a2, a3, a4, sp, satp }) = reason {
+    if let Some(IpiReason::TriggerHartEntry { a0, a1, a2, a3, sp, satp }) = reason {
         csrw!(sie, 0x222);
         csrw!(satp, satp);
-        hart_entry3(a0, a1, a2, a3, a4, sp);
+        hart_entry3(a0, a1, a2, a3, sp);
     } else {
         unreachable!();
     }
@@ -166,25 +288,19 @@ unsafe fn hart_entry2(hartid: u64) {
 #[no_mangle]
 #[inline(never)]
 unsafe fn hart_entry3(_hartid: u64, _device_tree_blob: u64, _shared_segments_shift: u64,
-                      _hart_base_pa: u64, _guestid: u64, _stack_pointer: u64) {
-    asm!("mv sp, a5
+                      _host_base_pa: u64, _stack_pointer: u64) {
+    asm!("mv sp, a4
           j hart_entry4" :::: "volatile");
 }
 
 #[no_mangle]
-unsafe fn hart_entry4(hartid: u64, device_tree_blob: u64, shared_segments_shift: u64,
-                      hart_base_pa: u64, guestid: u64) {
+unsafe fn hart_entry4(hartid: u64, device_tree_blob: u64, shared_segments_shift: u64, host_base_pa: u64) {
     csrw!(stvec, trap::strap_entry as *const () as u64);
     csrw!(sie, 0x222);
     csrs!(sstatus, riscv::bits::STATUS_SUM);
     csrc!(sstatus, riscv::bits::STATUS_SPP);
     riscv::sbi::clear_ipi();
-
-    let guestid = if guestid == u64::max_value() {
-        None
-    } else {
-        Some(guestid)
-    };
+    trace::init(hartid);
 
     // Read and process host FDT.
     let mut fdt = Fdt::new(pa2va(device_tree_blob));
@@ -192,35 +308,59 @@ unsafe fn hart_entry4(hartid: u64, device_tree_blob: u64, shared_segments_shift:
     assert!(fdt.version() >= 17 && fdt.last_comp_version() <= 17);
     let machine = fdt.parse();
 
-    // Initialize memory subsystem.
-    let (shadow_page_tables, guest_memory, guest_shift) =
-        pmap::init(hart_base_pa, shared_segments_shift, &machine);
+    // Bring up the first vCPU of every guest before any of the others, since those have to wait
+    // for the guest to be loaded. This way harts never end up waiting on each other.
+    let mut assignments = SHARED_STATICS.vcpu_assignments[hartid as usize].lock().take().unwrap();
+    assignments.sort_unstable_by_key(|a| a.vcpu);
 
-    // Load guest binary
-    let (entry, max_addr) = sum::access_user_memory(||{
-        elf::load_elf(pa2va(hart_base_pa + pmap::HEAP_OFFSET) as *const u8,
-                      machine.physical_memory_offset as *mut u8)
-    });
-    let guest_dtb = (max_addr | 0x1fffff) + 1;
-    csrw!(sepc, entry);
+    let mut contexts = ArrayVec::<[context::Context; constants::MAX_HART_VCPUS]>::new();
+    for assignment in &assignments {
+        let guestid = if assignment.guestid == u64::max_value() {
+            None
+        } else {
+            Some(assignment.guestid)
+        };
 
-    // Load guest FDT.
-    let guest_machine = sum::access_user_memory(||{
-        core::ptr::copy(GUEST_DTB.as_ptr(),
-                        guest_dtb as *mut u8,
-                        GUEST_DTB.len());
-        let mut guest_fdt = Fdt::new(guest_dtb);
-        guest_fdt.initialize_guest(guest_memory.len(), &machine.bootargs);
-        guest_fdt.parse()
-    });
+        // Initialize memory subsystem.
+        let guest_base_pa = assignment.hart_base_pa - pmap::VM_RESERVATION_SIZE * assignment.vcpu;
+        let guest_memory_pa = guest_base_pa + pmap::VM_RESERVATION_SIZE * assignment.vcpus;
+        let overlay_size = if machine.disk_overlay { pmap::OVERLAY_SIZE } else { 0 };
+        let (shadow_page_tables, guest_memory, guest_shift) =
+            pmap::init(host_base_pa, assignment.hart_base_pa, guest_memory_pa,
+                       pmap::HART_SEGMENT_SIZE - pmap::VM_RESERVATION_SIZE * assignment.vcpus - pmap::DMA_SIZE - overlay_size,
+                       shared_segments_shift, &machine);
+
+        let (entry, guest_dtb) = if assignment.vcpu == 0 {
+            // Load guest binary and FDT.
+            let image = assignment.hart_base_pa + pmap::HEAP_OFFSET;
+            let (entry, guest_dtb, guest_machine) =
+                context::load_guest(image, GUEST_DTB, &guest_memory, guest_shift, &machine.bootargs, assignment.vcpus);
+
+            let dma = guest_base_pa + pmap::HART_SEGMENT_SIZE - pmap::DMA_SIZE;
+            context::create_guest(&machine, &guest_machine, guestid, assignment.vcpus, entry, guest_dtb,
+                                  image, GUEST_DTB, dma);
+            (entry, guest_dtb)
+        } else {
+            // Wait for the first vCPU to load the guest.
+            let guest = SHARED_STATICS.guest(guestid.unwrap_or(1) as usize - 1);
+            loop {
+                if let Some(ref guest) = *guest.lock() {
+                    break (guest.entry, guest.dtb);
+                }
+            }
+        };
 
-    // Initialize context
-    context::initialize(&machine, &guest_machine, shadow_page_tables, guest_memory, guest_shift, hartid, guestid);
+        contexts.push(context::create_context(&machine, shadow_page_tables, guest_memory, guest_shift,
+                                              hartid, guestid, assignment.vcpu, entry, guest_dtb));
+    }
 
-    // Jump into the guest kernel.
-    asm!("mv a1, $0 // dtb = guest_dtb
+    // Start running the first context. Only the first vCPU of each guest enters the kernel directly,
+    // so on other harts this waits until the guest brings up one of the vCPUs assigned here.
+    let (entry, a0, a1) = scheduler::initialize(contexts, machine.time_slice);
+    csrw!(sepc, entry);
 
-          li ra, 0
+    // Jump into the guest kernel.
+    asm!("li ra, 0
           li sp, 0
           li gp, 0
           li tp, 0
@@ -229,7 +369,6 @@ unsafe fn hart_entry4(hartid: u64, device_tree_blob: u64, shared_segments_shift:
           li t2, 0
           li s0, 0
           li s1, 0
-          li a0, 0  // hartid = 0
           li a2, 0
           li a3, 0
           li a4, 0
@@ -250,7 +389,7 @@ unsafe fn hart_entry4(hartid: u64, device_tree_blob: u64, shared_segments_shift:
           li t4, 0
           li t5, 0
           li t6, 0
-          sret" :: "r"(guest_dtb) : "memory" : "volatile");
+          sret" :: "{a0}"(a0), "{a1}"(a1) : "memory" : "volatile");
 
     unreachable!();
 }
@@ -0,0 +1,255 @@
+//! Trace of what the hypervisor did on each host hart, for working out after the fact how a guest
+//! ended up where it did. Only built with the `trace` feature; without it, every function here does
+//! nothing.
+//!
+//! Each hart keeps the last RING_ENTRIES events in a ring in its own data segment, written only by
+//! that hart from within the trap handler, so no locks are needed. The monitor's `trace` command
+//! asks every hart to print its ring, which each does the next time it traps, as:
+//!
+//! ```text
+//!  rvirt-trace-begin hart=<hartid> events=<count>
+//!  <one line of 80 hex digits per event, oldest first>
+//!  rvirt-trace-end hart=<hartid>
+//! ```
+//!
+//! Each line holds the bytes of a 40 byte event, in order:
+//!
+//! ```text
+//!  OFFSET  SIZE  FIELD
+//!  0x00    8     time    value of the time CSR when the event was recorded
+//!  0x08    1     kind    what happened, see below
+//!  0x09    1     guest   id of the guest
+//!  0x0a    1     vcpu    index of the vCPU within the guest
+//!  0x0b    1     path    for traps, how the trap was handled, see below; otherwise 0
+//!  0x0c    4     insn    for traps, the instruction at sepc if it was loaded; otherwise 0
+//!  0x10    8     arg0
+//!  0x18    8     arg1
+//!  0x20    8     arg2
+//! ```
+//!
+//! Multi-byte fields are little-endian. The kinds of event and their arguments are:
+//!
+//! ```text
+//!  KIND  EVENT                 ARG0              ARG1                ARG2
+//!  1     trap                  scause            sepc                stval
+//!  2     interrupt forwarded   scause for guest  interrupted pc      guest handler
+//!  3     exception forwarded   scause for guest  sepc for guest      stval for guest
+//!  4     shadow table flush    first address     end of range        0
+//!  5     virtio notification   device index      queue index         0
+//! ```
+//!
+//! A flush of the whole shadow page table is recorded with the range 0 to 0xffffffffffffffff.
+//! Traps are handled along one of these paths:
+//!
+//! ```text
+//!  1  host interrupt
+//!  2  page fault handled by the hypervisor
+//!  3  instruction emulated by the hypervisor
+//!  4  SBI call
+//!  5  breakpoint taken by the GDB stub
+//!  6  forwarded to the guest
+//! ```
+
+use core::sync::atomic::Ordering;
+use crate::context::Context;
+use crate::statics::SHARED_STATICS;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[repr(u8)]
+pub enum Path {
+    Interrupt = 1,
+    PageFault = 2,
+    Emulated = 3,
+    SbiCall = 4,
+    Breakpoint = 5,
+    Forwarded = 6,
+}
+
+#[cfg(feature = "trace")]
+mod ring {
+    use core::cell::UnsafeCell;
+    use core::fmt::Write;
+    use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use crate::context::Context;
+    use crate::statics::SHARED_STATICS;
+
+    pub const KIND_TRAP: u8 = 1;
+    pub const KIND_INTERRUPT: u8 = 2;
+    pub const KIND_EXCEPTION: u8 = 3;
+    pub const KIND_FLUSH: u8 = 4;
+    pub const KIND_VIRTIO_NOTIFY: u8 = 5;
+
+    /// Number of events kept by each hart.
+    const RING_ENTRIES: usize = 2048;
+
+    #[derive(Copy, Clone)]
+    struct Event {
+        time: u64,
+        kind: u8,
+        guest: u8,
+        vcpu: u8,
+        path: u8,
+        instruction: u32,
+        args: [u64; 3],
+    }
+
+    const EMPTY: Event = Event { time: 0, kind: 0, guest: 0, vcpu: 0, path: 0, instruction: 0, args: [0; 3] };
+
+    struct Ring {
+        events: UnsafeCell<[Event; RING_ENTRIES]>,
+        /// Total number of events ever recorded, so the next goes at `count % RING_ENTRIES`.
+        count: AtomicUsize,
+        /// Index of the most recent trap, whose path is filled in once it has been handled.
+        last_trap: AtomicUsize,
+        /// Id of the hart this copy of the ring belongs to.
+        hartid: AtomicU64,
+    }
+
+    // Each hart has its own copy of the ring, which only it ever touches.
+    unsafe impl Sync for Ring {}
+
+    static RING: Ring = Ring {
+        events: UnsafeCell::new([EMPTY; RING_ENTRIES]),
+        count: AtomicUsize::new(0),
+        last_trap: AtomicUsize::new(0),
+        hartid: AtomicU64::new(0),
+    };
+
+    pub fn set_hartid(hartid: u64) {
+        RING.hartid.store(hartid, Ordering::Relaxed);
+    }
+
+    pub fn hartid() -> u64 {
+        RING.hartid.load(Ordering::Relaxed)
+    }
+
+    pub fn record(state: &Context, kind: u8, instruction: u32, args: [u64; 3]) -> usize {
+        let index = RING.count.fetch_add(1, Ordering::Relaxed) % RING_ENTRIES;
+        let event = Event {
+            time: csrr!(time),
+            kind,
+            guest: state.guest_index as u8 + 1,
+            vcpu: state.vcpu as u8,
+            path: 0,
+            instruction,
+            args,
+        };
+        unsafe { (*RING.events.get())[index] = event; }
+        index
+    }
+
+    pub fn set_last_trap(index: usize) {
+        RING.last_trap.store(index, Ordering::Relaxed);
+    }
+
+    pub fn set_path(path: u8) {
+        let index = RING.last_trap.load(Ordering::Relaxed);
+        unsafe { (*RING.events.get())[index].path = path; }
+    }
+
+    /// Print the ring of this hart, which is `hartid`.
+    pub fn dump(hartid: u64) {
+        let count = RING.count.load(Ordering::Relaxed);
+        let events = count.min(RING_ENTRIES);
+        let mut writer = SHARED_STATICS.uart_writer.lock();
+        let _ = writeln!(writer, "rvirt-trace-begin hart={} events={}", hartid, events);
+        for i in count - events..count {
+            let event = unsafe { &(*RING.events.get())[i % RING_ENTRIES] };
+            let mut bytes = [0u8; 40];
+            bytes[0..8].copy_from_slice(&event.time.to_le_bytes());
+            bytes[8] = event.kind;
+            bytes[9] = event.guest;
+            bytes[10] = event.vcpu;
+            bytes[11] = event.path;
+            bytes[12..16].copy_from_slice(&event.instruction.to_le_bytes());
+            for (j, arg) in event.args.iter().enumerate() {
+                bytes[16 + j * 8..24 + j * 8].copy_from_slice(&arg.to_le_bytes());
+            }
+            for byte in bytes.iter() {
+                let _ = write!(writer, "{:02x}", byte);
+            }
+            let _ = writeln!(writer);
+        }
+        let _ = writeln!(writer, "rvirt-trace-end hart={}", hartid);
+    }
+}
+
+/// Whether tracing was compiled in.
+pub const ENABLED: bool = cfg!(feature = "trace");
+
+/// Start keeping a trace on this hart, which is `hartid`. Called as each hart starts running vCPUs.
+#[inline(always)]
+pub fn init(_hartid: u64) {
+    #[cfg(feature = "trace")]
+    {
+        ring::set_hartid(_hartid);
+        SHARED_STATICS.trace_harts.fetch_or(1 << _hartid, Ordering::SeqCst);
+    }
+}
+
+/// Record a trap out of the vCPU of `state`, whose cause is `scause`. Called before it is handled,
+/// while `sepc` and `stval` still hold the values the trap set.
+#[inline(always)]
+pub fn trap(_state: &Context, _scause: u64, _instruction: Option<u32>) {
+    #[cfg(feature = "trace")]
+    ring::set_last_trap(ring::record(_state, ring::KIND_TRAP, _instruction.unwrap_or(0),
+                                     [_scause, csrr!(sepc), csrr!(stval)]));
+}
+
+/// Record how the last trap was handled.
+#[inline(always)]
+pub fn trap_handled(_path: Path) {
+    #[cfg(feature = "trace")]
+    ring::set_path(_path as u8);
+}
+
+/// Record an interrupt with cause `scause` delivered to the guest at `pc`, which was interrupted
+/// at `sepc`.
+#[inline(always)]
+pub fn interrupt_forwarded(_state: &Context, _scause: u64, _sepc: u64, _pc: u64) {
+    #[cfg(feature = "trace")]
+    ring::record(_state, ring::KIND_INTERRUPT, 0, [_scause, _sepc, _pc]);
+}
+
+/// Record an exception delivered to the guest, from the CSRs that were set for it.
+#[inline(always)]
+pub fn exception_forwarded(_state: &Context) {
+    #[cfg(feature = "trace")]
+    ring::record(_state, ring::KIND_EXCEPTION, 0, [_state.csrs.scause, _state.csrs.sepc, _state.csrs.stval]);
+}
+
+/// Record a flush of shadow mappings for guest virtual addresses in `start..end`.
+#[inline(always)]
+pub fn flush(_state: &Context, _start: u64, _end: u64) {
+    #[cfg(feature = "trace")]
+    ring::record(_state, ring::KIND_FLUSH, 0, [_start, _end, 0]);
+}
+
+/// Record a store of `queue` to the QueueNotify register of virtio device `device`.
+#[inline(always)]
+pub fn virtio_notify(_state: &Context, _device: usize, _queue: u64) {
+    #[cfg(feature = "trace")]
+    ring::record(_state, ring::KIND_VIRTIO_NOTIFY, 0, [_device as u64, _queue, 0]);
+}
+
+/// Ask every host hart that keeps a trace to print it.
+pub fn request_dump() {
+    let harts = SHARED_STATICS.trace_harts.load(Ordering::SeqCst);
+    SHARED_STATICS.trace_dumps.store(harts, Ordering::SeqCst);
+}
+
+/// Called on every trap. Prints the trace of this hart if it was asked for.
+#[inline(always)]
+pub fn poll() {
+    #[cfg(feature = "trace")]
+    {
+        let trace_dumps = &SHARED_STATICS.trace_dumps;
+        if trace_dumps.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        let hartid = ring::hartid();
+        if trace_dumps.fetch_and(!(1 << hartid), Ordering::SeqCst) & (1 << hartid) != 0 {
+            ring::dump(hartid);
+        }
+    }
+}
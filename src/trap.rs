@@ -1,7 +1,9 @@
 use riscv_decode::Instruction;
-use crate::context::{Context, CONTEXT, IrqMapping};
+use crate::context::*;
 use crate::riscv::bits::*;
-use crate::{pfault, pmap, riscv, sum, virtio};
+use crate::scheduler::{self, RUN_QUEUE};
+use crate::statics::SHARED_STATICS;
+use crate::{console_log, gdbstub, monitor, pfault, pmap, riscv, sbi, shmem, sum, trace, virtio};
 
 pub trait U64Bits {
     fn get(&self, mask: Self) -> bool;
@@ -126,11 +128,13 @@ pub fn strap() {
             println!("reg x{} = {:#x}", i, state.saved_registers.get(i));
         }
 
+        console_log::dump_all();
         loop {}
     }
 
     let mut state = CONTEXT.lock();
     let mut state = (&mut *state).as_mut().unwrap();
+    SHARED_STATICS.guest_control(state.guest_index).count_exit(cause);
 
     // For the processor to have generated a load/store page fault or an illegal instruction fault,
     // the processor must have been able to load the relevant instruction (or else an access fault
@@ -144,21 +148,26 @@ pub fn strap() {
         }
         _ => None,
     };
+    trace::trap(&state, cause, instruction.map(|i| i.0));
 
-    if (cause as isize) < 0 {
+    let path = if (cause as isize) < 0 {
         handle_interrupt(&mut state, cause);
         maybe_forward_interrupt(&mut state, csrr!(sepc));
+        trace::Path::Interrupt
     } else if cause == SCAUSE_INSN_PAGE_FAULT || cause == SCAUSE_LOAD_PAGE_FAULT || cause == SCAUSE_STORE_PAGE_FAULT {
         let pc = csrr!(sepc);
         if pfault::handle_page_fault(&mut state, cause, instruction.map(|i|i.0)) {
             maybe_forward_interrupt(&mut state, pc);
+            trace::Path::PageFault
         } else {
             forward_exception(&mut state, cause, pc);
+            trace::Path::Forwarded
         }
     } else if cause == SCAUSE_ILLEGAL_INSN && state.smode {
         let pc = csrr!(sepc);
         let (instruction, len) = instruction.unwrap();
         let mut advance_pc = true;
+        let mut path = trace::Path::Emulated;
         match riscv_decode::decode(instruction).ok() {
             Some(Instruction::Sret) => {
                 if !state.csrs.sstatus.get(STATUS_SIE) && state.csrs.sstatus.get(STATUS_SPIE) {
@@ -212,16 +221,18 @@ pub fn strap() {
                 }
                 state.saved_registers.set(i.rd(), prev);
             }
-            Some(Instruction::Wfi) => {}
+            Some(Instruction::Wfi) => scheduler::yield_hart(),
             Some(decoded) => {
                 println!("Unrecognized instruction! {:?} @ pc={:#x}", decoded, pc);
                 forward_exception(&mut state, cause, pc);
                 advance_pc = false;
+                path = trace::Path::Forwarded;
             }
             None => {
                 println!("Unrecognized instruction {:#x} @ pc={:#x}", instruction, pc);
                 forward_exception(&mut state, cause, pc);
                 advance_pc = false;
+                path = trace::Path::Forwarded;
             }
         }
 
@@ -229,59 +240,96 @@ pub fn strap() {
             riscv::set_sepc(pc + len);
         }
         maybe_forward_interrupt(&mut state, csrr!(sepc));
+        path
     } else if cause == SCAUSE_ENV_CALL && state.smode {
-        match state.saved_registers.get(17) {
-            0 => {
-                state.csrs.sip.set(IP_STIP, false);
-                state.csrs.mtimecmp = state.saved_registers.get(10);
-                riscv::sbi::set_timer(state.csrs.mtimecmp);
-            }
-            1 => {
-                let value = state.saved_registers.get(10) as u8;
-                state.uart.output_byte(value)
-            }
-            5 => riscv::fence_i(),
-            6 | 7 => {
-                // Current versions of the Linux kernel pass wrong arguments to these SBI calls. As
-                // a result, this function ignores the arguments and just does a global fence. This
-                // will eventually be fixed by https://patchwork.kernel.org/patch/10872353.
-                pmap::flush_shadow_page_table(&mut state.shadow_page_tables);
-            }
-            8 => {
-                if let Some(ref mut finisher) = state.test_finisher {
-                    finisher.pass();
-                }
-                loop {}
-            }
-            i => {
-                println!("Got ecall from guest function={}!", i);
-                loop {}
-            }
-        }
+        sbi::handle_ecall(&mut state);
         riscv::set_sepc(csrr!(sepc) + 4);
+        trace::Path::SbiCall
+    } else if cause == SCAUSE_BREAKPOINT && gdbstub::handle_breakpoint(&mut state) {
+        // Hit a breakpoint placed by the debugger rather than by the guest.
+        trace::Path::Breakpoint
     } else {
         if cause != SCAUSE_ENV_CALL { // no need to print anything for guest syscalls...
             println!("Forward exception (cause = {}, smode={})!", cause, state.smode);
         }
         forward_exception(&mut state, cause, csrr!(sepc));
-    }
+        trace::Path::Forwarded
+    };
+    trace::trap_handled(path);
 
+    // If no vCPU on this hart can run because they've all been stopped, wait here until one of them
+    // is started again.
+    loop {
+        if scheduler::maybe_switch(&mut state) {
+            maybe_forward_interrupt(&mut state, csrr!(sepc));
+        }
+        if SHARED_STATICS.guest_control(state.guest_index).take_reboot_request() {
+            sbi::reboot(&mut state);
+        }
+        monitor::poll(&mut state);
+        trace::poll();
+        if scheduler::ensure_running(&mut state) {
+            break;
+        }
+        idle(&mut state);
+    }
+    gdbstub::poll(&mut state);
     state.shadow_page_tables.install_root(state.shadow());
 }
 
+/// Wait for a host interrupt while the current vCPU is stopped and handle it on its behalf.
+fn idle(state: &mut Context) {
+    riscv::wfi();
+    let sip = csrr!(sip);
+    if sip & IP_SSIP != 0 {
+        handle_interrupt(state, 1 << 63 | 0x1);
+    }
+    if sip & IP_STIP != 0 {
+        handle_interrupt(state, 1 << 63 | 0x5);
+    }
+    if sip & IP_SEIP != 0 {
+        handle_interrupt(state, 1 << 63 | 0x9);
+    }
+    scheduler::yield_hart();
+}
+
 fn handle_interrupt(state: &mut Context, cause: u64) {
     let interrupt = cause & 0xff;
     match interrupt {
         0x1 => {
-            // Software interrupt
-            unreachable!();
+            // Software interrupt, sent by another vCPU of this guest, or on behalf of a guest on
+            // this hart by the switch when frames arrive or by another guest ringing its doorbell.
+            riscv::clear_sip(IP_SSIP);
+            handle_vcpu_requests(state);
+
+            let guest = state.guest;
+            let mut guest = guest.lock();
+            let guest = guest.as_mut().unwrap();
+            let doorbell = shmem::poll_doorbell(state, guest);
+            if virtio::poll_devices(&mut state.guest_memory, guest) || doorbell {
+                state.no_interrupt = false;
+                guest.notify_external_interrupts(state.vcpu);
+            }
         }
         0x5 => {
             // Timer interrupt
             let time = state.host_clint.get_mtime();
             let mut next = time + 1_000_000;
+            if let Some(slice_end) = scheduler::timer(time) {
+                next = next.min(slice_end);
+            }
 
-            crate::context::Uart::timer(state, time);
+            let guest = state.guest;
+            let mut guest = guest.lock();
+            let guest = guest.as_mut().unwrap();
+
+            // Give the paravirtual console first pick of host input, if the guest is using it.
+            let console_input = virtio::poll_devices(&mut state.guest_memory, guest);
+            let doorbell = shmem::poll_doorbell(state, guest);
+            if Uart::timer(guest, time) || console_input || doorbell {
+                state.no_interrupt = false;
+                guest.notify_external_interrupts(state.vcpu);
+            }
             if state.csrs.mtimecmp <= time {
                 state.csrs.sip |= IP_STIP;
                 state.no_interrupt = false;
@@ -289,38 +337,23 @@ fn handle_interrupt(state: &mut Context, cause: u64) {
                 next = next.min(state.csrs.mtimecmp);
             }
 
-            if state.uart.next_interrupt_time > time {
-                next = next.min(state.uart.next_interrupt_time);
+            if guest.uart.next_interrupt_time > time {
+                next = next.min(guest.uart.next_interrupt_time);
             }
             riscv::sbi::set_timer(next);
         }
         0x9 => {
             // External
             let host_irq = state.host_plic.claim_and_clear();
-            let guest_irq = state.irq_map[host_irq as usize];
-            match guest_irq {
-                IrqMapping::Virtio { device_index, guest_irq } => {
-                    let forward = match state.virtio.devices[device_index as usize] {
-                        virtio::Device::Passthrough { .. } => true,
-                        virtio::Device::Unmapped => false,
-                        virtio::Device::Macb(ref mut macb) => macb.interrupt(&mut state.guest_memory),
-                    };
-
-                    if forward {
-                        state.plic.set_pending(guest_irq as u32, true);
-
-                        // Guest might have masked out this interrupt
-                        if state.plic.interrupt_pending() {
-                            state.no_interrupt = false;
-                            state.csrs.sip |= IP_SEIP;
-                        } else {
-                            assert_eq!(state.csrs.sip & IP_SEIP, 0);
-                        }
+
+            // The interrupt might belong to a guest whose vCPU is switched out right now.
+            if !forward_host_interrupt(state, host_irq) {
+                for context in RUN_QUEUE.lock().as_mut().unwrap().contexts.iter_mut() {
+                    if forward_host_interrupt(context, host_irq) {
+                        break;
                     }
                 }
-                IrqMapping::Ignored => {}
             }
-
         }
         i => {
             println!("Got interrupt #{}", i);
@@ -329,13 +362,57 @@ fn handle_interrupt(state: &mut Context, cause: u64) {
     }
 }
 
+/// Deliver a host external interrupt to the guest that `state` belongs to. Returns false if the
+/// interrupt isn't for that guest.
+fn forward_host_interrupt(state: &mut Context, host_irq: u32) -> bool {
+    let guest = state.guest;
+    let mut guest = guest.lock();
+    let guest = guest.as_mut().unwrap();
+
+    let guest_irq = guest.irq_map[host_irq as usize];
+    match guest_irq {
+        IrqMapping::Virtio { device_index, guest_irq } => {
+            let forward = match guest.virtio.devices[device_index as usize] {
+                virtio::Device::Passthrough { .. } => {
+                    guest.virtio.devices[device_index as usize].collect_used(&mut state.guest_memory, state.guest_shift);
+                    true
+                }
+                virtio::Device::Unmapped => false,
+                virtio::Device::Macb(ref mut macb) => macb.interrupt(&mut state.guest_memory),
+                virtio::Device::Console(_) => false,
+                virtio::Device::Switch(_) => false,
+                virtio::Device::Blk(_) => false,
+                virtio::Device::Partition(_) => false,
+                virtio::Device::Rng(_) => false,
+                virtio::Device::Vsock(_) => false,
+            };
+
+            if forward {
+                guest.plic.set_pending(guest_irq as u32, true);
+
+                // Guest might have masked out this interrupt, or routed it to another vCPU
+                if guest.plic.interrupt_pending(state.plic_context()) {
+                    state.no_interrupt = false;
+                    state.csrs.sip |= IP_SEIP;
+                }
+                guest.notify_external_interrupts(state.vcpu);
+            }
+            true
+        }
+        IrqMapping::Ignored => false,
+    }
+}
+
 fn maybe_forward_interrupt(state: &mut Context, sepc: u64) {
     if state.no_interrupt {
         return;
     }
 
-    if !state.csrs.sip.get(IP_SEIP) && state.plic.interrupt_pending() {
-        state.csrs.sip.set(IP_SEIP, true);
+    if !state.csrs.sip.get(IP_SEIP) {
+        let guest = state.guest.lock();
+        if guest.as_ref().unwrap().plic.interrupt_pending(state.plic_context()) {
+            state.csrs.sip.set(IP_SEIP, true);
+        }
     }
 
     if (!state.smode || state.csrs.sstatus.get(STATUS_SIE)) && (state.csrs.sie & state.csrs.sip != 0) {
@@ -358,17 +435,119 @@ fn maybe_forward_interrupt(state: &mut Context, sepc: u64) {
         state.csrs.stval = 0;
         state.smode = true;
 
-        match state.csrs.stvec & TVEC_MODE {
-            0 => riscv::set_sepc(state.csrs.stvec & TVEC_BASE),
-            1 => riscv::set_sepc((state.csrs.stvec & TVEC_BASE) + 4 * cause),
+        let pc = match state.csrs.stvec & TVEC_MODE {
+            0 => state.csrs.stvec & TVEC_BASE,
+            1 => (state.csrs.stvec & TVEC_BASE) + 4 * cause,
             _ => unreachable!(),
-        }
+        };
+        riscv::set_sepc(pc);
+        trace::interrupt_forwarded(state, state.csrs.scause, sepc, pc);
     } else {
         state.no_interrupt = true;
     }
 }
 
-fn forward_exception(state: &mut Context, cause: u64, sepc: u64) {
+fn apply_vcpu_requests(state: &mut Context, requests: u32, flush_range: (u64, u64)) {
+    if requests & REQUEST_SOFTWARE_INTERRUPT != 0 {
+        state.csrs.sip.set(IP_SSIP, true);
+        state.no_interrupt = false;
+    }
+    if requests & REQUEST_FENCE_I != 0 {
+        riscv::fence_i();
+    }
+    if requests & REQUEST_FLUSH_SHADOW_PAGE_TABLES != 0 {
+        pmap::flush_shadow_page_table(&mut state.shadow_page_tables);
+        trace::flush(state, 0, u64::max_value());
+    } else if requests & REQUEST_FLUSH_SHADOW_PAGE_TABLE_RANGE != 0 {
+        pmap::flush_shadow_page_table_range(&mut state.shadow_page_tables, flush_range.0, flush_range.1);
+        trace::flush(state, flush_range.0, flush_range.1);
+    }
+}
+
+/// Handle any requests that other vCPUs have made of this one. The guest lock is held until the
+/// requests have been carried out, so senders waiting for the request bits to clear also wait for
+/// completion.
+pub fn handle_vcpu_requests(state: &mut Context) {
+    let guest = state.guest;
+    let mut guest = guest.lock();
+    let vcpu = &mut guest.as_mut().unwrap().vcpus[state.vcpu];
+    if vcpu.requests != 0 {
+        let (requests, flush_range) = vcpu.take_requests();
+        apply_vcpu_requests(state, requests, flush_range);
+    }
+}
+
+/// Record whether the vCPU is scheduled on its host hart. Requests that arrived while it was
+/// switched out are handled as it is switched back in.
+pub fn set_vcpu_running(state: &mut Context, running: bool) {
+    let guest = state.guest;
+    let mut guest = guest.lock();
+    let vcpu = &mut guest.as_mut().unwrap().vcpus[state.vcpu];
+    vcpu.running = running;
+    if running && vcpu.requests != 0 {
+        let (requests, flush_range) = vcpu.take_requests();
+        apply_vcpu_requests(state, requests, flush_range);
+    }
+}
+
+/// Make `request` of every vCPU in `hart_mask`, interrupting the harts they are running on. If
+/// `wait` is set, don't return until all of them have handled it. vCPUs that haven't come online
+/// yet are skipped, and ones that are switched out handle the request when they next run.
+pub fn send_vcpu_requests(state: &mut Context, hart_mask: u64, request: u32, wait: bool) {
+    let mut pending = 0u64;
+    {
+        let mut guest = state.guest.lock();
+        for (i, vcpu) in guest.as_mut().unwrap().vcpus.iter_mut().enumerate() {
+            if i == state.vcpu || !hart_mask.get(1 << i) {
+                continue;
+            }
+            if let Some(hartid) = vcpu.hartid {
+                vcpu.requests |= request;
+                if vcpu.running {
+                    riscv::sbi::send_ipi_to_hart(hartid);
+                    pending |= 1 << i;
+                }
+            }
+        }
+    }
+
+    if hart_mask.get(1 << state.vcpu) {
+        apply_vcpu_requests(state, request, VirtualHart::EMPTY_RANGE);
+    }
+
+    while wait && pending != 0 {
+        // Another vCPU may be waiting on us at the same time, so keep servicing our own requests.
+        handle_vcpu_requests(state);
+
+        let guest = state.guest.lock();
+        for (i, vcpu) in guest.as_ref().unwrap().vcpus.iter().enumerate() {
+            if vcpu.requests & request == 0 || !vcpu.running {
+                pending &= !(1 << i);
+            }
+        }
+    }
+}
+
+/// Have every vCPU in `hart_mask` flush shadow mappings for guest virtual addresses in
+/// `start..end`, and wait for them to do so.
+pub fn send_flush_range_requests(state: &mut Context, hart_mask: u64, start: u64, end: u64) {
+    {
+        let mut guest = state.guest.lock();
+        for (i, vcpu) in guest.as_mut().unwrap().vcpus.iter_mut().enumerate() {
+            if i != state.vcpu && hart_mask.get(1 << i) && vcpu.hartid.is_some() {
+                vcpu.add_flush_range(start, end);
+            }
+        }
+    }
+
+    if hart_mask.get(1 << state.vcpu) {
+        pmap::flush_shadow_page_table_range(&mut state.shadow_page_tables, start, end);
+        trace::flush(state, start, end);
+    }
+    send_vcpu_requests(state, hart_mask & !(1 << state.vcpu), REQUEST_FLUSH_SHADOW_PAGE_TABLE_RANGE, true);
+}
+
+pub fn forward_exception(state: &mut Context, cause: u64, sepc: u64) {
     // println!("||> Forward exception sepc={:#x}", sepc);
     state.csrs.push_sie();
     state.csrs.sepc = sepc;
@@ -377,6 +556,7 @@ fn forward_exception(state: &mut Context, cause: u64, sepc: u64) {
     state.csrs.stval = csrr!(stval);
     state.smode = true;
     riscv::set_sepc(state.csrs.stvec & TVEC_BASE);
+    trace::exception_forwarded(state);
 }
 
 pub unsafe fn load_instruction_at_address(_state: &mut Context, guest_va: u64) -> (u32, u64) {
@@ -1,59 +1,301 @@
+use arrayvec::ArrayVec;
 use byteorder::{NativeEndian, ByteOrder};
 use riscv_decode::Instruction;
-use crate::context::Context;
+use crate::context::{Context, Guest, SavedRegisters, VirtIO, REQUEST_FLUSH_SHADOW_PAGE_TABLES};
 use crate::memory_region::MemoryRegion;
+use crate::drivers::*;
+use crate::drivers::blk::RamDiskDriver;
+use crate::drivers::console::ConsoleDriver;
 use crate::drivers::macb::MacbDriver;
-use crate::{pmap, riscv, drivers};
+use crate::drivers::partition::PartitionDriver;
+use crate::drivers::rng::RngDriver;
+use crate::drivers::switch::SwitchPortDriver;
+use crate::drivers::vsock::VsockDriver;
+use crate::{pmap, riscv, drivers, trace};
 
 pub const MAX_QUEUES: usize = 4;
 pub const MAX_DEVICES: usize = 4;
+/// The available ring of a queue with at most 256 entries takes up a page, but may straddle two.
+pub const MAX_QUEUE_PAGES: usize = 2 * MAX_DEVICES * MAX_QUEUES;
+
+/// Passthrough devices never see the rings of the guest. Instead, each queue gets this much
+/// hypervisor memory holding the descriptor table, available ring and used ring that the device
+/// actually uses, and chains are copied between the two sets of rings as they are made available
+/// and used.
+const QUEUE_MEMORY_SIZE: u64 = 0x8000;
+/// The rest of the memory of each queue, past its rings, holds copies of the indirect tables of
+/// chains the device has. It is handed out in blocks of 16 descriptors.
+const INDIRECT_OFFSET: u64 = 0x3000;
+const INDIRECT_BLOCK_SIZE: u64 = 0x100;
+const INDIRECT_BLOCKS: u64 = (QUEUE_MEMORY_SIZE - INDIRECT_OFFSET) / INDIRECT_BLOCK_SIZE;
+/// Memory needed by each passthrough device, at a host physical address passed to `Device::new`.
+pub const PASSTHROUGH_MEMORY_SIZE: u64 = QUEUE_MEMORY_SIZE * MAX_QUEUES as u64;
 
 #[derive(Copy, Clone)]
 pub struct Queue {
-    /// Address guest thinks queue is mapped at
-    guest_pa: u64,
-    /// Address queue is actually mapped at
-    host_pa: u64,
     /// Number of entries in queue
     size: u64,
+    /// Alignment of the used ring written to the legacy QueueAlign register.
+    align: u64,
+    /// Guest physical addresses of the descriptor table, available ring and used ring of the guest.
+    /// With the modern transport, these are written to the QueueDesc, QueueDriver and QueueDevice
+    /// registers, and only take effect once the queue is enabled.
+    desc: u64,
+    driver: u64,
+    device: u64,
+    /// Whether the queue was enabled through the legacy QueuePFN register.
+    legacy: bool,
+    /// Host physical address of the rings the device uses, or zero while the queue is disabled.
+    host_pa: u64,
+    /// Index in the available ring of the guest of the next chain to hand to the device. The
+    /// available ring of the device has the same index.
+    next_avail: u16,
+    /// Index of the next entry of the device's used ring to copy to the guest.
+    next_used: u16,
+    /// Descriptors that belong to chains the device hasn't returned yet, one bit each.
+    in_flight: [u64; 4],
+    /// Blocks of indirect table memory in use, one bit each.
+    indirect_blocks: u128,
+}
+
+impl Queue {
+    const EMPTY: Self = Queue { size: 0, align: 0, desc: 0, driver: 0, device: 0, legacy: false, host_pa: 0,
+                                next_avail: 0, next_used: 0, in_flight: [0; 4], indirect_blocks: 0 };
+
+    /// Host physical addresses of the descriptor table, available ring and used ring of the
+    /// device. The legacy transport fixes their layout, for which the device is told to align the
+    /// used ring to a page.
+    fn host_rings(&self) -> (u64, u64, u64) {
+        if self.legacy {
+            let avail = self.host_pa + self.size * 16;
+            (self.host_pa, avail, (avail + 6 + self.size * 2 + 0xfff) & !0xfff)
+        } else {
+            (self.host_pa, self.host_pa + 0x1000, self.host_pa + 0x2000)
+        }
+    }
+
+    fn is_in_flight(&self, index: u64) -> bool {
+        self.in_flight[index as usize / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set_in_flight(&mut self, index: u64, in_flight: bool) {
+        if in_flight {
+            self.in_flight[index as usize / 64] |= 1 << (index % 64);
+        } else {
+            self.in_flight[index as usize / 64] &= !(1 << (index % 64));
+        }
+    }
+
+    /// Reserve room for an indirect table of `len` bytes, returning its host physical address.
+    fn allocate_indirect(&mut self, len: u64) -> Option<u64> {
+        let blocks = (len + INDIRECT_BLOCK_SIZE - 1) / INDIRECT_BLOCK_SIZE;
+        let mask = (1u128 << blocks) - 1;
+        let first = (0..=INDIRECT_BLOCKS - blocks).find(|&i| self.indirect_blocks & (mask << i) == 0)?;
+        self.indirect_blocks |= mask << first;
+        Some(self.host_pa + INDIRECT_OFFSET + first * INDIRECT_BLOCK_SIZE)
+    }
+
+    fn free_indirect(&mut self, table: u64, len: u64) {
+        let blocks = (len + INDIRECT_BLOCK_SIZE - 1) / INDIRECT_BLOCK_SIZE;
+        let first = (table - self.host_pa - INDIRECT_OFFSET) / INDIRECT_BLOCK_SIZE;
+        self.indirect_blocks &= !(((1u128 << blocks) - 1) << first);
+    }
+}
+
+/// What became of a chain that the guest made available.
+enum Chain {
+    /// It was copied to the rings of the device.
+    Copied,
+    /// There is no room for its indirect table until the device returns other chains.
+    Wait,
+    /// It is malformed, or uses descriptors the device still has.
+    Invalid,
 }
 
 pub enum Device {
     Passthrough {
         /// Virtual Queue Index, offset=0x30
         queue_sel: u32,
+        /// Which half of the 64-bit feature bits is selected, offset=0x14
+        features_sel: u32,
         queues: [Queue; MAX_QUEUES],
         device_registers: MemoryRegion<u32>,
+        /// Rings of the device, `QUEUE_MEMORY_SIZE` bytes for each queue.
+        rings: MemoryRegion,
+        /// Set once the guest has handed the device an invalid queue or chain, until the guest
+        /// resets the device.
+        needs_reset: bool,
+        /// Whether a configuration change interrupt is pending for the guest.
+        config_interrupt: bool,
     },
     Unmapped,
     Macb(drivers::GuestDevice<MacbDriver>),
+    Console(drivers::GuestDevice<ConsoleDriver>),
+    Switch(drivers::GuestDevice<SwitchPortDriver>),
+    Blk(drivers::GuestDevice<RamDiskDriver>),
+    Partition(drivers::GuestDevice<PartitionDriver>),
+    Rng(drivers::GuestDevice<RngDriver>),
+    Vsock(drivers::GuestDevice<VsockDriver>),
 }
 impl Device {
-    pub unsafe fn new(host_base_address: u64) -> Self {
+    /// Pass through the device with registers at `host_base_address`, giving it the
+    /// `PASSTHROUGH_MEMORY_SIZE` bytes at host physical address `rings` for its rings.
+    pub unsafe fn new(host_base_address: u64, rings: u64) -> Self {
         Device::Passthrough {
             queue_sel: 0,
-            queues: [Queue {guest_pa: 0, host_pa: 0, size: 0}; MAX_QUEUES],
+            features_sel: 0,
+            queues: [Queue::EMPTY; MAX_QUEUES],
             device_registers: MemoryRegion::with_base_address(pmap::pa2va(host_base_address), 0, 0x1000),
+            rings: MemoryRegion::with_base_address(pmap::pa2va(rings), rings, PASSTHROUGH_MEMORY_SIZE),
+            needs_reset: false,
+            config_interrupt: false,
+        }
+    }
+
+    /// Reset the device as if the guest had written zero to its status register.
+    pub fn reset(&mut self, guest_memory: &mut MemoryRegion) {
+        match *self {
+            Device::Passthrough { ref mut queue_sel, ref mut features_sel, ref mut queues, ref mut device_registers,
+                                  ref mut needs_reset, ref mut config_interrupt, .. } => {
+                device_registers[REG_STATUS] = 0;
+                *queue_sel = 0;
+                *features_sel = 0;
+                *queues = [Queue::EMPTY; MAX_QUEUES];
+                *needs_reset = false;
+                *config_interrupt = false;
+            }
+            Device::Unmapped => {}
+            Device::Macb(ref mut macb) => macb.write_u32(guest_memory, 0x70, 0),
+            Device::Console(ref mut console) => console.write_u32(guest_memory, 0x70, 0),
+            Device::Switch(ref mut port) => port.write_u32(guest_memory, 0x70, 0),
+            Device::Blk(ref mut blk) => blk.write_u32(guest_memory, 0x70, 0),
+            Device::Partition(ref mut partition) => {
+                partition.write_u32(guest_memory, 0x70, 0);
+                partition.discard_overlay();
+            }
+            Device::Rng(ref mut rng) => rng.write_u32(guest_memory, 0x70, 0),
+            Device::Vsock(ref mut vsock) => vsock.write_u32(guest_memory, 0x70, 0),
+        }
+    }
+
+    /// Stop a passthrough device after the guest handed it an invalid queue or chain. Resetting the
+    /// device ends any DMA it might still do, and the guest is told that it must reset the device
+    /// before using it again. Returns whether the caller should raise the device's guest interrupt
+    /// to deliver the configuration change.
+    fn set_needs_reset(&mut self) -> bool {
+        match *self {
+            Device::Passthrough { ref mut device_registers, ref mut needs_reset, ref mut config_interrupt, .. } => {
+                if *needs_reset {
+                    return false;
+                }
+
+                println!("VIRTIO: Passthrough device given an invalid queue or descriptor chain");
+                device_registers[REG_STATUS] = 0;
+                while unsafe { core::ptr::read_volatile(&device_registers[REG_STATUS]) } != 0 {}
+
+                *needs_reset = true;
+                *config_interrupt = true;
+                true
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Called after the guest stored `len` bytes at `guest_pa`, in a page that holds the available
+    /// ring of a passthrough queue. If the store was to the index of the ring, the chains the guest
+    /// made available are handed to the device. Returns false if any of them was invalid.
+    fn avail_ring_written(&mut self, guest_memory: &MemoryRegion, guest_shift: u64, guest_pa: u64, len: u64) -> bool {
+        match *self {
+            Device::Passthrough { ref mut queues, ref mut rings, needs_reset: false, .. } => {
+                queues.iter_mut()
+                    .filter(|q| q.host_pa != 0 && guest_pa < q.driver + 4 && guest_pa + len > q.driver + 2)
+                    .all(|q| publish_chains(guest_memory, guest_shift, rings, q))
+            }
+            _ => true,
         }
     }
+
+    /// Copy the chains that a passthrough device has finished with to the used rings of the guest,
+    /// and hand it any chains that were waiting for room for their indirect tables. Called when
+    /// the device interrupts, and returns whether the guest should be interrupted.
+    pub fn collect_used(&mut self, guest_memory: &mut MemoryRegion, guest_shift: u64) -> bool {
+        let (collected, valid) = match *self {
+            Device::Passthrough { ref mut queues, ref mut device_registers, ref mut rings, needs_reset: false, .. } => {
+                let (mut collected, mut valid) = (false, true);
+                for (i, queue) in queues.iter_mut().enumerate().filter(|(_, q)| q.host_pa != 0) {
+                    if collect_used(guest_memory, rings, queue) {
+                        collected = true;
+                        let next_avail = queue.next_avail;
+                        valid &= publish_chains(guest_memory, guest_shift, rings, queue);
+                        if queue.next_avail != next_avail {
+                            device_registers[REG_QUEUE_NOTIFY] = i as u32;
+                        }
+                    }
+                }
+                (collected, valid)
+            }
+            _ => return false,
+        };
+        collected | (!valid && self.set_needs_reset())
+    }
 }
 
 #[inline(always)]
 pub fn is_device_access(state: &mut Context, guest_pa: u64) -> bool {
-    guest_pa >= 0x10001000 && guest_pa < 0x10001000 + 0x1000 * state.virtio.devices.len() as u64
+    let devices = state.guest.lock().as_ref().unwrap().virtio.devices.len() as u64;
+    guest_pa >= 0x10001000 && guest_pa < 0x10001000 + 0x1000 * devices
 }
 
 pub fn handle_device_access(state: &mut Context, guest_pa: u64, instruction: u32) -> bool {
     let device = ((guest_pa - 0x10001000) / 0x1000) as usize;
     let offset = guest_pa & 0xfff;
 
-    match state.virtio.devices[device] {
-        Device::Passthrough { ref mut queue_sel, ref mut queues, ref mut device_registers } => {
+    if trace::ENABLED && offset == REG_QUEUE_NOTIFY {
+        if let Ok(Instruction::Sw(i)) = riscv_decode::decode(instruction) {
+            trace::virtio_notify(state, device, state.saved_registers.get(i.rs2()) as u32 as u64);
+        }
+    }
+
+    let guest = state.guest;
+    let mut guest_lock = guest.lock();
+    let guest = guest_lock.as_mut().unwrap();
+
+    let mut flush_all_vcpus = false;
+    let mut queues_released = false;
+    let mut needs_reset_raised = false;
+    let mut interrupt_acked = false;
+    let mut raise_interrupt = false;
+    match guest.virtio.devices[device] {
+        Device::Passthrough { ref mut queue_sel, ref mut features_sel, ref mut queues, ref mut device_registers,
+                              ref mut rings, ref mut needs_reset, ref mut config_interrupt } => {
+            // Queues past the ones the hypervisor keeps track of are hidden from the guest.
+            let mut no_queue = Queue::EMPTY;
+            let queue_exists = (*queue_sel as usize) < MAX_QUEUES;
+            let queue = queues.get_mut(*queue_sel as usize).unwrap_or(&mut no_queue);
             let mut current = device_registers[offset & !0x3];
-            if offset == 0x10 {
-                current = current & !(1 << 28); // No VIRTIO_F_INDIRECT_DESC
-            } else if offset == 0x34 {
-                current = current.min(256); // ensure queues take up at most one page
+            match offset {
+                REG_STATUS if *needs_reset => current |= STATUS_NEEDS_RESET,
+                REG_INTERRUPT_STATUS if *config_interrupt => current |= INTERRUPT_CONFIG_CHANGE,
+                REG_HOST_FEATURES if *features_sel == 0 => {
+                    // The device is always asked for interrupts and the guest always notifies it,
+                    // since neither of them sees the other's rings.
+                    current &= !(VIRTIO_F_EVENT_IDX as u32);
+                }
+                REG_HOST_FEATURES if *features_sel == 1 => {
+                    // Chains are only copied between split virtqueues.
+                    current &= !((VIRTIO_F_RING_PACKED >> 32) as u32);
+                }
+                REG_QUEUE_NUM_MAX | REG_QUEUE_READY if !queue_exists => current = 0,
+                REG_QUEUE_NUM_MAX => current = current.min(256), // ensure queues take up at most one page
+                REG_QUEUE_PFN if queue.legacy && queue.host_pa != 0 => current = (queue.desc >> 12) as u32,
+                REG_QUEUE_PFN => current = 0,
+                REG_QUEUE_DESC_LOW => current = queue.desc as u32,
+                REG_QUEUE_DESC_HIGH => current = (queue.desc >> 32) as u32,
+                REG_QUEUE_DRIVER_LOW => current = queue.driver as u32,
+                REG_QUEUE_DRIVER_HIGH => current = (queue.driver >> 32) as u32,
+                REG_QUEUE_DEVICE_LOW => current = queue.device as u32,
+                REG_QUEUE_DEVICE_HIGH => current = (queue.device >> 32) as u32,
+                _ => {}
             }
 
             match riscv_decode::decode(instruction).ok() {
@@ -61,45 +303,117 @@ pub fn handle_device_access(state: &mut Context, guest_pa: u64, instruction: u32
                     state.saved_registers.set(i.rd(), current as u64)
                 }
                 Some(Instruction::Lb(i)) => {
-                    assert!(offset >= 0x100);
                     let value = (current >> (8*(offset & 0x3))) & 0xff;
                     state.saved_registers.set(i.rd(), value as u64)
                 }
                 Some(Instruction::Sw(i)) => {
                     let mut value = state.saved_registers.get(i.rs2()) as u32;
-                    if offset == 0x30 { // QueueSel
-                        assert!(value < 4);
-                        *queue_sel = value;
-                    } else if offset == 0x38 { // QueueNum
-                        let queue = &mut queues[*queue_sel as usize];
-                        queue.size = value as u64;
-
-                        // Linux never changes queue sizes, so this isn't supported.
-                        assert_eq!(queue.host_pa, 0);
-                    } else if offset == 0x40 { // QueuePFN
-                        let queue = &mut queues[*queue_sel as usize];
-
-                        // Linux never releases queues, so this is currently unimplemented.
-                        assert_eq!(queue.host_pa, 0);
-
-                        if value != 0 {
-                            queue.guest_pa = (value as u64) << 12;
-                            value += (state.guest_shift >> 12) as u32;
-                            queue.host_pa = (value as u64) << 12;
-                        } else {
-                            unimplemented!();
+                    let written = value as u64;
+                    let set_low = move |r: &mut u64| *r = (*r & !0xffffffff) | written;
+                    let set_high = move |r: &mut u64| *r = (*r & 0xffffffff) | (written << 32);
+                    let mut forward = true;
+                    match offset {
+                        // A stopped device ignores everything until the guest resets it.
+                        REG_STATUS if value == 0 => {
+                            *needs_reset = false;
+                            *config_interrupt = false;
+                            *queues = [Queue::EMPTY; MAX_QUEUES];
+                            queues_released = true;
+                        }
+                        REG_INTERRUPT_ACK => {
+                            if value & INTERRUPT_CONFIG_CHANGE != 0 {
+                                *config_interrupt = false;
+                            }
+                            interrupt_acked = true;
+                        }
+                        _ if *needs_reset => forward = false,
+                        REG_HOST_FEATURES_SEL => *features_sel = value,
+                        REG_QUEUE_SEL => *queue_sel = value,
+                        REG_QUEUE_NUM | REG_QUEUE_ALIGN | REG_QUEUE_PFN | REG_QUEUE_READY if !queue_exists => {
+                            forward = false;
+                        }
+                        // Writing zero to QueuePFN or QueueReady releases the queue. Once the device
+                        // has been told, it no longer uses the queue, and the available ring of the
+                        // guest needn't be trapped any more.
+                        REG_QUEUE_PFN if value == 0 => {
+                            queue.host_pa = 0;
+                            queues_released = true;
                         }
+                        REG_QUEUE_READY if value & 1 == 0 => {
+                            queue.host_pa = 0;
+                            queues_released = true;
+                        }
+                        // The queue is already enabled, so it can't be set up again until released.
+                        REG_QUEUE_NUM | REG_QUEUE_ALIGN | REG_QUEUE_PFN | REG_QUEUE_READY if queue.host_pa != 0 => {
+                            forward = false;
+                        }
+                        REG_QUEUE_NUM => queue.size = value as u64,
+                        REG_QUEUE_ALIGN => {
+                            // Only the guest's used ring uses this alignment.
+                            queue.align = value as u64;
+                            forward = false;
+                        }
+                        REG_QUEUE_PFN => {
+                            queue.legacy = true;
+                            queue.desc = (value as u64) << 12;
+                            queue.driver = queue.desc + queue.size * 16;
+                            queue.device = if queue.align.is_power_of_two() {
+                                (queue.driver + 6 + queue.size * 2 + queue.align - 1) & !(queue.align - 1)
+                            } else {
+                                0
+                            };
 
-                        // Sad, but necessary because we don't know all the places this page is mapped.
-                        pmap::flush_shadow_page_table(&mut state.shadow_page_tables);
+                            let host_pa = rings.base() + *queue_sel as u64 * QUEUE_MEMORY_SIZE;
+                            if enable_queue(queue, host_pa, &state.guest_memory, state.guest_shift, rings,
+                                            &mut guest.virtio.queue_guest_pages) {
+                                device_registers[REG_GUEST_PAGE_SIZE] = 0x1000;
+                                device_registers[REG_QUEUE_ALIGN] = 0x1000;
+                                value = (host_pa >> 12) as u32;
+                            } else {
+                                forward = false;
+                                needs_reset_raised = true;
+                            }
 
-                        state.virtio.queue_guest_pages.push(queue.guest_pa);
-                        for i in 0..queue.size {
-                            let value = &mut state.guest_memory[queue.guest_pa + i * 16];
-                            *value = (*value).wrapping_add(state.guest_shift);
+                            // Sad, but necessary because we don't know all the places the available
+                            // ring is mapped. Any vCPU might have it mapped, so they all need to be
+                            // flushed once the guest lock has been released.
+                            flush_all_vcpus = true;
                         }
+                        // The device is given its own rings instead, once the queue is enabled.
+                        REG_QUEUE_DESC_LOW => set_low(&mut queue.desc),
+                        REG_QUEUE_DESC_HIGH => set_high(&mut queue.desc),
+                        REG_QUEUE_DRIVER_LOW => set_low(&mut queue.driver),
+                        REG_QUEUE_DRIVER_HIGH => set_high(&mut queue.driver),
+                        REG_QUEUE_DEVICE_LOW => set_low(&mut queue.device),
+                        REG_QUEUE_DEVICE_HIGH => set_high(&mut queue.device),
+                        REG_QUEUE_READY => {
+                            queue.legacy = false;
+                            let host_pa = rings.base() + *queue_sel as u64 * QUEUE_MEMORY_SIZE;
+                            if enable_queue(queue, host_pa, &state.guest_memory, state.guest_shift, rings,
+                                            &mut guest.virtio.queue_guest_pages) {
+                                let (desc, avail, used) = queue.host_rings();
+                                for &(register, host_pa) in &[(REG_QUEUE_DESC_LOW, desc),
+                                                              (REG_QUEUE_DRIVER_LOW, avail),
+                                                              (REG_QUEUE_DEVICE_LOW, used)] {
+                                    device_registers[register] = host_pa as u32;
+                                    device_registers[register + 4] = (host_pa >> 32) as u32;
+                                }
+                            } else {
+                                forward = false;
+                                needs_reset_raised = true;
+                            }
+                            flush_all_vcpus = true;
+                        }
+                        _ => {}
+                    }
+
+                    match offset {
+                        REG_QUEUE_DESC_LOW | REG_QUEUE_DESC_HIGH |
+                        REG_QUEUE_DRIVER_LOW | REG_QUEUE_DRIVER_HIGH |
+                        REG_QUEUE_DEVICE_LOW | REG_QUEUE_DEVICE_HIGH => {}
+                        _ if !forward => {}
+                        _ => device_registers[offset] = value,
                     }
-                    device_registers[offset] = value;
                 }
                 Some(instr) => {
                     println!("VIRTIO: Instruction {:?} used to target addr {:#x} from pc {:#x}", instr, guest_pa, csrr!(sepc));
@@ -126,39 +440,350 @@ pub fn handle_device_access(state: &mut Context, guest_pa: u64, instruction: u32
                 }
             }
         }
-        Device::Macb(ref mut macb) => match riscv_decode::decode(instruction).ok() {
-            Some(Instruction::Lb(i)) => state.saved_registers.set(i.rd(), macb.read_u8(&mut state.guest_memory, offset) as u64),
-            Some(Instruction::Lw(i)) => state.saved_registers.set(i.rd(), macb.read_u32(&mut state.guest_memory, offset) as u64),
-            Some(Instruction::Sb(i)) => macb.write_u8(&mut state.guest_memory, offset, state.saved_registers.get(i.rs2()) as u8),
-            Some(Instruction::Sw(i)) => macb.write_u32(&mut state.guest_memory, offset, state.saved_registers.get(i.rs2()) as u32),
-            Some(_) | None => {}
+        Device::Macb(ref mut macb) => {
+            handle_emulated_access(macb, &mut state.saved_registers, &mut state.guest_memory, offset, instruction);
+            raise_interrupt = macb.take_interrupt();
+        }
+        Device::Console(ref mut console) => {
+            handle_emulated_access(console, &mut state.saved_registers, &mut state.guest_memory, offset, instruction);
+            raise_interrupt = console.take_interrupt();
+        }
+        Device::Switch(ref mut port) => {
+            handle_emulated_access(port, &mut state.saved_registers, &mut state.guest_memory, offset, instruction);
+            raise_interrupt = port.take_interrupt();
+        }
+        Device::Blk(ref mut blk) => {
+            handle_emulated_access(blk, &mut state.saved_registers, &mut state.guest_memory, offset, instruction);
+            raise_interrupt = blk.take_interrupt();
+        }
+        Device::Partition(ref mut partition) => {
+            handle_emulated_access(partition, &mut state.saved_registers, &mut state.guest_memory, offset, instruction);
+            raise_interrupt = partition.take_interrupt();
         }
+        Device::Rng(ref mut rng) => {
+            handle_emulated_access(rng, &mut state.saved_registers, &mut state.guest_memory, offset, instruction);
+            raise_interrupt = rng.take_interrupt();
+        }
+        Device::Vsock(ref mut vsock) => {
+            handle_emulated_access(vsock, &mut state.saved_registers, &mut state.guest_memory, offset, instruction);
+            raise_interrupt = vsock.take_interrupt();
+        }
+    }
+
+    // Chains the device finished with before the interrupt was acknowledged won't raise another, so
+    // they are passed to the guest now.
+    if interrupt_acked && guest.virtio.devices[device].collect_used(&mut state.guest_memory, state.guest_shift) {
+        raise_interrupt = true;
+    }
+
+    if raise_interrupt {
+        guest.plic.set_pending(guest.virtio.guest_irqs[device] as u32, true);
+        state.no_interrupt = false;
+        guest.notify_external_interrupts(state.vcpu);
+    }
+
+    if queues_released {
+        retrap_queue_pages(&mut guest.virtio);
+    }
+    if needs_reset_raised && guest.virtio.devices[device].set_needs_reset() {
+        raise_config_interrupt(state, guest, device);
+    }
+    drop(guest_lock);
+
+    if flush_all_vcpus {
+        crate::trap::send_vcpu_requests(state, u64::max_value(), REQUEST_FLUSH_SHADOW_PAGE_TABLES, true);
     }
     riscv::set_sepc(csrr!(sepc) + riscv_decode::instruction_length(instruction as u16) as u64);
     true
 }
 
-pub fn is_queue_access(state: &mut Context, guest_page: u64) -> bool {
-    for i in 0..state.virtio.queue_guest_pages.len() {
-        if state.virtio.queue_guest_pages[i] == guest_page {
-            return true;
+/// Start handing chains from the rings of the guest for `queue` to the device, which is given rings
+/// of its own at host physical address `host_pa`. The available ring of the guest is trapped from
+/// now on, so that chains can be copied as they are made available. Returns false if the rings of
+/// the guest don't lie within its memory, or it had already made an invalid chain available.
+fn enable_queue(queue: &mut Queue,
+                host_pa: u64,
+                guest_memory: &MemoryRegion,
+                guest_shift: u64,
+                rings: &mut MemoryRegion,
+                queue_guest_pages: &mut ArrayVec<[u64; MAX_QUEUE_PAGES]>) -> bool {
+    if !queue_in_guest_memory(guest_memory, queue.size, queue.desc, queue.driver, queue.device) {
+        return false;
+    }
+
+    queue.host_pa = host_pa;
+    queue.next_avail = 0;
+    queue.next_used = 0;
+    queue.in_flight = [0; 4];
+    queue.indirect_blocks = 0;
+    for byte in rings.slice_mut(host_pa, QUEUE_MEMORY_SIZE) {
+        *byte = 0;
+    }
+    trap_queue_pages(queue, queue_guest_pages);
+
+    // Nothing should be made available before the queue is enabled, but if anything was, the
+    // device should see it.
+    publish_chains(guest_memory, guest_shift, rings, queue)
+}
+
+fn trap_queue_pages(queue: &Queue, queue_guest_pages: &mut ArrayVec<[u64; MAX_QUEUE_PAGES]>) {
+    let last_page = (queue.driver + 6 + queue.size * 2 - 1) & !0xfff;
+    let mut page = queue.driver & !0xfff;
+    while page <= last_page {
+        if !queue_guest_pages.contains(&page) {
+            queue_guest_pages.push(page);
         }
+        page += 0x1000;
     }
-    false
 }
 
-pub fn handle_queue_access(state: &mut Context, guest_pa: u64, host_pa: u64, instruction: u32) -> bool {
-    let mut hit_queue = false;
-    for d in &state.virtio.devices {
+/// Recompute the set of trapped pages after a passthrough device released its queues.
+fn retrap_queue_pages(virtio: &mut VirtIO) {
+    virtio.queue_guest_pages.clear();
+    for d in &virtio.devices {
         if let Device::Passthrough { ref queues, .. } = d {
-            for q in queues {
-                if guest_pa >= q.guest_pa && guest_pa < q.guest_pa + q.size * 16 && guest_pa & 0xf < 8 {
-                    hit_queue = true;
+            for q in queues.iter().filter(|q| q.host_pa != 0) {
+                trap_queue_pages(q, &mut virtio.queue_guest_pages);
+            }
+        }
+    }
+}
+
+/// Whether the descriptor table, available ring and used ring of a queue with `size` entries all
+/// lie within guest memory, so that the hypervisor may copy chains between them and the device.
+fn queue_in_guest_memory(guest_memory: &MemoryRegion, size: u64, desc: u64, avail: u64, used: u64) -> bool {
+    size > 0 && size <= 256 && desc % 16 == 0 && avail % 2 == 0 && used % 4 == 0 &&
+        buffer_in_guest_memory(guest_memory, desc, size * 16) &&
+        buffer_in_guest_memory(guest_memory, avail, 6 + size * 2) &&
+        buffer_in_guest_memory(guest_memory, used, 6 + size * 8)
+}
+
+fn read_u16(guest_memory: &MemoryRegion, addr: u64) -> u64 {
+    (guest_memory[addr & !0x7] >> (8 * (addr & 0x7))) & 0xffff
+}
+
+/// Make the configuration change interrupt of a passthrough device pending in the guest.
+fn raise_config_interrupt(state: &mut Context, guest: &mut Guest, device: usize) {
+    guest.plic.set_pending(guest.virtio.guest_irqs[device] as u32, true);
+    state.no_interrupt = false;
+    guest.notify_external_interrupts(state.vcpu);
+}
+
+/// Returns the length, flags and next fields of a descriptor, given its second half.
+fn split_descriptor(word: u64) -> (u64, u16, u64) {
+    (word & 0xffffffff, (word >> 32) as u16, word >> 48)
+}
+
+/// Hand the device every chain that the guest made available since the last call, by copying them
+/// to the rings of the device and then advancing the index of its available ring past them.
+/// Returns false if any of them was invalid.
+fn publish_chains(guest_memory: &MemoryRegion, guest_shift: u64, rings: &mut MemoryRegion, queue: &mut Queue) -> bool {
+    let (_, host_avail, _) = queue.host_rings();
+    let avail_idx = read_u16(guest_memory, queue.driver + 2) as u16;
+    if avail_idx.wrapping_sub(queue.next_avail) as u64 > queue.size {
+        return false;
+    }
+
+    let mut valid = true;
+    while queue.next_avail != avail_idx {
+        let slot = queue.next_avail as u64 % queue.size;
+        let head = read_u16(guest_memory, queue.driver + 4 + slot * 2);
+        match copy_chain(guest_memory, guest_shift, rings, queue, head) {
+            Chain::Copied => {}
+            Chain::Wait => break,
+            Chain::Invalid => {
+                valid = false;
+                break;
+            }
+        }
+        NativeEndian::write_u16(rings.slice_mut(host_avail + 4 + slot * 2, 2), head as u16);
+        queue.next_avail = queue.next_avail.wrapping_add(1);
+    }
+
+    core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+    NativeEndian::write_u16(rings.slice_mut(host_avail + 2, 2), queue.next_avail);
+    valid
+}
+
+/// Copy the chain starting at descriptor `head` from the descriptor table of the guest to that of
+/// the device, translating buffer addresses on the way. Each descriptor is read from guest memory
+/// once and checked as it is copied, so nothing the guest writes afterwards can reach the device,
+/// and the device accesses every buffer directly, so each of them must lie entirely inside guest
+/// memory. An indirect table is copied the same way, and the device is given the copy.
+fn copy_chain(guest_memory: &MemoryRegion, guest_shift: u64, rings: &mut MemoryRegion, queue: &mut Queue, head: u64) -> Chain {
+    let (host_desc, _, _) = queue.host_rings();
+    let mut index = head;
+    for _ in 0..queue.size {
+        if index >= queue.size || queue.is_in_flight(index) {
+            return Chain::Invalid;
+        }
+
+        let desc = queue.desc + index * 16;
+        let addr = guest_memory[desc];
+        let word = guest_memory[desc + 8];
+        let (len, flags, next) = split_descriptor(word);
+        if !buffer_in_guest_memory(guest_memory, addr, len) {
+            return Chain::Invalid;
+        }
+
+        let mut host_addr = addr.wrapping_add(guest_shift);
+        if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+            if flags & VIRTQ_DESC_F_NEXT != 0 || len == 0 || len % 16 != 0 || addr % 16 != 0 ||
+                len > INDIRECT_BLOCKS * INDIRECT_BLOCK_SIZE {
+                return Chain::Invalid;
+            }
+            host_addr = match queue.allocate_indirect(len) {
+                Some(copy) => copy,
+                None => return Chain::Wait,
+            };
+            if !copy_indirect_table(guest_memory, guest_shift, rings, addr, len, host_addr) {
+                queue.free_indirect(host_addr, len);
+                return Chain::Invalid;
+            }
+        }
+        rings[host_desc + index * 16] = host_addr;
+        rings[host_desc + index * 16 + 8] = word;
+
+        if flags & VIRTQ_DESC_F_NEXT == 0 {
+            // The copy may differ from the chain as first walked if the guest changed it meanwhile,
+            // so the descriptors the device will use are found by walking the copy.
+            let mut index = head;
+            loop {
+                queue.set_in_flight(index, true);
+                let word = rings[host_desc + index * 16 + 8];
+                if (word >> 32) as u16 & VIRTQ_DESC_F_NEXT == 0 {
+                    return Chain::Copied;
                 }
+                index = word >> 48;
             }
         }
+        index = next;
+    }
+
+    // The chain is longer than the queue, so it must contain a loop.
+    Chain::Invalid
+}
+
+/// Copy the entries the device added to its used ring since the last call to the used ring of the
+/// guest, releasing the descriptors and indirect table of each chain returned. Returns whether
+/// there were any.
+fn collect_used(guest_memory: &mut MemoryRegion, rings: &MemoryRegion, queue: &mut Queue) -> bool {
+    let (host_desc, _, host_used) = queue.host_rings();
+    let used_idx = NativeEndian::read_u16(rings.slice(host_used + 2, 2));
+    if used_idx == queue.next_used {
+        return false;
     }
+    core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+
+    while queue.next_used != used_idx {
+        let slot = queue.next_used as u64 % queue.size;
+        let mut elem = [0; 8];
+        elem.copy_from_slice(rings.slice(host_used + 4 + slot * 8, 8));
+
+        let mut index = NativeEndian::read_u32(&elem[..4]) as u64;
+        while index < queue.size && queue.is_in_flight(index) {
+            queue.set_in_flight(index, false);
+            let (len, flags, next) = split_descriptor(rings[host_desc + index * 16 + 8]);
+            if flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                queue.free_indirect(rings[host_desc + index * 16], len);
+            }
+            if flags & VIRTQ_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = next;
+        }
 
+        guest_memory.slice_mut(queue.device + 4 + slot * 8, 8).copy_from_slice(&elem);
+        queue.next_used = queue.next_used.wrapping_add(1);
+    }
+
+    core::sync::atomic::fence(core::sync::atomic::Ordering::SeqCst);
+    NativeEndian::write_u16(guest_memory.slice_mut(queue.device + 2, 2), used_idx);
+    true
+}
+
+/// Copy the indirect table of `len` bytes at guest physical address `table` to host physical address
+/// `copy`, within the memory of the device, translating buffer addresses on the way. Returns false
+/// if any entry of the table is invalid.
+fn copy_indirect_table(guest_memory: &MemoryRegion, guest_shift: u64, rings: &mut MemoryRegion,
+                       table: u64, len: u64, copy: u64) -> bool {
+    let entries = len / 16;
+    for i in 0..entries {
+        let addr = guest_memory[table + i * 16];
+        let word = guest_memory[table + i * 16 + 8];
+        let (len, flags, next) = split_descriptor(word);
+        if flags & VIRTQ_DESC_F_INDIRECT != 0 ||
+            (flags & VIRTQ_DESC_F_NEXT != 0 && next >= entries) ||
+            !buffer_in_guest_memory(guest_memory, addr, len) {
+            return false;
+        }
+        rings[copy + i * 16] = addr.wrapping_add(guest_shift);
+        rings[copy + i * 16 + 8] = word;
+    }
+    true
+}
+
+/// Emulate an access to the registers of a device implemented by the hypervisor.
+fn handle_emulated_access<D: Driver>(device: &mut GuestDevice<D>,
+                                     saved_registers: &mut SavedRegisters,
+                                     guest_memory: &mut MemoryRegion,
+                                     offset: u64,
+                                     instruction: u32) {
+    match riscv_decode::decode(instruction).ok() {
+        Some(Instruction::Lb(i)) => saved_registers.set(i.rd(), device.read_u8(guest_memory, offset) as u64),
+        Some(Instruction::Lw(i)) => saved_registers.set(i.rd(), device.read_u32(guest_memory, offset) as u64),
+        Some(Instruction::Sb(i)) => device.write_u8(guest_memory, offset, saved_registers.get(i.rs2()) as u8),
+        Some(Instruction::Sw(i)) => device.write_u32(guest_memory, offset, saved_registers.get(i.rs2()) as u32),
+        Some(_) | None => {}
+    }
+}
+
+/// Let devices implemented by the hypervisor pick up host input and frames from other guests, and
+/// deliver anything else they have waiting for the guest. The switch's uplink also sends out what
+/// the other guests queued for it. Called on timer and software interrupts, and returns whether
+/// any of them made their guest interrupt pending.
+pub fn poll_devices(guest_memory: &mut MemoryRegion, guest: &mut Guest) -> bool {
+    let mut raised = false;
+    for i in 0..guest.virtio.devices.len() {
+        let interrupt = match guest.virtio.devices[i] {
+            Device::Console(ref mut console) => {
+                console.poll_input(guest_memory);
+                console.take_interrupt()
+            }
+            Device::Switch(ref mut port) => {
+                port.poll_input(guest_memory);
+                port.take_interrupt()
+            }
+            Device::Vsock(ref mut vsock) => {
+                vsock.poll_input(guest_memory);
+                vsock.take_interrupt()
+            }
+            Device::Macb(ref mut macb) => {
+                macb.transmit_uplink();
+                false
+            }
+            _ => false,
+        };
+        if interrupt {
+            guest.plic.set_pending(guest.virtio.guest_irqs[i] as u32, true);
+            raised = true;
+        }
+    }
+    raised
+}
+
+pub fn is_queue_access(state: &mut Context, guest_page: u64) -> bool {
+    let guest = state.guest.lock();
+    let virtio = &guest.as_ref().unwrap().virtio;
+    for i in 0..virtio.queue_guest_pages.len() {
+        if virtio.queue_guest_pages[i] == guest_page {
+            return true;
+        }
+    }
+    false
+}
+
+pub fn handle_queue_access(state: &mut Context, guest_pa: u64, host_pa: u64, instruction: u32) -> bool {
     let decoded = riscv_decode::decode(instruction);
     if let Err(err) = decoded {
         println!("Unrecognized instruction targetting VQUEUE {:#x} at {:#x} (error: {:?})!",
@@ -166,56 +791,53 @@ pub fn handle_queue_access(state: &mut Context, guest_pa: u64, host_pa: u64, ins
         loop {}
     }
 
-    if hit_queue {
-        match decoded.unwrap() {
-            Instruction::Ld(i) => {
-                state.saved_registers.set(i.rd(), state.guest_memory[guest_pa].wrapping_sub(state.guest_shift));
-            }
-            Instruction::Sd(i) => {
-                let value = state.saved_registers.get(i.rs2());
-                if value == 0 {
-                    state.guest_memory[guest_pa] = 0;
-                } else if state.guest_memory.in_region(value) {
-                    state.guest_memory[guest_pa] = value.wrapping_add(state.guest_shift);
-                } else {
-                    loop {}
-                }
-            }
-            instr => {
-                println!("VQUEUE: Instruction {:?} used to target addr {:#x} from pc {:#x}",
-                         instr, host_pa, csrr!(sepc));
-                loop {}
-            }
-        }
-    } else {
-        let index = guest_pa & !0x7;
-        let offset = (guest_pa % 8) as usize;
-        let mut current = state.guest_memory[index].to_ne_bytes();
-        match decoded.as_ref().unwrap() {
-            Instruction::Ld(i) => state.saved_registers.set(i.rd(), u64::from_ne_bytes(current)),
-            Instruction::Lwu(i) => state.saved_registers.set(i.rd(), NativeEndian::read_u32(&current[offset..]) as u64),
-            Instruction::Lhu(i) => state.saved_registers.set(i.rd(), NativeEndian::read_u16(&current[offset..]) as u64),
-            Instruction::Lbu(i) => state.saved_registers.set(i.rd(), current[offset] as u64),
-            Instruction::Lw(i) => state.saved_registers.set(i.rd(), NativeEndian::read_i32(&current[offset..]) as i64 as u64),
-            Instruction::Lh(i) => state.saved_registers.set(i.rd(), NativeEndian::read_i16(&current[offset..]) as i64 as u64),
-            Instruction::Lb(i) => state.saved_registers.set(i.rd(), current[offset] as i8 as i64 as u64),
-            Instruction::Sd(i) => state.guest_memory[index] = state.saved_registers.get(i.rs2()),
-            Instruction::Sw(i) => {
-                NativeEndian::write_u32(&mut current[offset..], state.saved_registers.get(i.rs2()) as u32);
-                state.guest_memory[index] = u64::from_ne_bytes(current);
-            }
-            Instruction::Sh(i) => {
-                NativeEndian::write_u16(&mut current[offset..], state.saved_registers.get(i.rs2()) as u16);
-                state.guest_memory[index] = u64::from_ne_bytes(current);
-            }
-            Instruction::Sb(i) => {
-                current[offset] = state.saved_registers.get(i.rs2()) as u8;
-                state.guest_memory[index] = u64::from_ne_bytes(current);
-            }
-            instr => {
-                println!("VQUEUE: Instruction {:?} used to target addr {:#x} from pc {:#x}",
-                         instr, host_pa, csrr!(sepc));
-                loop {}
+    let index = guest_pa & !0x7;
+    let offset = (guest_pa % 8) as usize;
+    let mut current = state.guest_memory[index].to_ne_bytes();
+    match decoded.as_ref().unwrap() {
+        Instruction::Ld(i) => state.saved_registers.set(i.rd(), u64::from_ne_bytes(current)),
+        Instruction::Lwu(i) => state.saved_registers.set(i.rd(), NativeEndian::read_u32(&current[offset..]) as u64),
+        Instruction::Lhu(i) => state.saved_registers.set(i.rd(), NativeEndian::read_u16(&current[offset..]) as u64),
+        Instruction::Lbu(i) => state.saved_registers.set(i.rd(), current[offset] as u64),
+        Instruction::Lw(i) => state.saved_registers.set(i.rd(), NativeEndian::read_i32(&current[offset..]) as i64 as u64),
+        Instruction::Lh(i) => state.saved_registers.set(i.rd(), NativeEndian::read_i16(&current[offset..]) as i64 as u64),
+        Instruction::Lb(i) => state.saved_registers.set(i.rd(), current[offset] as i8 as i64 as u64),
+        Instruction::Sd(i) => state.guest_memory[index] = state.saved_registers.get(i.rs2()),
+        Instruction::Sw(i) => {
+            NativeEndian::write_u32(&mut current[offset..], state.saved_registers.get(i.rs2()) as u32);
+            state.guest_memory[index] = u64::from_ne_bytes(current);
+        }
+        Instruction::Sh(i) => {
+            NativeEndian::write_u16(&mut current[offset..], state.saved_registers.get(i.rs2()) as u16);
+            state.guest_memory[index] = u64::from_ne_bytes(current);
+        }
+        Instruction::Sb(i) => {
+            current[offset] = state.saved_registers.get(i.rs2()) as u8;
+            state.guest_memory[index] = u64::from_ne_bytes(current);
+        }
+        instr => {
+            println!("VQUEUE: Instruction {:?} used to target addr {:#x} from pc {:#x}",
+                     instr, host_pa, csrr!(sepc));
+            loop {}
+        }
+    }
+
+    let stored_bytes = match decoded.unwrap() {
+        Instruction::Sd(_) => 8,
+        Instruction::Sw(_) => 4,
+        Instruction::Sh(_) => 2,
+        Instruction::Sb(_) => 1,
+        _ => 0,
+    };
+    if stored_bytes > 0 {
+        let guest = state.guest;
+        let mut guest = guest.lock();
+        let guest = guest.as_mut().unwrap();
+        for device in 0..guest.virtio.devices.len() {
+            if !guest.virtio.devices[device].avail_ring_written(&state.guest_memory, state.guest_shift,
+                                                               guest_pa, stored_bytes) &&
+                guest.virtio.devices[device].set_needs_reset() {
+                raise_config_interrupt(state, guest, device);
             }
         }
     }